@@ -3,7 +3,56 @@ fn main() {
         tauri_build::Attributes::new()
             .plugin(
                 "proxy-plugin",
-                tauri_build::InlinedPlugin::new().commands(&["get_proxy_url", "get_proxy_port"]),
+                tauri_build::InlinedPlugin::new().commands(&[
+                    "get_proxy_url",
+                    "get_proxy_port",
+                    "get_tile_cache_stats",
+                    "clear_tile_cache",
+                    "get_offline_tile_stats",
+                    "clear_offline_tiles",
+                    "download_tile_region",
+                    "cancel_tile_download",
+                    "export_mbtiles",
+                    "import_mbtiles",
+                    "list_upstreams",
+                    "reload_proxy_config",
+                    "get_client_settings",
+                    "set_client_settings",
+                    "rebuild_http_client",
+                    "get_proxy_base_url",
+                    "get_url_policy",
+                    "set_url_policy",
+                    "get_http_cache_stats",
+                    "clear_http_cache",
+                    "get_proxy_ws_url",
+                    "get_circuit_states",
+                    "reset_circuits",
+                    "get_rate_limits",
+                    "set_rate_limits",
+                    "get_rate_limit_stats",
+                    "get_traffic_log_settings",
+                    "set_traffic_log_settings",
+                    "get_traffic_log",
+                    "clear_traffic_log",
+                    "get_traffic_har",
+                    "export_traffic_har",
+                    "get_replay_settings",
+                    "set_replay_settings",
+                    "get_proxy_stats",
+                    "reset_proxy_stats",
+                    "get_tile_layers",
+                    "download_tile_corridor",
+                    "get_transform_settings",
+                    "set_transform_settings",
+                    "get_image_url",
+                    "get_image_cache_stats",
+                    "clear_image_cache",
+                    "get_proxy_status",
+                    "start_proxy",
+                    "stop_proxy",
+                    "restart_proxy",
+                    "rebind_proxy",
+                ]),
             )
             .plugin(
                 "androidfs-plugin",
@@ -0,0 +1,75 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use once_cell::sync::Lazy;
+use warp::http::StatusCode;
+use warp::reply::Reply;
+use warp::{Filter, Rejection};
+
+// 每次启动随机生成, 只通过 get_proxy_url 等命令交给前端
+static TOKEN: Lazy<String> = Lazy::new(|| {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("failed to generate proxy token");
+    BASE64_URL.encode(bytes)
+});
+
+// 应用自身的页面来源, 不同平台的 webview 不同
+const APP_ORIGINS: &[&str] = &[
+    "tauri://localhost",
+    "http://tauri.localhost",
+    "https://tauri.localhost",
+];
+// 开发模式下的 devUrl
+const DEV_ORIGIN: &str = "http://localhost:1420";
+
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+pub(crate) fn token() -> &'static str {
+    &TOKEN
+}
+
+pub(crate) fn allowed_origins() -> Vec<&'static str> {
+    let mut origins = APP_ORIGINS.to_vec();
+    if cfg!(debug_assertions) {
+        origins.push(DEV_ORIGIN);
+    }
+    origins
+}
+
+// 逐字节比较, 耗时与不匹配的位置无关
+fn token_matches(candidate: &str) -> bool {
+    let expected = token().as_bytes();
+    let candidate = candidate.as_bytes();
+    candidate.len() == expected.len()
+        && candidate
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// 校验地址中的第一段路径是否为本次启动的 token
+pub(crate) fn guard() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::param::<String>()
+        .and_then(|candidate: String| async move {
+            if token_matches(&candidate) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(Forbidden))
+            }
+        })
+        .untuple_one()
+}
+
+/// 把 token 校验失败转换为 403, 其余错误交给 warp 处理
+pub(crate) async fn handle_rejection(
+    rejection: Rejection,
+) -> Result<warp::reply::Response, Rejection> {
+    if rejection.find::<Forbidden>().is_some() {
+        let reply = warp::reply::with_status("Forbidden", StatusCode::FORBIDDEN);
+        return Ok(reply.into_response());
+    }
+    Err(rejection)
+}
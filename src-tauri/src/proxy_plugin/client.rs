@@ -0,0 +1,441 @@
+use super::config::{self, ProxyConfig};
+use super::policy::PolicyResolver;
+use super::tls;
+use super::upstream::Route;
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/136.0.0.0 Safari/537.36";
+
+// 代理请求共用一个客户端, 以复用连接池和 TLS 会话; 单独指定出站代理的命名上游各用一个
+static CLIENTS: Lazy<RwLock<Option<Clients>>> = Lazy::new(|| RwLock::new(None));
+// 环境变量中的出站代理, 与 reqwest 读取的变量一致
+const PROXY_ENV_VARS: [&str; 6] = [
+    "HTTP_PROXY",
+    "http_proxy",
+    "HTTPS_PROXY",
+    "https_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+];
+
+struct Clients {
+    shared: reqwest::Client,
+    upstreams: HashMap<String, reqwest::Client>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum HttpVersionPreference {
+    // 通过 ALPN 协商
+    Auto,
+    Http1Only,
+    // 不协商直接使用 HTTP/2
+    Http2Only,
+}
+
+/// 上游 HTTP 客户端设置, 对应配置文件中的 `client`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct ClientSettings {
+    pub connect_timeout_secs: u64,
+    // 两次读取之间的最长间隔, 默认不限制, 事件流和分块响应可能长时间没有数据
+    pub read_timeout_secs: Option<u64>,
+    // 整个请求的最长时间, 包括读取响应体
+    pub timeout_secs: Option<u64>,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_secs: u64,
+    pub user_agent: String,
+    pub http_version: HttpVersionPreference,
+    // 出站代理, WebSocket 连接同样经过出站代理
+    pub outbound: OutboundProxy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum OutboundMode {
+    // 读取 HTTP(S)_PROXY、ALL_PROXY、NO_PROXY 环境变量, 未设置时使用系统设置
+    #[default]
+    System,
+    // 使用 `url` 指定的代理
+    Manual,
+    // 直接连接
+    Direct,
+}
+
+/// 出站代理, 全局设置位于 `client.outbound`, 命名上游可单独指定;
+/// 支持 http、https、socks5 和 socks5h (由代理解析域名)
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct OutboundProxy {
+    pub mode: OutboundMode,
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // 不经过代理的主机, 格式同 NO_PROXY, 支持域名后缀、IP 和 CIDR
+    pub no_proxy: Vec<String>,
+}
+
+impl OutboundProxy {
+    fn proxy_url(&self) -> Result<Url, String> {
+        let url = self
+            .url
+            .as_deref()
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| "Outbound proxy URL is not set".to_string())?;
+        let mut url = Url::parse(url).map_err(|e| format!("Invalid outbound proxy URL: {}", e))?;
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(format!(
+                "Unsupported outbound proxy scheme: {}",
+                url.scheme()
+            ));
+        }
+        // 用户名和密码写入地址, 由 reqwest 转为 Proxy-Authorization 或 SOCKS5 认证
+        if let Some(username) = &self.username {
+            url.set_username(username)
+                .map_err(|_| "Invalid outbound proxy URL".to_string())?;
+            url.set_password(self.password.as_deref())
+                .map_err(|_| "Invalid outbound proxy URL".to_string())?;
+        }
+        Ok(url)
+    }
+
+    // 代理主机由用户指定, 解析到局域网地址时也允许连接
+    fn proxy_hosts(&self) -> Vec<String> {
+        let urls: Vec<String> = match self.mode {
+            OutboundMode::Manual => self.url.iter().cloned().collect(),
+            OutboundMode::System => PROXY_ENV_VARS
+                .iter()
+                .filter_map(|name| std::env::var(name).ok())
+                .collect(),
+            OutboundMode::Direct => Vec::new(),
+        };
+        urls.iter()
+            .filter_map(|url| Url::parse(url).ok())
+            .filter_map(|url| url.host_str().map(str::to_string))
+            .collect()
+    }
+
+    /// 访问 `url` 时使用的代理地址, 直接连接时返回 None;
+    /// system 模式只读取环境变量, 用于 WebSocket 等不经过 reqwest 的连接
+    pub(crate) fn for_url(&self, url: &Url) -> Result<Option<Url>, String> {
+        let (proxy, no_proxy) = match self.mode {
+            OutboundMode::Direct => return Ok(None),
+            OutboundMode::Manual => (self.proxy_url()?, self.no_proxy.clone()),
+            OutboundMode::System => {
+                let names: [&str; 4] = match url.scheme() {
+                    "https" | "wss" => ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"],
+                    _ => ["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"],
+                };
+                let proxy = match names
+                    .iter()
+                    .filter_map(|name| std::env::var(name).ok())
+                    .find(|value| !value.is_empty())
+                {
+                    // 与 reqwest 一致, 没有协议时按 http 处理
+                    Some(value) if value.contains("://") => value,
+                    Some(value) => format!("http://{}", value),
+                    None => return Ok(None),
+                };
+                let proxy =
+                    Url::parse(&proxy).map_err(|e| format!("Invalid outbound proxy URL: {}", e))?;
+                let no_proxy = ["NO_PROXY", "no_proxy"]
+                    .iter()
+                    .filter_map(|name| std::env::var(name).ok())
+                    .flat_map(|value| {
+                        value
+                            .split(',')
+                            .map(|pattern| pattern.trim().to_string())
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                (proxy, no_proxy)
+            }
+        };
+        let host = url
+            .host_str()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        if no_proxy
+            .iter()
+            .any(|pattern| no_proxy_matches(pattern, host))
+        {
+            return Ok(None);
+        }
+        Ok(Some(proxy))
+    }
+
+    /// 访问 `url` 时是否可能经过代理; macOS 和 Windows 上 reqwest 还会读取系统设置中的代理
+    pub(crate) fn may_proxy(&self, url: &Url) -> bool {
+        match self.mode {
+            OutboundMode::Direct => false,
+            OutboundMode::System if cfg!(any(target_os = "macos", target_os = "windows")) => true,
+            _ => !matches!(self.for_url(url), Ok(None)),
+        }
+    }
+
+    fn apply(&self, builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder, String> {
+        match self.mode {
+            // reqwest 默认读取环境变量和系统设置
+            OutboundMode::System => Ok(builder),
+            OutboundMode::Direct => Ok(builder.no_proxy()),
+            OutboundMode::Manual => {
+                let proxy = reqwest::Proxy::all(self.proxy_url()?)
+                    .map_err(|e| format!("Invalid outbound proxy: {}", e))?
+                    .no_proxy(reqwest::NoProxy::from_string(&self.no_proxy.join(",")));
+                Ok(builder.proxy(proxy))
+            }
+        }
+    }
+}
+
+// NO_PROXY 中的一项: `*`、IP、CIDR 或域名, 域名同时匹配子域名
+fn no_proxy_matches(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        let (net, prefix) = match pattern.split_once('/') {
+            Some((net, prefix)) => (net, prefix.parse::<u32>().ok()),
+            None => (pattern, None),
+        };
+        return match (ip, net.parse::<IpAddr>(), prefix) {
+            (ip, Ok(net), None) => ip == net,
+            (IpAddr::V4(ip), Ok(IpAddr::V4(net)), Some(prefix)) if prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(net) & mask
+            }
+            (IpAddr::V6(ip), Ok(IpAddr::V6(net)), Some(prefix)) if prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(net) & mask
+            }
+            _ => false,
+        };
+    }
+    let domain = pattern.trim_start_matches('*').trim_start_matches('.');
+    let host = host.to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+    !domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain)))
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            connect_timeout_secs: 15,
+            read_timeout_secs: None,
+            timeout_secs: None,
+            pool_max_idle_per_host: 16,
+            pool_idle_timeout_secs: 90,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            http_version: HttpVersionPreference::Auto,
+            outbound: OutboundProxy::default(),
+        }
+    }
+}
+
+fn build_client(config: &ProxyConfig, outbound: &OutboundProxy) -> Result<reqwest::Client, String> {
+    let settings = &config.client;
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::HeaderName::from_static("upgrade-insecure-requests"),
+        reqwest::header::HeaderValue::from_static("1"),
+    );
+    let mut tls_config = tls::client_config(&config.tls)?;
+    if settings.http_version == HttpVersionPreference::Http1Only {
+        tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    }
+    let mut builder = reqwest::Client::builder()
+        .use_preconfigured_tls(tls_config)
+        .user_agent(settings.user_agent.as_str())
+        .default_headers(headers)
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PolicyResolver {
+            block_private: config.policy.block_private,
+            trusted_hosts: outbound.proxy_hosts(),
+        }))
+        .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(settings.pool_idle_timeout_secs));
+    if let Some(secs) = settings.read_timeout_secs {
+        builder = builder.read_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = settings.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    builder = match settings.http_version {
+        HttpVersionPreference::Auto => builder,
+        HttpVersionPreference::Http1Only => builder.http1_only(),
+        HttpVersionPreference::Http2Only => builder.http2_prior_knowledge(),
+    };
+    builder = outbound.apply(builder)?;
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn build_clients(config: &ProxyConfig) -> Result<Clients, String> {
+    let shared = build_client(config, &config.client.outbound)?;
+    let mut upstreams = HashMap::new();
+    for (name, profile) in &config.upstreams {
+        if let Some(outbound) = &profile.outbound {
+            let client =
+                build_client(config, outbound).map_err(|e| format!("Upstream {}: {}", name, e))?;
+            upstreams.insert(name.clone(), client);
+        }
+    }
+    Ok(Clients { shared, upstreams })
+}
+
+// 首次使用时按当前配置创建全部客户端
+fn with_clients<T>(f: impl Fn(&Clients) -> T) -> Result<T, String> {
+    if let Some(clients) = CLIENTS.read().unwrap().as_ref() {
+        return Ok(f(clients));
+    }
+    let mut clients = CLIENTS.write().unwrap();
+    if let Some(clients) = clients.as_ref() {
+        return Ok(f(clients));
+    }
+    let built = build_clients(&config::current())?;
+    let value = f(&built);
+    *clients = Some(built);
+    Ok(value)
+}
+
+/// 共享的上游客户端
+pub(crate) fn shared() -> Result<reqwest::Client, String> {
+    with_clients(|clients| clients.shared.clone())
+}
+
+/// 命名上游单独指定了出站代理时使用对应的客户端, 否则使用共享的客户端
+pub(crate) fn for_upstream(name: &str) -> Result<reqwest::Client, String> {
+    with_clients(|clients| {
+        clients
+            .upstreams
+            .get(name)
+            .unwrap_or(&clients.shared)
+            .clone()
+    })
+}
+
+pub(crate) fn for_route(route: &Route) -> Result<reqwest::Client, String> {
+    match route {
+        Route::Proxy(_) => shared(),
+        Route::Upstream(name, _) => for_upstream(name),
+    }
+}
+
+/// 按指定配置重建客户端, 已在进行中的请求不受影响
+pub(crate) fn rebuild(config: &ProxyConfig) -> Result<(), String> {
+    let built = build_clients(config)?;
+    *CLIENTS.write().unwrap() = Some(built);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::commands;
+    use crate::proxy_plugin::test_support::{outbound_proxy, proxy_url, serve, setup, Proxied};
+    use warp::Filter;
+
+    fn manual(url: &str, no_proxy: &[&str]) -> OutboundProxy {
+        OutboundProxy {
+            mode: OutboundMode::Manual,
+            url: Some(url.to_string()),
+            no_proxy: no_proxy.iter().map(|host| host.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn no_proxy_hosts_connect_directly() {
+        let outbound = manual(
+            "socks5h://127.0.0.1:1080",
+            &["example.com", "10.0.0.0/8", "::1"],
+        );
+        let via = |url: &str| outbound.for_url(&Url::parse(url).unwrap()).unwrap();
+        assert_eq!(via("wss://tiles.example.com/a"), None);
+        assert_eq!(via("ws://10.1.2.3/a"), None);
+        assert_eq!(via("ws://[::1]:8080/a"), None);
+        let proxy = via("wss://example.org/a").unwrap();
+        assert_eq!(proxy.as_str(), "socks5h://127.0.0.1:1080");
+        assert!(!OutboundProxy {
+            mode: OutboundMode::Direct,
+            ..Default::default()
+        }
+        .may_proxy(&Url::parse("https://example.org/").unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_go_through_the_outbound_proxy() {
+        let (proxy_port, log) = outbound_proxy().await;
+        let _guard = setup(serde_json::json!({
+            "client": { "outbound": {
+                "mode": "manual",
+                "url": format!("http://127.0.0.1:{}", proxy_port),
+                "username": "user",
+                "password": "p@ss"
+            } }
+        }))
+        .await;
+        let port = serve(warp::path("hello").map(|| "hello"));
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+        let response = client.get(proxy_url(port, "/hello")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "hello");
+        assert_eq!(
+            log.lock().unwrap().as_slice(),
+            [Proxied {
+                kind: "http",
+                target: format!("127.0.0.1:{}", port),
+                auth: Some("Basic dXNlcjpwQHNz".to_string()),
+            }]
+        );
+
+        // socks5h 把域名交给代理解析
+        config::update(|config| {
+            config.client.outbound.url = Some(format!("socks5h://127.0.0.1:{}", proxy_port));
+        })
+        .unwrap();
+        let url =
+            commands::get_proxy_url(&format!("http://localhost:{}/hello", port), None).unwrap();
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "hello");
+        assert_eq!(
+            log.lock().unwrap().last(),
+            Some(&Proxied {
+                kind: "socks5",
+                target: format!("localhost:{}", port),
+                auth: Some("user:p@ss".to_string()),
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn private_targets_are_checked_before_the_outbound_proxy() {
+        let (proxy_port, log) = outbound_proxy().await;
+        let _guard = setup(serde_json::json!({
+            "client": { "outbound": {
+                "mode": "manual",
+                "url": format!("socks5h://127.0.0.1:{}", proxy_port)
+            } },
+            "policy": { "allowedHosts": [], "blockPrivate": true }
+        }))
+        .await;
+        let port = serve(warp::path("hello").map(|| "hello"));
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+        let url =
+            commands::get_proxy_url(&format!("http://localhost:{}/hello", port), None).unwrap();
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), 403);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("resolves to 127.0.0.1"), "{}", body);
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use futures_util::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{StatusCode, Url};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use warp::hyper::body::Bytes;
+
+// 超过该大小的响应不共享, 等待者各自请求
+const MAX_SHARED_BYTES: u64 = 8 * 1024 * 1024;
+
+static FLIGHTS: Lazy<Mutex<HashMap<String, Flight>>> = Lazy::new(Default::default);
+static FLIGHT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// 完整读取的上游响应, 分发给同时等待的请求, 也用于录制和回放
+#[derive(Debug)]
+pub(crate) struct SharedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub url: Url,
+    pub body: Bytes,
+}
+
+impl SharedResponse {
+    pub(crate) fn to_response(&self) -> reqwest::Response {
+        let mut response = http::Response::new(self.body.clone());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        reqwest::Response::from(response)
+    }
+}
+
+// None 表示首个请求放弃共享
+type FlightResult = Option<Arc<SharedResponse>>;
+
+struct Flight {
+    id: u64,
+    followers: usize,
+    rx: watch::Receiver<Option<FlightResult>>,
+}
+
+pub(crate) enum Joined {
+    // 负责请求上游
+    Leader(Leader),
+    // 等待首个请求的结果
+    Follower(watch::Receiver<Option<FlightResult>>),
+}
+
+pub(crate) struct Leader {
+    key: String,
+    id: u64,
+    tx: watch::Sender<Option<FlightResult>>,
+}
+
+/// 请求的 URL 和转发给上游的请求头相同时视为同一请求
+pub(crate) fn flight_key(url: &str, headers: &HeaderMap) -> String {
+    let mut pairs: Vec<(&str, &[u8])> = headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+        .collect();
+    pairs.sort();
+    let mut key = url.to_string();
+    for (name, value) in pairs {
+        key.push('\n');
+        key.push_str(name);
+        key.push(':');
+        key.push_str(&String::from_utf8_lossy(value));
+    }
+    key
+}
+
+pub(crate) fn join(key: String) -> Joined {
+    let mut flights = FLIGHTS.lock().unwrap();
+    if let Some(flight) = flights.get_mut(&key) {
+        flight.followers += 1;
+        return Joined::Follower(flight.rx.clone());
+    }
+    let id = FLIGHT_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = watch::channel(None);
+    flights.insert(
+        key.clone(),
+        Flight {
+            id,
+            followers: 0,
+            rx,
+        },
+    );
+    Joined::Leader(Leader { key, id, tx })
+}
+
+/// 等待首个请求的结果, 首个请求失败或放弃共享时返回 None
+pub(crate) async fn wait(mut rx: watch::Receiver<Option<FlightResult>>) -> FlightResult {
+    match rx.wait_for(Option::is_some).await {
+        Ok(result) => result.clone().flatten(),
+        Err(_) => None,
+    }
+}
+
+impl Leader {
+    // 从等待表中移除, 之后到达的请求会重新发起
+    fn take_followers(&self) -> usize {
+        let mut flights = FLIGHTS.lock().unwrap();
+        match flights.get(&self.key) {
+            Some(flight) if flight.id == self.id => flights
+                .remove(&self.key)
+                .map_or(0, |flight| flight.followers),
+            _ => 0,
+        }
+    }
+
+    /// 没有等待者时原样返回响应; 否则读取完整响应体分发给等待者,
+    /// 事件流和过大的响应不共享, 等待者各自请求
+    pub(crate) async fn finish(self, response: reqwest::Response) -> reqwest::Response {
+        if self.take_followers() == 0 {
+            return response;
+        }
+        match buffer_response(response, MAX_SHARED_BYTES).await {
+            Ok(shared) => {
+                let shared = Arc::new(shared);
+                let _ = self.tx.send(Some(Some(shared.clone())));
+                shared.to_response()
+            }
+            Err(response) => {
+                let _ = self.tx.send(Some(None));
+                response
+            }
+        }
+    }
+
+    /// 分发已读取的结果, 用于瓦片等不直接转发上游响应的请求
+    pub(crate) fn share(self, shared: SharedResponse) {
+        if self.take_followers() > 0 {
+            let _ = self.tx.send(Some(Some(Arc::new(shared))));
+        }
+    }
+}
+
+impl Drop for Leader {
+    // 首个请求失败或被取消时, 等待者会收到通道关闭并各自请求
+    fn drop(&mut self) {
+        let mut flights = FLIGHTS.lock().unwrap();
+        if flights
+            .get(&self.key)
+            .is_some_and(|flight| flight.id == self.id)
+        {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+/// 读取完整响应; 事件流、超过 `max_bytes` 或读取失败时返回
+/// 可继续读取的响应, 已读取的部分不会丢失
+pub(crate) async fn buffer_response(
+    mut response: reqwest::Response,
+    max_bytes: u64,
+) -> Result<SharedResponse, reqwest::Response> {
+    let event_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    let too_large = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|length| length > max_bytes);
+    if event_stream || too_large {
+        return Err(response);
+    }
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let url = response.url().clone();
+    let mut body = Vec::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                body.extend_from_slice(&chunk);
+                if body.len() as u64 > max_bytes {
+                    let head = stream::once(async move { Ok(Bytes::from(body)) });
+                    let rest = head.chain(response.bytes_stream());
+                    return Err(rebuild(status, headers, reqwest::Body::wrap_stream(rest)));
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                let failed = stream::once(async move { Err::<Bytes, _>(e) });
+                let head = stream::once(async move { Ok(Bytes::from(body)) });
+                let body = reqwest::Body::wrap_stream(head.chain(failed));
+                return Err(rebuild(status, headers, body));
+            }
+        }
+    }
+    Ok(SharedResponse {
+        status,
+        headers,
+        url,
+        body: body.into(),
+    })
+}
+
+fn rebuild(status: StatusCode, headers: HeaderMap, body: reqwest::Body) -> reqwest::Response {
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    reqwest::Response::from(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy_plugin::test_support::{proxy_url, serve, setup};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use warp::Filter;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_identical_gets_hit_upstream_once() {
+        let _guard = setup(serde_json::json!({})).await;
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        // 响应慢且不可缓存, 只能靠合并减少请求
+        let upstream = warp::path("slow")
+            .and(warp::header::optional::<String>("x-variant"))
+            .and_then(move |variant: Option<String>| {
+                let hit = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    let body = format!("{:?} {}", variant, hit);
+                    Ok::<_, warp::Rejection>(warp::reply::with_header(
+                        body,
+                        "cache-control",
+                        "no-store",
+                    ))
+                }
+            });
+        let url = proxy_url(serve(upstream), "/slow");
+        let client = reqwest::Client::new();
+        let get = |variant: &'static str| {
+            let request = client.get(&url).header("x-variant", variant).send();
+            async move {
+                let response = request.await.unwrap();
+                assert_eq!(response.status(), 200);
+                response.text().await.unwrap()
+            }
+        };
+
+        let bodies = futures_util::future::join_all((0..8).map(|_| get("a"))).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(
+            bodies.iter().all(|body| body == "Some(\"a\") 0"),
+            "{:?}",
+            bodies
+        );
+
+        // 转发的请求头不同时分别请求
+        let requests = (0..8).map(|i| get(if i % 2 == 0 { "a" } else { "b" }));
+        futures_util::future::join_all(requests).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // 上一轮结束后的请求重新发起
+        get("a").await;
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+}
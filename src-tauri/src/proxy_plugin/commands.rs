@@ -1,54 +1,258 @@
+use super::auth;
+use super::client::{self, ClientSettings};
+use super::coalesce::{self, Joined};
+use super::config;
+use super::headers;
+use super::http_cache::{self, CacheOverride, CachedResponse, HttpCacheStats};
+use super::limits::{self, HostLimitStats, LimitSettings};
+use super::mbtiles;
+use super::offline::{self, TileCorridor, TileRegion};
+use super::policy::{self, PolicyViolation, UrlPolicy};
+use super::redirect::{self, RedirectMode, SendError};
+use super::replay::{self, ReplayMode, ReplaySettings};
+use super::retry::{self, CircuitInfo};
+use super::stats::{self, ProxyStats};
+use super::thumbnail::{self, ImageCache, ImageCacheStats, ImageError, ImageOptions};
+use super::tile_cache::{self, TileCacheStats, TileKey};
+use super::tiles::{self, TileLayerInfo};
+use super::tls;
+use super::traffic::{self, Exchange, TrafficEntry, TrafficLogSettings};
+use super::transform::{self, TransformSettings};
+use super::upstream::{Route, UpstreamInfo};
+use super::ws::{self, ConnectError};
+use futures_util::TryStreamExt;
 use reqwest;
 use reqwest::header::HeaderMap as ReqwestHeaderMap;
-use reqwest::Method as ReqwestMethod;
-use std::net::TcpListener;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::string::ToString;
 use std::sync::atomic::{AtomicU16, Ordering};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Runtime};
+use tokio::sync::oneshot;
 use urlencoding::encode;
-use warp::http::header::{HeaderMap as WarpHeaderMap, HeaderName};
 use warp::http::HeaderValue;
 use warp::http::Method as WarpMethod;
+use warp::hyper::body::Buf;
 use warp::reply::Reply;
 use warp::{self, Filter};
 
 const HOST: &str = "http://127.0.0.1";
-// Store actual port and request data
+// 首选端口, 被占用时在其后 PORT_RANGE 个端口中选择
+const DEFAULT_PORT: u16 = 1430;
+const PORT_RANGE: u16 = 100;
+// 停止时等待进行中请求完成的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const READY_EVENT: &str = "proxy://ready";
+pub(crate) const STOPPED_EVENT: &str = "proxy://stopped";
+// Store actual port and request data, 未运行时为 0
 static ACTUAL_PORT: AtomicU16 = AtomicU16::new(0);
+// 当前运行的服务, 启动停止等操作依次进行
+static SERVER: tokio::sync::Mutex<Option<RunningServer>> = tokio::sync::Mutex::const_new(None);
 
-fn convert_to_reqwest_method(warp_method: WarpMethod) -> ReqwestMethod {
-    match warp_method {
-        WarpMethod::GET => ReqwestMethod::GET,
-        WarpMethod::POST => ReqwestMethod::POST,
-        WarpMethod::PUT => ReqwestMethod::PUT,
-        WarpMethod::DELETE => ReqwestMethod::DELETE,
-        // 其他方法...
-        _ => ReqwestMethod::from_bytes(warp_method.as_str().as_bytes()).unwrap(),
-    }
-}
-fn convert_to_reqwest_headers(warp_headers: &WarpHeaderMap) -> ReqwestHeaderMap {
-    let cloned_headers = warp_headers.clone();
-    let mut reqwest_headers = ReqwestHeaderMap::new();
-    for (name, value) in cloned_headers.iter() {
-        // 使用 reqwest::HeaderValue::from_bytes 显式创建值
-        let header_value = reqwest::header::HeaderValue::from_bytes(value.as_bytes())
-            .expect("Failed to convert header value");
-        reqwest_headers.insert(
-            reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes())
-                .expect("Failed to convert header name"),
-            header_value,
-        );
+struct RunningServer {
+    port: u16,
+    shutdown: oneshot::Sender<()>,
+    task: tauri::async_runtime::JoinHandle<()>,
+}
+
+// 由缓存瓦片构建响应
+fn tile_reply(data: Vec<u8>, cache_status: &'static str) -> warp::reply::Response {
+    let content_type = tile_cache::sniff_content_type(&data);
+    let mut reply = warp::http::Response::new(warp::hyper::Body::from(data));
+    let headers = reply.headers_mut();
+    headers.insert(
+        warp::http::header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type),
+    );
+    headers.insert(
+        tile_cache::CACHE_STATUS_HEADER,
+        HeaderValue::from_static(cache_status),
+    );
+    reply
+}
+
+// 从提供方取得的瓦片, 响应头注明实际提供瓦片的主机
+fn fetched_tile_reply(data: Vec<u8>, host: &str) -> warp::reply::Response {
+    let mut reply = tile_reply(data, "MISS");
+    if let Ok(value) = HeaderValue::from_str(host) {
+        reply.headers_mut().insert(tiles::PROVIDER_HEADER, value);
+    }
+    reply
+}
+
+// 不访问上游的瓦片回复同样计入流量记录, 上游请求由 tiles::fetch 记录
+fn log_tile_reply(url: Option<&str>, reply: warp::reply::Response) -> warp::reply::Response {
+    match url.and_then(|url| traffic::begin("GET", url, None)) {
+        Some(exchange) => exchange.finish(reply),
+        None => reply,
     }
-    reqwest_headers
 }
-fn convert_to_wrap_headers(reqwest_headers: ReqwestHeaderMap) -> WarpHeaderMap {
-    let mut warp_headers = WarpHeaderMap::new();
-    for (name, value) in reqwest_headers.iter() {
-        let name = HeaderName::from_bytes(name.as_str().as_bytes()).unwrap();
-        let value = HeaderValue::from_bytes(value.as_bytes()).unwrap();
-        warp_headers.insert(name, value);
+
+// 由缓存的响应构建回复
+fn cached_reply(cached: &CachedResponse, cache_status: &'static str) -> warp::reply::Response {
+    let mut reply = warp::http::Response::new(warp::hyper::Body::from(cached.body.clone()));
+    *reply.status_mut() =
+        warp::http::StatusCode::from_u16(cached.status()).unwrap_or(warp::http::StatusCode::OK);
+    let headers = reply.headers_mut();
+    *headers = headers::convert_to_warp_headers(&cached.headers());
+    headers.insert(warp::http::header::AGE, HeaderValue::from(cached.age()));
+    headers.insert(
+        tile_cache::CACHE_STATUS_HEADER,
+        HeaderValue::from_static(cache_status),
+    );
+    reply
+}
+
+// 边转发边保存响应体, 完整读取后写入 HTTP 缓存
+fn cache_while_streaming(
+    mut response: reqwest::Response,
+    cache: &'static http_cache::HttpCache,
+    url: String,
+    request_headers: ReqwestHeaderMap,
+    cache_override: Option<CacheOverride>,
+) -> warp::hyper::Body {
+    let status = response.status().as_u16();
+    let response_headers = response.headers().clone();
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tauri::async_runtime::spawn(async move {
+        let mut data = Some(Vec::new());
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    if let Some(buffer) = &mut data {
+                        if (buffer.len() + chunk.len()) as u64 > http_cache::MAX_ENTRY_BYTES {
+                            data = None;
+                        } else {
+                            buffer.extend_from_slice(&chunk);
+                        }
+                    }
+                    // 客户端已断开, 不缓存不完整的响应
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("failed to read response from {}: {}", url, e);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+        if let Some(data) = data {
+            if let Err(e) = cache
+                .put(
+                    &url,
+                    &request_headers,
+                    status,
+                    &response_headers,
+                    &data,
+                    cache_override,
+                )
+                .await
+            {
+                log::warn!("failed to cache response from {}: {}", url, e);
+            }
+        }
+    });
+    body
+}
+
+// 证书校验失败, 返回结构化错误便于前端区分
+fn tls_error_reply(host: &str, reason: &str) -> warp::reply::Response {
+    let body = serde_json::json!({
+        "error": "tls_verification_failed",
+        "host": host,
+        "reason": reason,
+    });
+    warp::reply::with_status(
+        warp::reply::json(&body),
+        warp::http::StatusCode::BAD_GATEWAY,
+    )
+    .into_response()
+}
+
+// 目标地址被访问策略拦截
+fn policy_error_reply(violation: &PolicyViolation) -> warp::reply::Response {
+    let body = serde_json::json!({
+        "error": "blocked_by_policy",
+        "host": violation.host,
+        "rule": violation.rule,
+        "detail": violation.detail,
+    });
+    warp::reply::with_status(warp::reply::json(&body), warp::http::StatusCode::FORBIDDEN)
+        .into_response()
+}
+
+fn image_error_reply(error: &ImageError) -> warp::reply::Response {
+    let body = serde_json::json!({
+        "error": error.code(),
+        "message": error.to_string(),
+    });
+    let status = warp::http::StatusCode::from_u16(error.status())
+        .unwrap_or(warp::http::StatusCode::BAD_REQUEST);
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+fn image_reply(
+    data: Vec<u8>,
+    options: &ImageOptions,
+    cache_status: &'static str,
+) -> warp::reply::Response {
+    let mut reply = warp::http::Response::new(warp::hyper::Body::from(data));
+    let headers = reply.headers_mut();
+    headers.insert(
+        warp::http::header::CONTENT_TYPE,
+        HeaderValue::from_static(options.format.content_type()),
+    );
+    headers.insert(
+        tile_cache::CACHE_STATUS_HEADER,
+        HeaderValue::from_static(cache_status),
+    );
+    reply
+}
+
+// 上游不可用时返回过期的缓存, 网络不可用时返回旧瓦片
+fn stale_reply(
+    cached: Option<&CachedResponse>,
+    stale_tile: Option<Vec<u8>>,
+) -> Option<warp::reply::Response> {
+    if let Some(entry) = cached.filter(|entry| !entry.must_revalidate()) {
+        return Some(cached_reply(entry, "STALE"));
     }
-    warp_headers
+    stale_tile.map(|data| tile_reply(data, "STALE"))
+}
+
+// 回放模式下没有对应的录制
+fn fixture_missing_reply(method: &str, url: &str) -> warp::reply::Response {
+    let body = serde_json::json!({
+        "error": "fixture_not_found",
+        "method": method,
+        "url": url,
+    });
+    warp::reply::with_status(
+        warp::reply::json(&body),
+        warp::http::StatusCode::GATEWAY_TIMEOUT,
+    )
+    .into_response()
+}
+
+// 熔断或限流时返回 503, Retry-After 为建议等待的秒数
+fn unavailable_reply(error: &str, host: &str, wait: Duration) -> warp::reply::Response {
+    let secs = wait.as_secs() + 1;
+    let body = serde_json::json!({
+        "error": error,
+        "host": host,
+        "retryAfterSecs": secs,
+    });
+    let reply = warp::reply::with_status(
+        warp::reply::json(&body),
+        warp::http::StatusCode::SERVICE_UNAVAILABLE,
+    );
+    warp::reply::with_header(reply, warp::http::header::RETRY_AFTER, secs.to_string())
+        .into_response()
 }
 
 async fn handle_proxy_request(
@@ -57,8 +261,8 @@ async fn handle_proxy_request(
     params: Option<String>,
     method: warp::http::Method,
     headers: warp::http::HeaderMap,
-    body: warp::hyper::body::Bytes,
-) -> Result<impl warp::Reply, warp::Rejection> {
+    body: reqwest::Body,
+) -> Result<warp::reply::Response, warp::Rejection> {
     // 解码URL
     let url = match urlencoding::decode(encoded_url) {
         Ok(decoded) => decoded,
@@ -87,40 +291,499 @@ async fn handle_proxy_request(
         uri.to_string()
     };
     // 解码headers
-    let decoded_headers = urlencoding::decode(headers_part).unwrap_or_default();
+    let header_map = match headers::decode_header_segment(headers_part) {
+        Ok(header_map) => header_map,
+        Err(e) => {
+            let reply = warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST);
+            return Ok(reply.into_response());
+        }
+    };
+    let route = Route::Proxy(headers_part.to_string());
+    forward_request(uri, header_map, method, headers, body, route).await
+}
+
+// 图片缩放, 源图片经过与 /proxy 相同的转发流程, 缩放结果缓存在磁盘上
+async fn handle_image_request(
+    headers_part: &str,
+    encoded_url: &str,
+    query: HashMap<String, String>,
+    headers: warp::http::HeaderMap,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let started = Instant::now();
+    let options = match ImageOptions::from_query(&query) {
+        Ok(options) => options,
+        Err(e) => return Ok(image_error_reply(&e)),
+    };
+    let url = match urlencoding::decode(encoded_url)
+        .ok()
+        .and_then(|url| reqwest::Url::parse(&url).ok())
+    {
+        Some(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => {
+            let reply = warp::reply::with_status(
+                "Invalid target URL".to_string(),
+                warp::http::StatusCode::BAD_REQUEST,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+    let mut header_map = match headers::decode_header_segment(headers_part) {
+        Ok(header_map) => header_map,
+        Err(e) => {
+            let reply = warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST);
+            return Ok(reply.into_response());
+        }
+    };
+    // 缩放需要最终的图片内容, 未指定时由代理跟随跳转
+    if !header_map.contains_key(redirect::MODE_HEADER) {
+        header_map.insert(
+            redirect::MODE_HEADER,
+            reqwest::header::HeaderValue::from_static("follow"),
+        );
+    }
+    let host = url.host_str().unwrap_or_default().to_string();
+    let cache = thumbnail::image_cache();
+    let key = ImageCache::key(url.as_str(), headers_part, &options);
+    if let Some(data) = match cache {
+        Some(cache) => cache.get(&key).await,
+        None => None,
+    } {
+        return Ok(stats::record(
+            &host,
+            None,
+            started,
+            image_reply(data, &options, "HIT"),
+        ));
+    }
+
+    let route = Route::Proxy(headers_part.to_string());
+    let reply = send_upstream(
+        url.to_string(),
+        header_map,
+        WarpMethod::GET,
+        headers,
+        reqwest::Body::from(Vec::new()),
+        route,
+        None,
+    )
+    .await?;
+    // 上游出错时原样返回, 包括访问策略和熔断的错误信息
+    if !reply.status().is_success() {
+        return Ok(stats::record(&host, None, started, reply));
+    }
+    let result = match thumbnail::read_source(reply.into_body()).await {
+        Ok(data) => {
+            let render_options = options.clone();
+            tauri::async_runtime::spawn_blocking(move || thumbnail::render(&data, &render_options))
+                .await
+                .unwrap_or_else(|e| Err(ImageError::Processing(e.to_string())))
+        }
+        Err(e) => Err(e),
+    };
+    let reply = match result {
+        Ok(data) => {
+            if let Some(cache) = cache {
+                if let Err(e) = cache.put(&key, &data).await {
+                    log::warn!("failed to cache image variant for {}: {}", url, e);
+                }
+            }
+            image_reply(data, &options, "MISS")
+        }
+        Err(e) => image_error_reply(&e),
+    };
+    Ok(stats::record(&host, None, started, reply))
+}
+
+async fn handle_ws_request(
+    headers_part: &str,
+    encoded_url: &str,
+    upgrade: warp::ws::Ws,
+    headers: warp::http::HeaderMap,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let url = match urlencoding::decode(encoded_url)
+        .ok()
+        .and_then(|url| reqwest::Url::parse(&url).ok())
+    {
+        Some(url) if url.scheme() == "ws" || url.scheme() == "wss" => url,
+        _ => {
+            let reply = warp::reply::with_status(
+                "Invalid target URL".to_string(),
+                warp::http::StatusCode::BAD_REQUEST,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+    let header_map = match headers::decode_header_segment(headers_part) {
+        Ok(header_map) => header_map,
+        Err(e) => {
+            let reply = warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST);
+            return Ok(reply.into_response());
+        }
+    };
+    // WebSocket 不录制, 回放模式下不连接上游
+    if config::current().replay.mode == ReplayMode::Replay {
+        return Ok(fixture_missing_reply("GET", url.as_str()));
+    }
+    // 先连接上游, 失败时不升级客户端连接
+    let (upstream, protocol) = match ws::connect_upstream(&url, header_map, &headers).await {
+        Ok(connected) => connected,
+        Err(ConnectError::Policy(violation)) => return Ok(policy_error_reply(&violation)),
+        Err(ConnectError::Tls(reason)) => {
+            return Ok(tls_error_reply(url.host_str().unwrap_or_default(), &reason))
+        }
+        Err(ConnectError::Other(e)) => {
+            let reply = warp::reply::with_status(
+                format!("WebSocket connection failed: {}", e),
+                warp::http::StatusCode::BAD_GATEWAY,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+    let mut reply = upgrade
+        .on_upgrade(move |socket| ws::pipe(socket, upstream))
+        .into_response();
+    // 上游选定的子协议需要告知客户端
+    if let Some(protocol) = protocol.and_then(|p| HeaderValue::from_str(&p).ok()) {
+        reply
+            .headers_mut()
+            .insert(warp::http::header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    Ok(reply)
+}
+
+async fn handle_upstream_request(
+    name: &str,
+    path: &str,
+    params: Option<String>,
+    method: warp::http::Method,
+    headers: warp::http::HeaderMap,
+    body: reqwest::Body,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let config = config::current();
+    let profile = match config.upstreams.get(name) {
+        Some(profile) => profile,
+        None => {
+            let reply = warp::reply::with_status(
+                format!("Unknown upstream: {}", name),
+                warp::http::StatusCode::NOT_FOUND,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+    // key 缺失属于配置错误, 不把请求发往上游
+    if let Err(e) = profile.key(name) {
+        let reply = warp::reply::with_status(e, warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(reply.into_response());
+    }
+    let uri = match profile.target_url(name, path, params.as_deref()) {
+        Ok(uri) => uri,
+        Err(e) => {
+            let reply = warp::reply::with_status(e, warp::http::StatusCode::BAD_REQUEST);
+            return Ok(reply.into_response());
+        }
+    };
     let mut header_map = reqwest::header::HeaderMap::new();
-    if !decoded_headers.is_empty() {
-        for header_pair in decoded_headers.split(',') {
-            if let Some((name_part, value_part)) = header_pair.split_once(':') {
-                if let Ok(header_name) = name_part.parse::<http::header::HeaderName>() {
-                    if let Ok(header_value) = http::HeaderValue::from_str(value_part) {
-                        header_map.insert(header_name, header_value);
-                    }
+    if let Err(e) = profile.inject_headers(name, &mut header_map) {
+        let reply = warp::reply::with_status(e, warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Ok(reply.into_response());
+    }
+    let route = Route::Upstream(name.to_string(), Box::new(profile.clone()));
+    forward_request(uri, header_map, method, headers, body, route).await
+}
+
+// 瓦片路由, 主提供方出错时依次尝试备用提供方, 只缓存主提供方的瓦片
+async fn handle_tile_request(
+    layer: &str,
+    z: u32,
+    x: u32,
+    y: &str,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let started = Instant::now();
+    let config = config::current();
+    let tile_layer = match config.tile_layers.get(layer) {
+        Some(tile_layer) => tile_layer,
+        None => {
+            let reply = warp::reply::with_status(
+                format!("Unknown tile layer: {}", layer),
+                warp::http::StatusCode::NOT_FOUND,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+    // y 可以带扩展名, 例如 12.png
+    let key = y
+        .split('.')
+        .next()
+        .and_then(|y| y.parse().ok())
+        .and_then(|y| TileKey::new(layer, z, x, y))
+        .filter(|key| tile_layer.has_zoom(key.z));
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let reply = warp::reply::with_status(
+                "Invalid tile coordinates".to_string(),
+                warp::http::StatusCode::NOT_FOUND,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+    // 录制和回放时不使用缓存
+    let cache = match config.replay.mode {
+        ReplayMode::Off => tile_cache::tile_cache(),
+        _ => None,
+    };
+    // 缓存命中和失败时计入主提供方
+    let primary_host = tile_layer
+        .providers
+        .first()
+        .and_then(|provider| provider.hosts().into_iter().next())
+        .unwrap_or_default();
+    let stale_tile = match cache {
+        Some(_) => match tile_cache::lookup(&key).await {
+            Some(tile) if tile.fresh => {
+                let reply = tile_reply(tile.data, "HIT");
+                let reply = stats::record(&primary_host, None, started, reply);
+                let url = tile_layer.primary_url(&config, &key);
+                return Ok(log_tile_reply(url.as_deref(), reply));
+            }
+            Some(tile) => Some(tile.data),
+            None => None,
+        },
+        None => None,
+    };
+
+    // 同一瓦片同时未命中时只请求一次上游, 首个请求失败时等待者各自请求
+    let flight = coalesce::join(format!("tile\n{}/{}/{}/{}", key.layer, key.z, key.x, key.y));
+    let leader = match flight {
+        Joined::Leader(leader) => Some(leader),
+        Joined::Follower(rx) => match coalesce::wait(rx).await {
+            Some(shared) => {
+                let host = shared
+                    .headers
+                    .get(tiles::PROVIDER_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+                let reply = fetched_tile_reply(shared.body.to_vec(), host);
+                let reply = stats::record(host, None, started, reply);
+                return Ok(log_tile_reply(Some(shared.url.as_str()), reply));
+            }
+            None => None,
+        },
+    };
+    let reply = match tiles::fetch(&config, tile_layer, &key).await {
+        Ok(tile) => {
+            if let (Some(cache), 0) = (cache, tile.provider) {
+                if let Err(e) = cache.put(&key, &tile.data).await {
+                    log::warn!("failed to cache tile {:?}: {}", key, e);
                 }
             }
+            // 写入缓存后再分发, 之后到达的请求直接命中缓存
+            if let Some(leader) = leader {
+                leader.share(tile.to_shared());
+            }
+            let reply = fetched_tile_reply(tile.data, &tile.host);
+            stats::record(&tile.host, None, started, reply)
+        }
+        Err(errors) => {
+            let reply = match stale_tile {
+                Some(data) => {
+                    let url = tile_layer.primary_url(&config, &key);
+                    log_tile_reply(url.as_deref(), tile_reply(data, "STALE"))
+                }
+                None => {
+                    let body = serde_json::json!({
+                        "error": "tile_unavailable",
+                        "layer": layer,
+                        "errors": errors,
+                    });
+                    warp::reply::with_status(
+                        warp::reply::json(&body),
+                        warp::http::StatusCode::BAD_GATEWAY,
+                    )
+                    .into_response()
+                }
+            };
+            stats::record(&primary_host, None, started, reply)
+        }
+    };
+    Ok(reply)
+}
+
+// 转发请求到目标地址, header_map 中的请求头优先于 webview 传入的请求头
+async fn forward_request(
+    uri: String,
+    mut header_map: ReqwestHeaderMap,
+    method: warp::http::Method,
+    mut headers: warp::http::HeaderMap,
+    body: reqwest::Body,
+    route: Route,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let request_size = headers
+        .get(warp::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let started = Instant::now();
+    let host = reqwest::Url::parse(&uri)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let transform = transform::resolve(&mut header_map, &mut headers, &route);
+    let mut exchange = traffic::begin(method.as_str(), &uri, request_size);
+    let reply = send_upstream(
+        uri,
+        header_map,
+        method,
+        headers,
+        body,
+        route,
+        exchange.as_mut(),
+    )
+    .await?;
+    let reply = transform::apply(transform, reply).await;
+    let reply = stats::record(&host, request_size, started, reply);
+    Ok(match exchange {
+        Some(exchange) => exchange.finish(reply),
+        None => reply,
+    })
+}
+
+async fn send_upstream(
+    uri: String,
+    mut header_map: ReqwestHeaderMap,
+    method: warp::http::Method,
+    headers: warp::http::HeaderMap,
+    body: reqwest::Body,
+    route: Route,
+    exchange: Option<&mut Exchange>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let config = config::current();
+    let url = reqwest::Url::parse(&uri).ok();
+    if let Some(url) = &url {
+        if let Err(violation) = policy::check(&config, url) {
+            return Ok(policy_error_reply(&violation));
         }
     }
-    let reqwest_headers = convert_to_reqwest_headers(&headers);
+    // 录制和回放时不使用缓存, 保证结果可复现
+    let replaying = config.replay.mode != ReplayMode::Off;
+    // 天地图瓦片先查磁盘缓存, 未过期直接返回; 分段请求直接转发
+    let ranged = headers.contains_key("range") || header_map.contains_key("range");
+    let tile_key = if method == WarpMethod::GET && !ranged && !replaying {
+        url.as_ref().and_then(TileKey::from_url)
+    } else {
+        None
+    };
+    let stale_tile = match &tile_key {
+        Some(key) => match tile_cache::lookup(key).await {
+            Some(tile) if tile.fresh => return Ok(tile_reply(tile.data, "HIT")),
+            Some(tile) => Some(tile.data),
+            None => None,
+        },
+        None => None,
+    };
+    let reqwest_headers = headers::convert_to_reqwest_headers(&headers);
     let excluded_headers: [reqwest::header::HeaderName; 3] = [
         reqwest::header::HeaderName::from_static("host"),
         reqwest::header::HeaderName::from_static("referer"),
         reqwest::header::HeaderName::from_static("origin"),
     ];
-    // 遍历源 headers
-    for (name, value) in reqwest_headers.iter() {
-        // 检查头部是否不在排除列表中且不在目标 header_map 中
-        if !excluded_headers.contains(&name) && !header_map.contains_key(name) {
-            // 由于 HeaderValue 是不可变的，我们可以直接克隆它
-            header_map.insert(name.clone(), value.clone());
+    headers::merge_headers(&mut header_map, &reqwest_headers, &excluded_headers);
+    // x-proxy-cache-ttl 只给代理使用, 不转发给上游
+    let cache_override = header_map
+        .remove(http_cache::OVERRIDE_HEADER)
+        .and_then(|value| value.to_str().ok().and_then(CacheOverride::parse));
+    // 重定向模式: 请求头 > 命名上游 > 全局配置
+    let redirect_mode = header_map
+        .remove(redirect::MODE_HEADER)
+        .and_then(|value| value.to_str().ok().and_then(RedirectMode::parse))
+        .or(route.profile().and_then(|profile| profile.redirect))
+        .unwrap_or(config.redirect.mode);
+    let retry_policy = route
+        .profile()
+        .and_then(|profile| profile.retry.as_ref())
+        .unwrap_or(&config.retry);
+
+    // 共享的HTTP客户端
+    let client = match client::for_route(&route) {
+        Ok(client) => client,
+        Err(e) => {
+            let reply = warp::reply::with_status(
+                format!("Failed to build HTTP client: {}", e),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+
+    let method = match headers::convert_to_reqwest_method(&method) {
+        Some(method) => method,
+        None => {
+            let reply = warp::reply::with_status(
+                "Unsupported method".to_string(),
+                warp::http::StatusCode::METHOD_NOT_ALLOWED,
+            );
+            return Ok(reply.into_response());
+        }
+    };
+
+    // 瓦片由瓦片缓存处理, 其余 GET 请求使用 HTTP 缓存
+    let http_cache = match http_cache::http_cache() {
+        Some(cache)
+            if tile_key.is_none()
+                && !replaying
+                && http_cache::request_cacheable(&method, &header_map, cache_override) =>
+        {
+            Some(cache)
+        }
+        _ => None,
+    };
+    let cached = match http_cache {
+        Some(cache) => cache.get(&uri, &header_map).await,
+        None => None,
+    };
+    let request_headers = http_cache.map(|_| header_map.clone());
+    if let Some(entry) = &cached {
+        if entry.is_fresh() && !http_cache::request_requires_revalidation(&header_map) {
+            return Ok(cached_reply(entry, "HIT"));
+        }
+        // 过期后带上校验信息向上游确认
+        for (name, value) in entry.validators() {
+            header_map.insert(name, value);
         }
     }
 
-    // 创建HTTP客户端
-    let client = get_default_http_client();
+    // 回放模式只返回录制的响应, 不访问网络
+    let fixture = replay::fixture(&config.replay, &method, &uri, &header_map);
+    let replayed = match (&fixture, config.replay.mode) {
+        (Some(fixture), ReplayMode::Replay) => match fixture.load().await {
+            Some(response) => Some(Arc::new(response)),
+            None => return Ok(fixture_missing_reply(method.as_str(), &uri)),
+        },
+        // 没有夹具目录时同样不访问网络
+        (None, ReplayMode::Replay) => return Ok(fixture_missing_reply(method.as_str(), &uri)),
+        _ => None,
+    };
+
+    // 相同的并发 GET 请求只向上游请求一次
+    let flight = match method {
+        reqwest::Method::GET if replayed.is_none() => {
+            Some(coalesce::join(coalesce::flight_key(&uri, &header_map)))
+        }
+        _ => None,
+    };
+    let (leader, shared) = match flight {
+        Some(Joined::Leader(leader)) => (Some(leader), None),
+        Some(Joined::Follower(rx)) => (None, coalesce::wait(rx).await),
+        None => (None, replayed),
+    };
+
+    // 共享首个请求的响应时没有发出请求
+    if let (Some(exchange), None) = (exchange, &shared) {
+        exchange.request_headers(&header_map);
+    }
 
     // 构建请求
     let reqwest_request = match client
-        .request(convert_to_reqwest_method(method), uri)
+        .request(method, &uri)
         .headers(header_map)
         .body(body)
         .build()
@@ -135,9 +798,54 @@ async fn handle_proxy_request(
         }
     };
 
-    let response = match client.execute(reqwest_request).await {
+    let host = reqwest_request
+        .url()
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+    let max_hops = match redirect_mode {
+        RedirectMode::Follow => config.redirect.max_hops,
+        _ => 0,
+    };
+    let result = match &shared {
+        Some(shared) => Ok(shared.to_response()),
+        None => {
+            retry::execute(
+                &client,
+                &config,
+                reqwest_request,
+                max_hops,
+                retry_policy,
+                route.profile(),
+            )
+            .await
+        }
+    };
+    let response = match result {
         Ok(res) => res,
-        Err(e) => {
+        // 跳转目标不符合访问策略
+        Err(SendError::Policy(violation)) => return Ok(policy_error_reply(&violation)),
+        // 主机熔断中或限流排队超时, 有缓存时返回缓存
+        Err(SendError::CircuitOpen(wait)) => {
+            let reply = stale_reply(cached.as_ref(), stale_tile);
+            return Ok(reply.unwrap_or_else(|| unavailable_reply("circuit_open", &host, wait)));
+        }
+        Err(SendError::Throttled(wait)) => {
+            let reply = stale_reply(cached.as_ref(), stale_tile);
+            return Ok(reply.unwrap_or_else(|| unavailable_reply("rate_limited", &host, wait)));
+        }
+        Err(SendError::Request(e)) => {
+            // 证书校验失败不回退到缓存
+            if let Some(reason) = tls::verification_error(&e) {
+                return Ok(tls_error_reply(&host, &reason));
+            }
+            // 域名解析到了被禁止的地址
+            if let Some(violation) = policy::violation_error(&e) {
+                return Ok(policy_error_reply(&violation));
+            }
+            if let Some(reply) = stale_reply(cached.as_ref(), stale_tile) {
+                return Ok(reply);
+            }
             let reply = warp::reply::with_status(
                 format!("Request failed: {}", e),
                 warp::http::StatusCode::BAD_GATEWAY,
@@ -145,22 +853,101 @@ async fn handle_proxy_request(
             return Ok(reply.into_response());
         }
     };
+    let final_url = match &shared {
+        Some(shared) => shared.url.clone(),
+        None => response.url().clone(),
+    };
+    let response = match leader {
+        Some(leader) => leader.finish(response).await,
+        None => response,
+    };
+    // 共享的响应已由首个请求录制
+    let response = match (&fixture, config.replay.mode, &shared) {
+        (Some(fixture), ReplayMode::Record, None) => fixture.record(response).await,
+        _ => response,
+    };
     // 转换响应状态码
     let status = warp::http::StatusCode::from_u16(response.status().as_u16())
         .unwrap_or(warp::http::StatusCode::INTERNAL_SERVER_ERROR);
 
     // 转换响应头（过滤掉非法头）
-    let mut headers = warp::http::HeaderMap::new();
-    for (key, value) in response.headers() {
-        if let Ok(name) = warp::http::HeaderName::from_bytes(key.as_ref()) {
-            if let Ok(val) = warp::http::HeaderValue::from_bytes(value.as_bytes()) {
-                headers.insert(name, val);
+    let mut headers = headers::convert_to_warp_headers(response.headers());
+
+    // 移除可能冲突的头部
+    headers.remove(warp::http::header::CONNECTION);
+
+    if redirect_mode == RedirectMode::Rewrite && redirect::is_redirect(response.status()) {
+        // 停止过程中完成的请求没有可用的代理地址, 保留原地址
+        if let (Some(location), Ok(base)) = (
+            redirect::location(response.headers(), &final_url),
+            proxy_base(),
+        ) {
+            let rewritten = redirect::rewrite_location(&location, &final_url, &route, &base);
+            if let Ok(value) = HeaderValue::from_str(&rewritten) {
+                headers.insert(warp::http::header::LOCATION, value);
             }
         }
     }
 
-    // 移除可能冲突的头部
-    headers.remove(warp::http::header::CONNECTION);
+    if let (Some(cache), Some(mut entry)) = (http_cache, cached) {
+        if status == warp::http::StatusCode::NOT_MODIFIED {
+            if let Err(e) = cache.refresh(&mut entry, response.headers()).await {
+                log::warn!("failed to refresh cached response for {}: {}", uri, e);
+            }
+            return Ok(cached_reply(&entry, "REVALIDATED"));
+        }
+    }
+    // 共享的响应已由首个请求写入缓存
+    let store = shared.is_none();
+    if let (Some(cache), Some(request_headers), true) = (http_cache, request_headers, store) {
+        if http_cache::response_storable(status.as_u16(), response.headers(), cache_override) {
+            headers.insert(
+                tile_cache::CACHE_STATUS_HEADER,
+                HeaderValue::from_static("MISS"),
+            );
+            let body = cache_while_streaming(response, cache, uri, request_headers, cache_override);
+            let mut reply = warp::http::Response::new(body);
+            *reply.status_mut() = status;
+            *reply.headers_mut() = headers;
+            return Ok(reply);
+        }
+    }
+
+    if let (Some(key), Some(cache)) = (tile_key, tile_cache::tile_cache()) {
+        headers.insert(
+            tile_cache::CACHE_STATUS_HEADER,
+            HeaderValue::from_static("MISS"),
+        );
+        if !status.is_success() {
+            if let Some(data) = stale_tile {
+                return Ok(tile_reply(data, "STALE"));
+            }
+        } else {
+            // 瓦片体积很小, 完整读取后写入缓存
+            let data = match response.bytes().await {
+                Ok(data) => data,
+                Err(e) => {
+                    if let Some(data) = stale_tile {
+                        return Ok(tile_reply(data, "STALE"));
+                    }
+                    let reply = warp::reply::with_status(
+                        format!("Request failed: {}", e),
+                        warp::http::StatusCode::BAD_GATEWAY,
+                    );
+                    return Ok(reply.into_response());
+                }
+            };
+            if store {
+                if let Err(e) = cache.put(&key, &data).await {
+                    log::warn!("failed to cache tile {:?}: {}", key, e);
+                }
+            }
+            let mut reply = warp::http::Response::new(warp::hyper::Body::from(data));
+            *reply.status_mut() = status;
+            *reply.headers_mut() = headers;
+            return Ok(reply);
+        }
+    }
 
     // 转换响应体为流
     let stream = response.bytes_stream();
@@ -173,27 +960,12 @@ async fn handle_proxy_request(
     Ok(reply)
 }
 
-fn get_default_http_client() -> reqwest::Client {
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        reqwest::header::HeaderName::from_static("user-agent"),
-        reqwest::header::HeaderValue::from_static(
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/136.0.0.0 Safari/537.36",
-        ),
-    );
-    headers.insert(
-        reqwest::header::HeaderName::from_static("upgrade-insecure-requests"),
-        reqwest::header::HeaderValue::from_static("1"),
-    );
-    return reqwest::Client::builder()
-        .use_rustls_tls()
-        .default_headers(headers)
-        .redirect(reqwest::redirect::Policy::none())
-        .danger_accept_invalid_certs(true)
-        .danger_accept_invalid_hostnames(true)
-        .connect_timeout(Duration::from_secs(15))
-        .build()
-        .unwrap();
+// 带访问 token 的代理地址前缀, 未运行时没有可用的地址
+fn proxy_base() -> Result<String, String> {
+    match ACTUAL_PORT.load(Ordering::SeqCst) {
+        0 => Err("Proxy server is not running".to_string()),
+        port => Ok(base_url(port)),
+    }
 }
 
 #[tauri::command]
@@ -201,54 +973,365 @@ pub(crate) fn get_proxy_url(
     url: &str,
     headers: Option<Vec<(String, String)>>,
 ) -> Result<String, String> {
-    let port = ACTUAL_PORT.load(Ordering::SeqCst);
-
     let encoded_url = encode(url);
+    let headers_part = headers::encode_header_segment(&headers.unwrap_or_default());
 
-    let mut headers_part = if let Some(h) = headers {
-        let mut headers_str = String::new();
-        for (name, value) in h.iter() {
-            headers_str.push_str(&format!("{}:{},", name.as_str(), value));
-        }
-        // 移除最后的逗号
-        if !headers_str.is_empty() {
-            headers_str.pop();
-        }
-        encode(&headers_str).into_owned()
-    } else {
-        encode("").into_owned()
-    };
-    if headers_part.is_empty() {
-        headers_part = encode("_").into_owned();
-    }
+    Ok(format!(
+        "{}/proxy/{}/{}",
+        proxy_base()?,
+        headers_part,
+        encoded_url
+    ))
+}
+
+/// 缩放图片的地址, 请求头的编码方式与 get_proxy_url 相同
+#[tauri::command]
+pub(crate) fn get_image_url(
+    url: &str,
+    options: ImageOptions,
+    headers: Option<Vec<(String, String)>>,
+) -> Result<String, String> {
+    options.validate().map_err(|e| e.to_string())?;
+    let headers_part = headers::encode_header_segment(&headers.unwrap_or_default());
+    Ok(format!(
+        "{}/img/{}/{}?{}",
+        proxy_base()?,
+        headers_part,
+        encode(url),
+        options.to_query()
+    ))
+}
 
+/// WebSocket 代理地址, 请求头的编码方式与 get_proxy_url 相同
+#[tauri::command]
+pub(crate) fn get_proxy_ws_url(
+    url: &str,
+    headers: Option<Vec<(String, String)>>,
+) -> Result<String, String> {
+    let port = match ACTUAL_PORT.load(Ordering::SeqCst) {
+        0 => return Err("Proxy server is not running".to_string()),
+        port => port,
+    };
+    let headers_part = headers::encode_header_segment(&headers.unwrap_or_default());
     Ok(format!(
-        "{}:{}/proxy/{}/{}",
-        HOST, port, headers_part, encoded_url
+        "ws://127.0.0.1:{}/{}/ws/{}/{}",
+        port,
+        auth::token(),
+        headers_part,
+        encode(url)
     ))
 }
 
+/// 代理地址前缀, 包含访问 token, 前端拼接 `/upstream/...` 等路径时使用
+#[tauri::command]
+pub(crate) fn get_proxy_base_url() -> Result<String, String> {
+    proxy_base()
+}
+
 #[tauri::command]
 pub(crate) fn get_proxy_port() -> Result<u16, u16> {
     let port = ACTUAL_PORT.load(Ordering::SeqCst);
     Ok(port)
 }
 
-fn find_available_port(start_port: u16) -> Option<u16> {
-    (start_port..=start_port + 100).find(|port| TcpListener::bind(("127.0.0.1", *port)).is_ok())
+#[tauri::command]
+pub(crate) fn get_tile_cache_stats() -> Result<TileCacheStats, String> {
+    tile_cache::tile_cache()
+        .map(|cache| cache.stats())
+        .ok_or_else(|| "Tile cache is not initialized".to_string())
 }
 
-pub(crate) fn start_proxy_server() -> std::io::Result<()> {
-    let port = find_available_port(1430).ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::AddrInUse, "No available port found")
-    })?;
+#[tauri::command]
+pub(crate) fn clear_tile_cache() -> Result<(), String> {
+    let cache =
+        tile_cache::tile_cache().ok_or_else(|| "Tile cache is not initialized".to_string())?;
+    cache.clear().map_err(|e| e.to_string())
+}
 
-    ACTUAL_PORT.store(port, Ordering::SeqCst);
+#[tauri::command]
+pub(crate) fn get_image_cache_stats() -> Result<ImageCacheStats, String> {
+    thumbnail::image_cache()
+        .map(|cache| cache.stats())
+        .ok_or_else(|| "Image cache is not initialized".to_string())
+}
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
-        .allow_headers(vec!["Content-Type"]);
+#[tauri::command]
+pub(crate) fn clear_image_cache() -> Result<(), String> {
+    let cache =
+        thumbnail::image_cache().ok_or_else(|| "Image cache is not initialized".to_string())?;
+    cache.clear().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub(crate) fn get_offline_tile_stats() -> Result<TileCacheStats, String> {
+    tile_cache::offline_store()
+        .map(|store| store.stats())
+        .ok_or_else(|| "Offline store is not initialized".to_string())
+}
+
+#[tauri::command]
+pub(crate) fn clear_offline_tiles() -> Result<(), String> {
+    let store = tile_cache::offline_store()
+        .ok_or_else(|| "Offline store is not initialized".to_string())?;
+    store.clear().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub(crate) fn get_http_cache_stats() -> Result<HttpCacheStats, String> {
+    http_cache::http_cache()
+        .map(|cache| cache.stats())
+        .ok_or_else(|| "HTTP cache is not initialized".to_string())
+}
+
+#[tauri::command]
+pub(crate) fn clear_http_cache() -> Result<(), String> {
+    let cache =
+        http_cache::http_cache().ok_or_else(|| "HTTP cache is not initialized".to_string())?;
+    cache.clear().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub(crate) fn list_upstreams() -> Result<Vec<UpstreamInfo>, String> {
+    Ok(config::current()
+        .upstreams
+        .iter()
+        .map(|(name, profile)| profile.info(name))
+        .collect())
+}
+
+#[tauri::command]
+pub(crate) fn reload_proxy_config() -> Result<(), String> {
+    config::reload()
+}
+
+#[tauri::command]
+pub(crate) fn get_client_settings() -> Result<ClientSettings, String> {
+    Ok(config::current().client.clone())
+}
+
+#[tauri::command]
+pub(crate) fn set_client_settings(settings: ClientSettings) -> Result<(), String> {
+    config::update(|config| config.client = settings)
+}
+
+#[tauri::command]
+pub(crate) fn rebuild_http_client() -> Result<(), String> {
+    client::rebuild(&config::current())
+}
+
+#[tauri::command]
+pub(crate) fn get_url_policy() -> Result<UrlPolicy, String> {
+    Ok(config::current().policy.clone())
+}
+
+#[tauri::command]
+pub(crate) fn set_url_policy(policy: UrlPolicy) -> Result<(), String> {
+    config::update(|config| config.policy = policy)
+}
+
+#[tauri::command]
+pub(crate) fn get_circuit_states() -> Result<Vec<CircuitInfo>, String> {
+    Ok(retry::circuits(&config::current().breaker))
+}
+
+#[tauri::command]
+pub(crate) fn reset_circuits() -> Result<(), String> {
+    retry::reset_circuits();
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn get_rate_limits() -> Result<LimitSettings, String> {
+    Ok(config::current().limits.clone())
+}
+
+#[tauri::command]
+pub(crate) fn set_rate_limits(limits: LimitSettings) -> Result<(), String> {
+    config::update(|config| config.limits = limits)
+}
+
+#[tauri::command]
+pub(crate) fn get_rate_limit_stats() -> Result<Vec<HostLimitStats>, String> {
+    Ok(limits::stats())
+}
+
+#[tauri::command]
+pub(crate) fn get_traffic_log_settings() -> Result<TrafficLogSettings, String> {
+    Ok(config::current().traffic_log.clone())
+}
+
+#[tauri::command]
+pub(crate) fn set_traffic_log_settings(settings: TrafficLogSettings) -> Result<(), String> {
+    config::update(|config| config.traffic_log = settings)
+}
+
+#[tauri::command]
+pub(crate) fn get_traffic_log(limit: Option<usize>) -> Result<Vec<TrafficEntry>, String> {
+    Ok(traffic::entries(limit))
+}
+
+#[tauri::command]
+pub(crate) fn clear_traffic_log() -> Result<(), String> {
+    traffic::clear();
+    Ok(())
+}
+
+// Android 上由前端通过 androidfs 插件保存
+#[tauri::command]
+pub(crate) fn get_traffic_har() -> Result<String, String> {
+    serde_json::to_string_pretty(&traffic::to_har()).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub(crate) async fn export_traffic_har(path: String) -> Result<usize, String> {
+    let har = traffic::to_har();
+    let count = har["log"]["entries"].as_array().map_or(0, Vec::len);
+    let data = serde_json::to_vec_pretty(&har).map_err(|e| e.to_string())?;
+    tokio::fs::write(&path, data)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    Ok(count)
+}
+
+#[tauri::command]
+pub(crate) fn get_replay_settings() -> Result<ReplaySettings, String> {
+    Ok(config::current().replay.clone())
+}
+
+#[tauri::command]
+pub(crate) fn set_replay_settings(settings: ReplaySettings) -> Result<(), String> {
+    config::update(|config| config.replay = settings)
+}
+
+#[tauri::command]
+pub(crate) fn get_transform_settings() -> Result<TransformSettings, String> {
+    Ok(config::current().transform)
+}
+
+#[tauri::command]
+pub(crate) fn set_transform_settings(settings: TransformSettings) -> Result<(), String> {
+    config::update(|config| config.transform = settings)
+}
+
+#[tauri::command]
+pub(crate) fn get_tile_layers() -> Result<Vec<TileLayerInfo>, String> {
+    let base = proxy_base()?;
+    Ok(config::current()
+        .tile_layers
+        .iter()
+        .map(|(id, layer)| layer.info(id, &base))
+        .collect())
+}
+
+#[tauri::command]
+pub(crate) fn get_proxy_stats() -> Result<ProxyStats, String> {
+    Ok(stats::snapshot())
+}
+
+#[tauri::command]
+pub(crate) fn reset_proxy_stats() -> Result<(), String> {
+    stats::reset();
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn download_tile_region<R: Runtime>(
+    app: AppHandle<R>,
+    region: TileRegion,
+    layers: Vec<String>,
+) -> Result<u64, String> {
+    offline::start_download(app, region, layers)
+}
+
+#[tauri::command]
+pub(crate) async fn download_tile_corridor<R: Runtime>(
+    app: AppHandle<R>,
+    corridor: TileCorridor,
+    layers: Vec<String>,
+) -> Result<u64, String> {
+    offline::start_corridor_download(app, corridor, layers).await
+}
+
+#[tauri::command]
+pub(crate) fn cancel_tile_download(id: u64) -> Result<bool, String> {
+    Ok(offline::cancel_download(id))
+}
+
+#[tauri::command]
+pub(crate) async fn export_mbtiles(
+    path: String,
+    layer: String,
+    region: Option<TileRegion>,
+) -> Result<u64, String> {
+    if let Some(region) = &region {
+        region.validate()?;
+    }
+    tauri::async_runtime::spawn_blocking(move || {
+        mbtiles::export(&PathBuf::from(path), &layer, region.as_ref())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub(crate) async fn import_mbtiles(path: String, layer: Option<String>) -> Result<u64, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        mbtiles::import(&PathBuf::from(path), layer.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// 请求体以流的方式转发给上游, 不在内存中缓冲
+fn request_body() -> impl Filter<Extract = (reqwest::Body,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and(warp::body::stream())
+        .map(|length: Option<u64>, encoding: Option<String>, body| {
+            // 没有请求体时不使用分块编码
+            if length.unwrap_or(0) == 0 && encoding.is_none() {
+                return reqwest::Body::from(Vec::new());
+            }
+            stream_body(body)
+        })
+}
+
+fn stream_body<S, B>(body: S) -> reqwest::Body
+where
+    S: futures_util::Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
+{
+    reqwest::Body::wrap_stream(body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())))
+}
+
+// 端口被占用时依次尝试后面的端口
+fn default_ports() -> impl Iterator<Item = u16> {
+    DEFAULT_PORT..=DEFAULT_PORT + PORT_RANGE
+}
+
+fn routes(
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone + Send + Sync + 'static {
+    // 只允许应用自身的页面跨域访问
+    let cors = warp::cors()
+        .allow_origins(auth::allowed_origins())
+        .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
+        .allow_headers(vec![
+            "Content-Type",
+            "Range",
+            "If-Range",
+            http_cache::OVERRIDE_HEADER,
+            redirect::MODE_HEADER,
+            transform::TRANSFORM_HEADER,
+        ])
+        .expose_headers(vec![
+            "Content-Length",
+            "Content-Range",
+            "Accept-Ranges",
+            "Location",
+            tile_cache::CACHE_STATUS_HEADER,
+            tiles::PROVIDER_HEADER,
+            transform::TRANSFORM_HEADER,
+        ]);
 
     let proxy = warp::path!("proxy" / String / String)
         .and(
@@ -259,22 +1342,697 @@ pub(crate) fn start_proxy_server() -> std::io::Result<()> {
         ) // 获取可选的查询参数
         .and(warp::method()) // 获取 HTTP 方法
         .and(warp::header::headers_cloned()) // 获取请求头
-        .and(warp::body::bytes()) // 获取请求体
+        .and(request_body()) // 获取请求体
         .and_then(
             |headers_part: String,
              encoded_url: String,
              params: Option<String>,
              method: warp::http::Method,
              headers: warp::http::HeaderMap,
-             body: warp::hyper::body::Bytes| async move {
+             body: reqwest::Body| async move {
                 handle_proxy_request(&headers_part, &encoded_url, params, method, headers, body)
                     .await
             },
         );
 
-    let routers = proxy.with(cors);
+    // 命名上游, key 由 Rust 侧注入
+    let upstream = warp::path("upstream")
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail())
+        .and(
+            warp::query::raw()
+                .map(Some)
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .and(warp::method())
+        .and(warp::header::headers_cloned())
+        .and(request_body())
+        .and_then(
+            |name: String,
+             tail: warp::path::Tail,
+             params: Option<String>,
+             method: warp::http::Method,
+             headers: warp::http::HeaderMap,
+             body: reqwest::Body| async move {
+                handle_upstream_request(&name, tail.as_str(), params, method, headers, body).await
+            },
+        );
 
-    tauri::async_runtime::spawn(warp::serve(routers).run(([127, 0, 0, 1], port)));
+    // 瓦片路由, 图层和提供方由 Rust 侧配置
+    let tiles = warp::path!("tiles" / String / u32 / u32 / String)
+        .and(warp::get())
+        .and_then(|layer: String, z: u32, x: u32, y: String| async move {
+            handle_tile_request(&layer, z, x, &y).await
+        });
 
-    Ok(())
+    // 图片缩放, 参数见 ImageOptions
+    let image = warp::path!("img" / String / String)
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::headers_cloned())
+        .and_then(
+            |headers_part: String,
+             encoded_url: String,
+             query: HashMap<String, String>,
+             headers: warp::http::HeaderMap| async move {
+                handle_image_request(&headers_part, &encoded_url, query, headers).await
+            },
+        );
+
+    // WebSocket, 连接上游后双向转发消息
+    let ws = warp::path!("ws" / String / String)
+        .and(warp::ws())
+        .and(warp::header::headers_cloned())
+        .and_then(
+            |headers_part: String,
+             encoded_url: String,
+             upgrade: warp::ws::Ws,
+             headers: warp::http::HeaderMap| async move {
+                handle_ws_request(&headers_part, &encoded_url, upgrade, headers).await
+            },
+        );
+
+    // 所有路由都需要以本次启动的 token 开头
+    auth::guard()
+        .and(proxy.or(upstream).or(tiles).or(image).or(ws))
+        .recover(auth::handle_rejection)
+        .with(cors)
+}
+
+// 直接绑定端口并开始监听, 不会先探测再释放, 绑定成功才返回
+fn bind(ports: impl Iterator<Item = u16>) -> std::io::Result<RunningServer> {
+    let routes = routes();
+    let mut last_error = None;
+    for port in ports {
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let bound = warp::serve(routes.clone()).try_bind_with_graceful_shutdown(
+            ([127, 0, 0, 1], port),
+            async {
+                let _ = signal.await;
+            },
+        );
+        match bound {
+            Ok((addr, server)) => {
+                return Ok(RunningServer {
+                    port: addr.port(),
+                    shutdown,
+                    task: tauri::async_runtime::spawn(server),
+                })
+            }
+            Err(e) => {
+                log::debug!("cannot bind port {}: {}", port, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    let message = match last_error {
+        Some(e) => format!("No available port found: {}", e),
+        None => "No available port found".to_string(),
+    };
+    Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, message))
+}
+
+// 通知服务停止接受新连接, 等待进行中的请求完成, 超时后强制结束
+async fn shutdown(server: RunningServer) {
+    let _ = server.shutdown.send(());
+    let mut task = server.task;
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut task)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "proxy on port {} did not stop within {:?}, aborting",
+            server.port,
+            SHUTDOWN_TIMEOUT
+        );
+        task.abort();
+    }
+}
+
+/// 启动代理服务, 已在运行时返回当前端口
+pub(crate) async fn start_proxy_server() -> std::io::Result<u16> {
+    let mut server = SERVER.lock().await;
+    if let Some(running) = server.as_ref() {
+        return Ok(running.port);
+    }
+    let running = bind(default_ports())?;
+    let port = running.port;
+    ACTUAL_PORT.store(port, Ordering::SeqCst);
+    stats::mark_started();
+    *server = Some(running);
+    Ok(port)
+}
+
+/// 停止代理服务, 返回停止前的端口, 未运行时返回 None
+pub(crate) async fn stop_proxy_server() -> Option<u16> {
+    let mut server = SERVER.lock().await;
+    let running = server.take()?;
+    let port = running.port;
+    ACTUAL_PORT.store(0, Ordering::SeqCst);
+    stats::mark_stopped();
+    shutdown(running).await;
+    Some(port)
+}
+
+/// 换到新端口, 新端口绑定成功后才停止旧服务, 失败时旧服务继续运行;
+/// 未指定端口时从默认范围中选一个与当前不同的端口
+pub(crate) async fn rebind_proxy_server(port: Option<u16>) -> std::io::Result<(Option<u16>, u16)> {
+    let mut server = SERVER.lock().await;
+    let previous = server.as_ref().map(|running| running.port);
+    if let Some(port) = port.filter(|port| Some(*port) == previous) {
+        return Ok((previous, port));
+    }
+    let running = match port {
+        Some(port) => bind(std::iter::once(port))?,
+        None => bind(default_ports().filter(|port| Some(*port) != previous))?,
+    };
+    let port = running.port;
+    ACTUAL_PORT.store(port, Ordering::SeqCst);
+    stats::mark_started();
+    if let Some(old) = server.replace(running) {
+        // 旧端口上的请求在后台完成, 不阻塞新端口
+        tauri::async_runtime::spawn(shutdown(old));
+    }
+    Ok((previous, port))
+}
+
+// 代理地址前缀, 端口为 0 表示未运行
+fn base_url(port: u16) -> String {
+    format!("{}:{}/{}", HOST, port, auth::token())
+}
+
+/// 代理服务状态, 同时作为 `proxy://ready` 和 `proxy://stopped` 事件的内容
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProxyStatus {
+    pub running: bool,
+    pub port: u16,
+    // 停止事件中为停止前的地址
+    pub base_url: String,
+}
+
+impl ProxyStatus {
+    fn new(running: bool, port: u16) -> Self {
+        ProxyStatus {
+            running,
+            port,
+            base_url: base_url(port),
+        }
+    }
+}
+
+pub(crate) fn emit_ready<R: Runtime>(app: &AppHandle<R>, port: u16) -> ProxyStatus {
+    let status = ProxyStatus::new(true, port);
+    let _ = app.emit(READY_EVENT, status.clone());
+    status
+}
+
+fn emit_stopped<R: Runtime>(app: &AppHandle<R>, port: u16) {
+    let _ = app.emit(STOPPED_EVENT, ProxyStatus::new(false, port));
+}
+
+#[tauri::command]
+pub(crate) fn get_proxy_status() -> Result<ProxyStatus, String> {
+    let port = ACTUAL_PORT.load(Ordering::SeqCst);
+    Ok(ProxyStatus::new(port != 0, port))
+}
+
+#[tauri::command]
+pub(crate) async fn start_proxy<R: Runtime>(app: AppHandle<R>) -> Result<ProxyStatus, String> {
+    let port = start_proxy_server().await.map_err(|e| e.to_string())?;
+    Ok(emit_ready(&app, port))
+}
+
+/// 停止代理, 进行中的请求最多等待 SHUTDOWN_TIMEOUT; 已建立的 WebSocket 连接不受影响
+#[tauri::command]
+pub(crate) async fn stop_proxy<R: Runtime>(app: AppHandle<R>) -> Result<bool, String> {
+    match stop_proxy_server().await {
+        Some(port) => {
+            emit_stopped(&app, port);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// 停止后在原端口重新启动, 原端口被占用时换用默认范围内的其他端口
+#[tauri::command]
+pub(crate) async fn restart_proxy<R: Runtime>(app: AppHandle<R>) -> Result<ProxyStatus, String> {
+    let mut server = SERVER.lock().await;
+    let previous = match server.take() {
+        Some(running) => {
+            let port = running.port;
+            ACTUAL_PORT.store(0, Ordering::SeqCst);
+            stats::mark_stopped();
+            shutdown(running).await;
+            emit_stopped(&app, port);
+            Some(port)
+        }
+        None => None,
+    };
+    let running = bind(previous.into_iter().chain(default_ports())).map_err(|e| e.to_string())?;
+    let port = running.port;
+    ACTUAL_PORT.store(port, Ordering::SeqCst);
+    stats::mark_started();
+    *server = Some(running);
+    Ok(emit_ready(&app, port))
+}
+
+/// 换到指定端口, 未指定时换用默认范围内的其他端口
+#[tauri::command]
+pub(crate) async fn rebind_proxy<R: Runtime>(
+    app: AppHandle<R>,
+    port: Option<u16>,
+) -> Result<ProxyStatus, String> {
+    let (previous, port) = rebind_proxy_server(port).await.map_err(|e| e.to_string())?;
+    if let Some(previous) = previous.filter(|previous| *previous != port) {
+        emit_stopped(&app, previous);
+    }
+    Ok(emit_ready(&app, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::test_support::{proxy_url, serve, setup};
+    use futures_util::StreamExt;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use tokio::sync::{mpsc, Notify};
+
+    // 上游逐块读取请求体, 收到第一块时通知发送方, 返回收到的块数和字节数
+    async fn count_chunks<S, B>(seen: Arc<Notify>, body: S) -> Result<String, warp::Rejection>
+    where
+        S: futures_util::Stream<Item = Result<B, warp::Error>>,
+        B: Buf,
+    {
+        let mut body = Box::pin(body);
+        let (mut chunks, mut bytes) = (0u64, 0u64);
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|_| warp::reject())?;
+            if chunks == 0 {
+                seen.notify_one();
+            }
+            chunks += 1;
+            bytes += chunk.remaining() as u64;
+        }
+        Ok(format!("{} {}", chunks, bytes))
+    }
+
+    // 由通道驱动的消息体
+    fn channel_body(
+        rx: mpsc::Receiver<Vec<u8>>,
+    ) -> impl futures_util::Stream<Item = Result<Vec<u8>, std::io::Error>> {
+        futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (Ok(chunk), rx))
+        })
+    }
+
+    // 先发送一块, 对端收到后再发送其余的块;
+    // 代理缓冲整个消息体时对端在发送结束前收不到数据, 超时后提前结束并返回 false
+    async fn send_after_first(
+        tx: &mpsc::Sender<Vec<u8>>,
+        seen: Arc<Notify>,
+        chunk: usize,
+        count: usize,
+    ) -> bool {
+        if tx.send(vec![7u8; chunk]).await.is_err() {
+            return false;
+        }
+        if tokio::time::timeout(Duration::from_secs(10), seen.notified())
+            .await
+            .is_err()
+        {
+            return false;
+        }
+        for _ in 1..count {
+            if tx.send(vec![7u8; chunk]).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_bodies_are_streamed() {
+        const CHUNK: usize = 64 * 1024;
+        const TOTAL: u64 = 200 * 1024 * 1024;
+        const COUNT: usize = TOTAL as usize / CHUNK;
+        let _guard = setup(serde_json::json!({})).await;
+        let uploaded = Arc::new(Notify::new());
+        let downloaded = Arc::new(Notify::new());
+        let streamed_down = Arc::new(AtomicBool::new(false));
+        let upload = {
+            let seen = uploaded.clone();
+            warp::path("upload")
+                .and(warp::body::stream())
+                .and_then(move |body| count_chunks(seen.clone(), body))
+        };
+        let download = {
+            let (seen, streamed) = (downloaded.clone(), streamed_down.clone());
+            warp::path("download").map(move || {
+                let (tx, rx) = mpsc::channel(4);
+                let (seen, streamed) = (seen.clone(), streamed.clone());
+                tokio::spawn(async move {
+                    let ok = send_after_first(&tx, seen, CHUNK, COUNT).await;
+                    // 先记录结果再结束响应体
+                    streamed.store(ok, Ordering::SeqCst);
+                    drop(tx);
+                });
+                warp::http::Response::builder()
+                    .header("content-type", "application/octet-stream")
+                    .body(warp::hyper::Body::wrap_stream(channel_body(rx)))
+                    .unwrap()
+            })
+        };
+        let port = serve(upload.or(download));
+        let client = reqwest::Client::new();
+
+        // 上游在客户端发送完之前就收到请求体
+        let (tx, rx) = mpsc::channel(4);
+        let sender =
+            tokio::spawn(async move { send_after_first(&tx, uploaded, CHUNK, COUNT).await });
+        let response = client
+            .post(proxy_url(port, "/upload"))
+            .header("content-length", TOTAL)
+            .body(reqwest::Body::wrap_stream(channel_body(rx)))
+            .send()
+            .await;
+        assert!(
+            sender.await.unwrap(),
+            "upstream got nothing before the upload ended"
+        );
+        let response = response.unwrap();
+        assert_eq!(response.status(), 200);
+        let text = response.text().await.unwrap();
+        let (chunks, bytes) = text.split_once(' ').unwrap();
+        assert_eq!(bytes, TOTAL.to_string());
+        assert!(chunks.parse::<u64>().unwrap() > 100, "{} chunks", chunks);
+
+        // 客户端在上游发送完之前就收到响应体
+        let response = client
+            .get(proxy_url(port, "/download"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let mut stream = response.bytes_stream();
+        let (mut chunks, mut bytes) = (0u64, 0u64);
+        while let Some(chunk) = stream.next().await {
+            if chunks == 0 {
+                downloaded.notify_one();
+            }
+            chunks += 1;
+            bytes += chunk.unwrap().len() as u64;
+        }
+        assert!(
+            streamed_down.load(Ordering::SeqCst),
+            "client got nothing before the download ended"
+        );
+        assert_eq!(bytes, TOTAL);
+        assert!(chunks > 100, "{} chunks", chunks);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn range_requests_pass_through() {
+        let _guard = setup(serde_json::json!({})).await;
+        let data: Arc<Vec<u8>> = Arc::new((0..1000u32).map(|i| (i % 251) as u8).collect());
+        let media = data.clone();
+        // 按 Range 和 If-Range 返回 206 或完整内容, 完整内容可以缓存
+        let upstream = warp::path("media")
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>("if-range"))
+            .map(move |range: Option<String>, if_range: Option<String>| {
+                let builder = warp::http::Response::builder()
+                    .header("accept-ranges", "bytes")
+                    .header("etag", "\"v1\"")
+                    .header("cache-control", "max-age=60");
+                let range = range
+                    .filter(|_| if_range.as_deref().unwrap_or("\"v1\"") == "\"v1\"")
+                    .and_then(|range| {
+                        let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+                        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+                    });
+                match range {
+                    Some((start, end)) => builder
+                        .status(206)
+                        .header(
+                            "content-range",
+                            format!("bytes {}-{}/{}", start, end, media.len()),
+                        )
+                        .body(warp::hyper::Body::from(media[start..=end].to_vec())),
+                    None => builder.body(warp::hyper::Body::from(media.to_vec())),
+                }
+                .unwrap()
+            });
+        let url = proxy_url(serve(upstream), "/media");
+        let client = reqwest::Client::new();
+
+        // 先缓存完整内容, 之后的 Range 请求仍要转发给上游
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), data[..]);
+
+        let response = client
+            .get(&url)
+            .header("range", "bytes=100-199")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["content-range"], "bytes 100-199/1000");
+        assert_eq!(response.headers()["accept-ranges"], "bytes");
+        assert!(response
+            .headers()
+            .get(tile_cache::CACHE_STATUS_HEADER)
+            .is_none());
+        assert_eq!(response.bytes().await.unwrap(), data[100..200]);
+
+        let response = client
+            .get(&url)
+            .header("range", "bytes=990-999")
+            .header("if-range", "\"v1\"")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.bytes().await.unwrap(), data[990..]);
+
+        // 资源已变化时上游返回完整内容
+        let response = client
+            .get(&url)
+            .header("range", "bytes=990-999")
+            .header("if-range", "\"v0\"")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.bytes().await.unwrap(), data[..]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tile_cache_hits_are_counted() {
+        let _guard = setup(serde_json::json!({})).await;
+        let port =
+            serve(warp::path!(u32 / u32 / u32).map(|_, _, _| {
+                warp::http::Response::new(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec())
+            }));
+        config::update(|config| {
+            let layer = tiles::TileLayer {
+                name: "counted".to_string(),
+                min_zoom: 0,
+                max_zoom: 18,
+                providers: vec![tiles::TileProvider {
+                    url: format!("http://localhost:{}/{{z}}/{{x}}/{{y}}", port),
+                    ..Default::default()
+                }],
+            };
+            config.tile_layers.insert("counted".to_string(), layer);
+        })
+        .unwrap();
+        let requests = || {
+            stats::snapshot()
+                .hosts
+                .iter()
+                .find(|host| host.host == "localhost")
+                .map_or(0, |host| host.requests)
+        };
+        let before = requests();
+        let url = format!("{}/tiles/counted/3/1/2.png", get_proxy_base_url().unwrap());
+        for expected in ["MISS", "HIT"] {
+            let response = reqwest::get(&url).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                response.headers()[tile_cache::CACHE_STATUS_HEADER],
+                expected
+            );
+        }
+        assert_eq!(requests() - before, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_tile_misses_hit_upstream_once() {
+        let _guard = setup(serde_json::json!({})).await;
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let port = serve(warp::path!(u32 / u32 / u32).and_then(move |_, _, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                Ok::<_, warp::Rejection>(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec())
+            }
+        }));
+        config::update(|config| {
+            let layer = tiles::TileLayer {
+                name: "coalesced".to_string(),
+                min_zoom: 0,
+                max_zoom: 18,
+                providers: vec![tiles::TileProvider {
+                    url: format!("http://127.0.0.1:{}/{{z}}/{{x}}/{{y}}", port),
+                    ..Default::default()
+                }],
+            };
+            config.tile_layers.insert("coalesced".to_string(), layer);
+        })
+        .unwrap();
+        let url = format!(
+            "{}/tiles/coalesced/5/6/7.png",
+            get_proxy_base_url().unwrap()
+        );
+        let requests = (0..8).map(|_| async {
+            let response = reqwest::get(&url).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()[tile_cache::CACHE_STATUS_HEADER], "MISS");
+            assert_eq!(response.headers()[tiles::PROVIDER_HEADER], "127.0.0.1");
+            response.bytes().await.unwrap()
+        });
+        let bodies = futures_util::future::join_all(requests).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(bodies.iter().all(|body| body.starts_with(b"\x89PNG")));
+
+        // 不同的瓦片分别请求
+        let other = format!(
+            "{}/tiles/coalesced/5/6/8.png",
+            get_proxy_base_url().unwrap()
+        );
+        assert_eq!(reqwest::get(&other).await.unwrap().status(), 200);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expired_tiles_are_served_when_the_upstream_fails() {
+        // 上游失败不计入熔断, 以免影响其他访问本地上游的测试
+        let _guard = setup(serde_json::json!({
+            "retry": {"maxRetries": 0},
+            "breaker": {"failureThreshold": 0}
+        }))
+        .await;
+        let failing = Arc::new(AtomicBool::new(false));
+        let fail = failing.clone();
+        let port = serve(warp::path!(u32 / u32 / u32).map(move |_, _, _| {
+            if fail.load(Ordering::SeqCst) {
+                warp::reply::with_status(Vec::new(), warp::http::StatusCode::BAD_GATEWAY)
+            } else {
+                warp::reply::with_status(
+                    b"\x89PNG\r\n\x1a\nold".to_vec(),
+                    warp::http::StatusCode::OK,
+                )
+            }
+        }));
+        config::update(|config| {
+            let layer = tiles::TileLayer {
+                name: "stale".to_string(),
+                min_zoom: 0,
+                max_zoom: 18,
+                providers: vec![tiles::TileProvider {
+                    url: format!("http://127.0.0.1:{}/{{z}}/{{x}}/{{y}}", port),
+                    ..Default::default()
+                }],
+            };
+            config.tile_layers.insert("stale".to_string(), layer);
+        })
+        .unwrap();
+        let url = format!("{}/tiles/stale/4/3/2.png", get_proxy_base_url().unwrap());
+        for expected in ["MISS", "HIT"] {
+            let response = reqwest::get(&url).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                response.headers()[tile_cache::CACHE_STATUS_HEADER],
+                expected
+            );
+        }
+
+        // 把缓存文件的修改时间调到有效期之前, 上游失败时返回旧瓦片
+        let path = crate::proxy_plugin::test_support::temp_dir("cache").join("tiles/stale/4/3/2");
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - tile_cache::DEFAULT_MAX_AGE * 2)
+            .unwrap();
+        failing.store(true, Ordering::SeqCst);
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[tile_cache::CACHE_STATUS_HEADER], "STALE");
+        assert_eq!(
+            &response.bytes().await.unwrap()[..],
+            b"\x89PNG\r\n\x1a\nold"
+        );
+
+        // 没有缓存的瓦片直接报错
+        let missing = format!("{}/tiles/stale/4/3/3.png", get_proxy_base_url().unwrap());
+        assert!(!reqwest::get(&missing).await.unwrap().status().is_success());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stopped_server_has_no_urls_or_uptime() {
+        let _guard = setup(serde_json::json!({})).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(stats::snapshot().uptime_secs >= 1);
+
+        assert!(stop_proxy_server().await.is_some());
+        assert_eq!(stats::snapshot().uptime_secs, 0);
+        assert!(get_proxy_url("https://example.com/", None).is_err());
+        assert!(get_proxy_ws_url("wss://example.com/", None).is_err());
+        assert!(get_proxy_base_url().is_err());
+        let options = ImageOptions {
+            width: Some(10),
+            ..Default::default()
+        };
+        assert!(get_image_url("https://example.com/a.png", options, None).is_err());
+
+        // 重新启动后重新计时
+        tauri::async_runtime::spawn(start_proxy_server())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats::snapshot().uptime_secs, 0);
+        assert!(get_proxy_url("https://example.com/", None).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn policy_violations_name_the_rule() {
+        let _guard = setup(serde_json::json!({
+            "policy": {"allowedHosts": ["127.0.0.1"], "blockPrivate": true}
+        }))
+        .await;
+        for (url, rule) in [
+            ("http://127.0.0.1:9/", "blockPrivate"),
+            ("http://[64:ff9b::7f00:1]:9/", "allowedHosts"),
+            ("https://example.com/", "allowedHosts"),
+            ("ftp://127.0.0.1/", "allowedSchemes"),
+        ] {
+            let response = reqwest::get(get_proxy_url(url, None).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 403, "{}", url);
+            let body = response.text().await.unwrap();
+            assert!(body.contains("\"error\":\"blocked_by_policy\""), "{}", body);
+            assert!(
+                body.contains(&format!("\"rule\":\"{}\"", rule)),
+                "{}: {}",
+                url,
+                body
+            );
+        }
+    }
 }
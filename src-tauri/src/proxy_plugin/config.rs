@@ -0,0 +1,115 @@
+use super::client::{self, ClientSettings};
+use super::limits::LimitSettings;
+use super::policy::UrlPolicy;
+use super::redirect::RedirectSettings;
+use super::replay::ReplaySettings;
+use super::retry::{BreakerSettings, RetryPolicy};
+use super::stats::StatsSettings;
+use super::tiles::TileLayer;
+use super::tls::TlsPolicy;
+use super::traffic::TrafficLogSettings;
+use super::transform::TransformSettings;
+use super::upstream::UpstreamProfile;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+pub(crate) const CONFIG_FILE_NAME: &str = "proxy.json";
+
+static CONFIG_PATH: OnceCell<PathBuf> = OnceCell::new();
+static CONFIG: Lazy<RwLock<Arc<ProxyConfig>>> =
+    Lazy::new(|| RwLock::new(Arc::new(ProxyConfig::default())));
+
+/// 代理配置, 保存在应用配置目录的 `proxy.json` 中, 缺省字段使用默认值
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct ProxyConfig {
+    pub upstreams: BTreeMap<String, UpstreamProfile>,
+    pub tls: TlsPolicy,
+    pub client: ClientSettings,
+    pub policy: UrlPolicy,
+    pub redirect: RedirectSettings,
+    pub retry: RetryPolicy,
+    pub breaker: BreakerSettings,
+    pub limits: LimitSettings,
+    pub traffic_log: TrafficLogSettings,
+    pub replay: ReplaySettings,
+    pub stats: StatsSettings,
+    pub tile_layers: BTreeMap<String, TileLayer>,
+    pub transform: TransformSettings,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            upstreams: UpstreamProfile::defaults(),
+            tls: TlsPolicy::default(),
+            client: ClientSettings::default(),
+            policy: UrlPolicy::default(),
+            redirect: RedirectSettings::default(),
+            retry: RetryPolicy::default(),
+            breaker: BreakerSettings::default(),
+            limits: LimitSettings::default(),
+            traffic_log: TrafficLogSettings::default(),
+            replay: ReplaySettings::default(),
+            stats: StatsSettings::default(),
+            tile_layers: TileLayer::defaults(),
+            transform: TransformSettings::default(),
+        }
+    }
+}
+
+fn read_config(path: &Path) -> Result<ProxyConfig, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// 读取配置文件, 文件不存在时写入默认配置
+pub(crate) fn init(dir: PathBuf) -> Result<(), String> {
+    let path = dir.join(CONFIG_FILE_NAME);
+    let _ = CONFIG_PATH.set(path.clone());
+    if !path.exists() {
+        let config = ProxyConfig::default();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let text = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+        fs::write(&path, text).map_err(|e| e.to_string())?;
+        *CONFIG.write().unwrap() = Arc::new(config);
+        return Ok(());
+    }
+    reload()
+}
+
+/// 重新读取配置文件, 用于修改 key 等配置后立即生效
+pub(crate) fn reload() -> Result<(), String> {
+    let path = CONFIG_PATH
+        .get()
+        .ok_or_else(|| "Proxy config is not initialized".to_string())?;
+    let config = read_config(path)?;
+    // 客户端创建失败时保留原配置
+    client::rebuild(&config)?;
+    *CONFIG.write().unwrap() = Arc::new(config);
+    Ok(())
+}
+
+/// 修改配置并写回配置文件
+pub(crate) fn update<F>(f: F) -> Result<(), String>
+where
+    F: FnOnce(&mut ProxyConfig),
+{
+    let mut config = (*current()).clone();
+    f(&mut config);
+    client::rebuild(&config)?;
+    if let Some(path) = CONFIG_PATH.get() {
+        let text = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+        fs::write(path, text).map_err(|e| e.to_string())?;
+    }
+    *CONFIG.write().unwrap() = Arc::new(config);
+    Ok(())
+}
+
+pub(crate) fn current() -> Arc<ProxyConfig> {
+    CONFIG.read().unwrap().clone()
+}
@@ -0,0 +1,264 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use reqwest::header::{
+    HeaderMap as ReqwestHeaderMap, HeaderName as ReqwestHeaderName,
+    HeaderValue as ReqwestHeaderValue,
+};
+use reqwest::Method as ReqwestMethod;
+use std::collections::HashSet;
+use warp::http::header::{
+    HeaderMap as WarpHeaderMap, HeaderName as WarpHeaderName, HeaderValue as WarpHeaderValue,
+};
+use warp::http::Method as WarpMethod;
+
+// warp 与 reqwest 依赖的 http 版本不同, 需要逐个转换
+
+pub(crate) fn convert_to_reqwest_method(warp_method: &WarpMethod) -> Option<ReqwestMethod> {
+    match *warp_method {
+        WarpMethod::GET => Some(ReqwestMethod::GET),
+        WarpMethod::POST => Some(ReqwestMethod::POST),
+        WarpMethod::PUT => Some(ReqwestMethod::PUT),
+        WarpMethod::DELETE => Some(ReqwestMethod::DELETE),
+        // 其他方法...
+        _ => ReqwestMethod::from_bytes(warp_method.as_str().as_bytes()).ok(),
+    }
+}
+
+/// 转换请求头, 保留同名头的所有取值, 无法转换的头记录日志后跳过
+pub(crate) fn convert_to_reqwest_headers(warp_headers: &WarpHeaderMap) -> ReqwestHeaderMap {
+    let mut reqwest_headers = ReqwestHeaderMap::with_capacity(warp_headers.len());
+    for (name, value) in warp_headers.iter() {
+        match (
+            ReqwestHeaderName::from_bytes(name.as_str().as_bytes()),
+            ReqwestHeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                reqwest_headers.append(name, value);
+            }
+            _ => log::warn!("skipping invalid request header: {}", name),
+        }
+    }
+    reqwest_headers
+}
+
+/// 转换响应头, 保留 `Set-Cookie`、`Vary` 等多值头
+pub(crate) fn convert_to_warp_headers(reqwest_headers: &ReqwestHeaderMap) -> WarpHeaderMap {
+    let mut warp_headers = WarpHeaderMap::with_capacity(reqwest_headers.len());
+    for (name, value) in reqwest_headers.iter() {
+        match (
+            WarpHeaderName::from_bytes(name.as_str().as_bytes()),
+            WarpHeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                warp_headers.append(name, value);
+            }
+            _ => log::warn!("skipping invalid response header: {}", name),
+        }
+    }
+    warp_headers
+}
+
+/// 把 webview 传入的请求头合并进 `target`, `target` 中已有的头优先, `excluded` 中的头丢弃
+pub(crate) fn merge_headers(
+    target: &mut ReqwestHeaderMap,
+    source: &ReqwestHeaderMap,
+    excluded: &[ReqwestHeaderName],
+) {
+    let overridden: HashSet<ReqwestHeaderName> = target.keys().cloned().collect();
+    for (name, value) in source.iter() {
+        if !excluded.contains(name) && !overridden.contains(name) {
+            target.append(name.clone(), value.clone());
+        }
+    }
+}
+
+// get_proxy_url 生成的请求头段前缀, 后接 base64url 编码的 `[[name, value], ...]`
+const ENCODED_PREFIX: &str = "b64.";
+// 没有自定义请求头时的占位
+const EMPTY_SEGMENT: &str = "_";
+
+/// 编码自定义请求头, 用作代理地址中的一段路径
+pub(crate) fn encode_header_segment(headers: &[(String, String)]) -> String {
+    if headers.is_empty() {
+        return EMPTY_SEGMENT.to_string();
+    }
+    let json = serde_json::to_vec(headers).unwrap_or_default();
+    format!("{}{}", ENCODED_PREFIX, BASE64_URL.encode(json))
+}
+
+/// 解码请求头段, 兼容旧的 `name:value,name:value` 格式
+pub(crate) fn decode_header_segment(segment: &str) -> Result<ReqwestHeaderMap, String> {
+    let decoded = urlencoding::decode(segment).map_err(|_| "Invalid header encoding")?;
+    let pairs: Vec<(String, String)> = match decoded.strip_prefix(ENCODED_PREFIX) {
+        Some(encoded) if !encoded.contains(':') => {
+            let json = BASE64_URL
+                .decode(encoded)
+                .map_err(|_| "Invalid header encoding")?;
+            serde_json::from_slice(&json).map_err(|_| "Invalid header encoding")?
+        }
+        _ if decoded == EMPTY_SEGMENT => Vec::new(),
+        _ => decoded
+            .split(',')
+            .filter_map(|pair| pair.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    };
+    let mut header_map = ReqwestHeaderMap::with_capacity(pairs.len());
+    for (name, value) in pairs {
+        match (
+            ReqwestHeaderName::from_bytes(name.as_bytes()),
+            ReqwestHeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                header_map.append(name, value);
+            }
+            _ => log::warn!("skipping invalid proxy header: {}", name),
+        }
+    }
+    Ok(header_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warp_headers(pairs: &[(&str, &[u8])]) -> WarpHeaderMap {
+        let mut headers = WarpHeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                WarpHeaderName::from_bytes(name.as_bytes()).unwrap(),
+                WarpHeaderValue::from_bytes(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn values<'a>(headers: &'a ReqwestHeaderMap, name: &str) -> Vec<&'a [u8]> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.as_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn multi_valued_headers_survive_both_directions() {
+        let original = warp_headers(&[
+            ("set-cookie", b"a=1; Path=/"),
+            ("set-cookie", b"b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT"),
+            ("vary", b"Accept-Encoding"),
+            ("vary", b"Origin"),
+            ("link", b"<https://example.com/a>; rel=\"preload\""),
+            ("link", b"<https://example.com/b>; rel=\"next\""),
+        ]);
+        let converted = convert_to_reqwest_headers(&original);
+        assert_eq!(
+            values(&converted, "set-cookie"),
+            [
+                &b"a=1; Path=/"[..],
+                b"b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT"
+            ]
+        );
+        assert_eq!(
+            values(&converted, "vary"),
+            [&b"Accept-Encoding"[..], b"Origin"]
+        );
+        assert_eq!(values(&converted, "link").len(), 2);
+
+        let back = convert_to_warp_headers(&converted);
+        assert_eq!(back, original);
+    }
+
+    #[test]
+    fn non_ascii_values_are_kept_as_bytes() {
+        // Latin-1 和 UTF-8 字节都属于 obs-text, 原样转发
+        let original = warp_headers(&[("x-latin1", b"caf\xe9"), ("x-utf8", "北京".as_bytes())]);
+        let converted = convert_to_reqwest_headers(&original);
+        assert_eq!(values(&converted, "x-latin1"), [&b"caf\xe9"[..]]);
+        assert_eq!(values(&converted, "x-utf8"), ["北京".as_bytes()]);
+        assert_eq!(convert_to_warp_headers(&converted), original);
+    }
+
+    #[test]
+    fn invalid_headers_are_skipped() {
+        let pairs = vec![
+            ("bad name".to_string(), "1".to_string()),
+            ("x-newline".to_string(), "a\r\nb".to_string()),
+            ("x-ok".to_string(), "1".to_string()),
+        ];
+        let headers = decode_header_segment(&encode_header_segment(&pairs)).unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-ok"], "1");
+
+        let headers = decode_header_segment("x-ok:1,bad%20name:2,x-nul:a%00b").unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-ok"], "1");
+    }
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn segment_round_trip() {
+        let headers = pairs(&[
+            ("accept", "text/html, application/json;q=0.9, */*;q=0.8"),
+            ("x-time", "12:30:45"),
+            ("if-modified-since", "Wed, 21 Oct 2026 07:28:00 GMT"),
+            (
+                "referer",
+                "https://map.tianditu.gov.cn:443/search?a=1,2&b=c:d#top",
+            ),
+            ("x-empty", ""),
+        ]);
+        let segment = encode_header_segment(&headers);
+        // 作为一段路径使用, 不能包含分隔符
+        assert!(!segment.contains(['/', '?', '#', '%', ':', ',']));
+        let decoded = decode_header_segment(&segment).unwrap();
+        assert_eq!(decoded.len(), headers.len());
+        for (name, value) in &headers {
+            assert_eq!(decoded[name.as_str()], value.as_str());
+        }
+        // 前端可能再做一次地址编码
+        let decoded = decode_header_segment(&urlencoding::encode(&segment)).unwrap();
+        assert_eq!(decoded.len(), headers.len());
+    }
+
+    #[test]
+    fn segment_keeps_repeated_names() {
+        let headers = pairs(&[("x-tag", "a"), ("x-tag", "b")]);
+        let decoded = decode_header_segment(&encode_header_segment(&headers)).unwrap();
+        let values: Vec<_> = decoded.get_all("x-tag").iter().collect();
+        assert_eq!(values, ["a", "b"]);
+    }
+
+    #[test]
+    fn legacy_segments_still_decode() {
+        assert_eq!(encode_header_segment(&[]), "_");
+        assert!(decode_header_segment("_").unwrap().is_empty());
+
+        let decoded = decode_header_segment("x-a:1,x-b:two").unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded["x-a"], "1");
+        assert_eq!(decoded["x-b"], "two");
+
+        // 旧格式的取值按第一个冒号切分, 地址编码后同样可以解码
+        let decoded = decode_header_segment("x-time%3A12%3A30%2Cx-b%3A2").unwrap();
+        assert_eq!(decoded["x-time"], "12:30");
+        assert_eq!(decoded["x-b"], "2");
+
+        // 以 b64. 开头但带冒号的按旧格式处理
+        let decoded = decode_header_segment("b64.name:value").unwrap();
+        assert_eq!(decoded["b64.name"], "value");
+    }
+
+    #[test]
+    fn malformed_segment_is_an_error() {
+        assert!(decode_header_segment("b64.!!!").is_err());
+        let not_json = BASE64_URL.encode("not json");
+        assert!(decode_header_segment(&format!("b64.{}", not_json)).is_err());
+    }
+}
@@ -0,0 +1,757 @@
+use super::lru::LruIndex;
+use super::traffic;
+use once_cell::sync::OnceCell;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 默认缓存上限 256MB
+pub(crate) const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+// 超过该大小的响应不缓存
+pub(crate) const MAX_ENTRY_BYTES: u64 = 16 * 1024 * 1024;
+// 请求头, 取值为缓存时间 (`30s`、`10m`、`2h`、`1d`) 或 `no-store`, 不会转发给上游
+pub(crate) const OVERRIDE_HEADER: &str = "x-proxy-cache-ttl";
+// 只有 Last-Modified 时按其 10% 估算有效期, 最长一天
+const MAX_HEURISTIC_SECS: u64 = 24 * 60 * 60;
+// 这些状态码的响应默认可以缓存, 重定向的 Location 可能需要改写, 不缓存
+const CACHEABLE_STATUS: &[u16] = &[200, 203, 204, 404, 405, 410, 414, 501];
+// 逐跳头不保存
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+// 只属于当次响应的头, 不保存也不从缓存返回, 避免把 cookie 发给之后的请求
+const PRIVATE_HEADERS: &[&str] = &["set-cookie", "set-cookie2"];
+
+static HTTP_CACHE: OnceCell<HttpCache> = OnceCell::new();
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// `x-proxy-cache-ttl` 请求头
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CacheOverride {
+    // 不读也不写缓存
+    Bypass,
+    // 忽略响应头, 按指定时间缓存
+    Ttl(u64),
+}
+
+impl CacheOverride {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        match value.as_str() {
+            "no-store" | "bypass" | "off" | "0" => return Some(CacheOverride::Bypass),
+            "" => return None,
+            _ => {}
+        }
+        let (number, unit) = value.split_at(value.len() - 1);
+        let (number, scale) = match unit {
+            "s" => (number, 1),
+            "m" => (number, 60),
+            "h" => (number, 60 * 60),
+            "d" => (number, 24 * 60 * 60),
+            _ => (value.as_str(), 1),
+        };
+        let secs = number.parse::<u64>().ok()?.checked_mul(scale)?;
+        Some(CacheOverride::Ttl(secs))
+    }
+}
+
+// Cache-Control 指令, 名称转为小写
+fn cache_control(headers: &HeaderMap) -> HashMap<String, Option<String>> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|directive| {
+            let directive = directive.trim();
+            if directive.is_empty() {
+                return None;
+            }
+            Some(match directive.split_once('=') {
+                Some((name, value)) => (
+                    name.trim().to_ascii_lowercase(),
+                    Some(value.trim().trim_matches('"').to_string()),
+                ),
+                None => (directive.to_ascii_lowercase(), None),
+            })
+        })
+        .collect()
+}
+
+fn header_time(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// 请求是否可以使用缓存, 已带条件或 Range 的请求直接转发
+pub(crate) fn request_cacheable(
+    method: &reqwest::Method,
+    headers: &HeaderMap,
+    cache_override: Option<CacheOverride>,
+) -> bool {
+    if method != reqwest::Method::GET || cache_override == Some(CacheOverride::Bypass) {
+        return false;
+    }
+    if headers.contains_key(header::RANGE)
+        || headers.contains_key(header::IF_NONE_MATCH)
+        || headers.contains_key(header::IF_MODIFIED_SINCE)
+        || headers.contains_key(header::AUTHORIZATION)
+    {
+        return false;
+    }
+    !cache_control(headers).contains_key("no-store")
+}
+
+/// 请求要求先向上游确认 (`no-cache` 或 `max-age=0`)
+pub(crate) fn request_requires_revalidation(headers: &HeaderMap) -> bool {
+    let directives = cache_control(headers);
+    directives.contains_key("no-cache")
+        || matches!(directives.get("max-age"), Some(Some(age)) if age == "0")
+        || headers
+            .get(header::PRAGMA)
+            .map(|value| value.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+            .unwrap_or(false)
+}
+
+/// 响应是否可以写入缓存
+pub(crate) fn response_storable(
+    status: u16,
+    headers: &HeaderMap,
+    cache_override: Option<CacheOverride>,
+) -> bool {
+    if !CACHEABLE_STATUS.contains(&status) {
+        return false;
+    }
+    if headers
+        .get_all(header::VARY)
+        .iter()
+        .any(|value| value.as_bytes().contains(&b'*'))
+    {
+        return false;
+    }
+    // 事件流需要逐条转发, 不能缓冲
+    if headers
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.as_bytes().starts_with(b"text/event-stream"))
+        .unwrap_or(false)
+    {
+        return false;
+    }
+    if let Some(CacheOverride::Ttl(ttl)) = cache_override {
+        return ttl > 0;
+    }
+    let directives = cache_control(headers);
+    if directives.contains_key("no-store") {
+        return false;
+    }
+    // 没有有效期也没有校验信息的响应存下来也用不上
+    freshness_lifetime(headers) > 0
+        || headers.contains_key(header::ETAG)
+        || headers.contains_key(header::LAST_MODIFIED)
+}
+
+// 按 max-age、Expires、Last-Modified 的顺序计算有效期 (秒)
+fn freshness_lifetime(headers: &HeaderMap) -> u64 {
+    let directives = cache_control(headers);
+    if directives.contains_key("no-cache") {
+        return 0;
+    }
+    if let Some(Some(max_age)) = directives.get("max-age") {
+        return max_age.parse().unwrap_or(0);
+    }
+    let date = header_time(headers, header::DATE).unwrap_or_else(SystemTime::now);
+    if headers.contains_key(header::EXPIRES) {
+        // 无法解析的 Expires (例如 0) 视为已过期
+        return header_time(headers, header::EXPIRES)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+    }
+    header_time(headers, header::LAST_MODIFIED)
+        .and_then(|modified| date.duration_since(modified).ok())
+        .map(|d| (d.as_secs() / 10).min(MAX_HEURISTIC_SECS))
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryMeta {
+    // 查询参数中的密钥已替换为占位符, 只用于核对
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    // Vary 中列出的请求头及其取值
+    vary: Vec<(String, Option<String>)>,
+    // 收到响应的时间
+    stored_at: u64,
+    // x-proxy-cache-ttl 指定的有效期
+    ttl: Option<u64>,
+}
+
+/// 缓存的响应
+pub(crate) struct CachedResponse {
+    key: String,
+    meta: EntryMeta,
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    pub(crate) fn status(&self) -> u16 {
+        self.meta.status
+    }
+
+    pub(crate) fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::with_capacity(self.meta.headers.len());
+        for (name, value) in &self.meta.headers {
+            if PRIVATE_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    /// 从收到响应到现在经过的秒数, 包括上游返回的 Age
+    pub(crate) fn age(&self) -> u64 {
+        let upstream_age = self
+            .meta
+            .headers
+            .iter()
+            .find(|(name, _)| name == "age")
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+            .unwrap_or(0);
+        unix_now().saturating_sub(self.meta.stored_at) + upstream_age
+    }
+
+    pub(crate) fn is_fresh(&self) -> bool {
+        let lifetime = match self.meta.ttl {
+            Some(ttl) => ttl,
+            None => freshness_lifetime(&self.headers()),
+        };
+        self.age() < lifetime
+    }
+
+    /// 过期后向上游确认时附带的条件请求头
+    pub(crate) fn validators(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let mut validators = Vec::new();
+        for (name, value) in &self.meta.headers {
+            let condition = match name.as_str() {
+                "etag" => header::IF_NONE_MATCH,
+                "last-modified" => header::IF_MODIFIED_SINCE,
+                _ => continue,
+            };
+            if let Ok(value) = HeaderValue::from_str(value) {
+                validators.push((condition, value));
+            }
+        }
+        validators
+    }
+
+    /// 上游声明过期后不能直接使用
+    pub(crate) fn must_revalidate(&self) -> bool {
+        cache_control(&self.headers()).contains_key("must-revalidate")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HttpCacheStats {
+    pub dir: String,
+    pub entries: usize,
+    pub total_bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// 代理的私有 HTTP 缓存, 每个地址保存一份响应, 元数据和响应体分别存放
+pub(crate) struct HttpCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<LruIndex<String>>,
+}
+
+// 文件名使用完整地址的摘要, 磁盘上不出现地址中的 key
+fn cache_key(url: &str) -> String {
+    Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl HttpCache {
+    pub(crate) fn open(dir: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let cache = HttpCache {
+            dir,
+            max_bytes,
+            index: Mutex::new(LruIndex::default()),
+        };
+        cache.load_index()?;
+        Ok(cache)
+    }
+
+    // 启动时扫描缓存目录, 按修改时间恢复 LRU 顺序
+    fn load_index(&self) -> io::Result<()> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("body") {
+                continue;
+            }
+            let key = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            if !self.meta_path(&key).exists() {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if let Ok(meta) = entry.metadata() {
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, key, meta.len()));
+            }
+        }
+        found.sort_by_key(|entry| entry.0);
+        let mut index = self.index.lock().unwrap();
+        for (_, key, size) in found {
+            index.insert(key, size);
+        }
+        drop(index);
+        self.evict();
+        Ok(())
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    fn body_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.body", key))
+    }
+
+    /// 查找缓存, Vary 的请求头取值不一致时视为未命中
+    pub(crate) async fn get(
+        &self,
+        url: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<CachedResponse> {
+        let key = cache_key(url);
+        if !self.index.lock().unwrap().contains(&key) {
+            return None;
+        }
+        let meta = tokio::fs::read(self.meta_path(&key))
+            .await
+            .ok()
+            .and_then(|data| serde_json::from_slice::<EntryMeta>(&data).ok());
+        let body = tokio::fs::read(self.body_path(&key)).await.ok();
+        let (meta, body) = match (meta, body) {
+            (Some(meta), Some(body)) if meta.url == traffic::redact_url(url) => (meta, body),
+            _ => {
+                // 文件已损坏或被外部删除, 同步索引
+                self.remove(&key);
+                return None;
+            }
+        };
+        let matches = meta.vary.iter().all(|(name, value)| {
+            request_headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                == value.as_deref()
+        });
+        if !matches {
+            return None;
+        }
+        self.index.lock().unwrap().touch(&key);
+        Some(CachedResponse { key, meta, body })
+    }
+
+    pub(crate) async fn put(
+        &self,
+        url: &str,
+        request_headers: &HeaderMap,
+        status: u16,
+        response_headers: &HeaderMap,
+        body: &[u8],
+        cache_override: Option<CacheOverride>,
+    ) -> io::Result<()> {
+        if body.len() as u64 > MAX_ENTRY_BYTES.min(self.max_bytes) {
+            return Ok(());
+        }
+        let vary = response_headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let value = request_headers
+                    .get(name.as_str())
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                (name, value)
+            })
+            .collect();
+        let meta = EntryMeta {
+            url: traffic::redact_url(url),
+            status,
+            headers: entry_headers(response_headers),
+            vary,
+            stored_at: unix_now(),
+            ttl: match cache_override {
+                Some(CacheOverride::Ttl(ttl)) => Some(ttl),
+                _ => None,
+            },
+        };
+        let key = cache_key(url);
+        self.write_entry(&key, &meta, Some(body)).await?;
+        self.index.lock().unwrap().insert(key, body.len() as u64);
+        self.evict();
+        Ok(())
+    }
+
+    /// 上游返回 304 后, 用新的响应头更新缓存
+    pub(crate) async fn refresh(
+        &self,
+        cached: &mut CachedResponse,
+        response_headers: &HeaderMap,
+    ) -> io::Result<()> {
+        let updated = entry_headers(response_headers);
+        cached.meta.headers.retain(|(name, _)| {
+            // 304 不带响应体, 保留原有的长度和编码
+            name == "content-length"
+                || name == "content-encoding"
+                || !updated.iter().any(|(updated, _)| updated == name)
+        });
+        cached.meta.headers.extend(
+            updated
+                .into_iter()
+                .filter(|(name, _)| name != "content-length" && name != "content-encoding"),
+        );
+        cached.meta.stored_at = unix_now();
+        self.write_entry(&cached.key, &cached.meta, None).await?;
+        self.index.lock().unwrap().touch(&cached.key);
+        Ok(())
+    }
+
+    // 先写临时文件再重命名, 避免读到写了一半的数据
+    async fn write_entry(
+        &self,
+        key: &str,
+        meta: &EntryMeta,
+        body: Option<&[u8]>,
+    ) -> io::Result<()> {
+        if let Some(body) = body {
+            let path = self.body_path(key);
+            let tmp = self.dir.join(format!(
+                "{}.{}.tmp",
+                key,
+                TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            tokio::fs::write(&tmp, body).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+        }
+        let data = serde_json::to_vec(meta).map_err(io::Error::other)?;
+        let path = self.meta_path(key);
+        let tmp = self.dir.join(format!(
+            "{}.{}.tmp",
+            key,
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+
+    fn remove(&self, key: &str) {
+        self.index.lock().unwrap().remove(key);
+        let _ = fs::remove_file(self.meta_path(key));
+        let _ = fs::remove_file(self.body_path(key));
+    }
+
+    fn evict(&self) {
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            while index.total_bytes() > self.max_bytes {
+                match index.pop_oldest() {
+                    Some(key) => evicted.push(key),
+                    None => break,
+                }
+            }
+        }
+        for key in evicted {
+            let _ = fs::remove_file(self.meta_path(&key));
+            let _ = fs::remove_file(self.body_path(&key));
+        }
+    }
+
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        *index = LruIndex::default();
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            if entry.path().is_file() {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> HttpCacheStats {
+        let index = self.index.lock().unwrap();
+        HttpCacheStats {
+            dir: self.dir.to_string_lossy().into_owned(),
+            entries: index.len(),
+            total_bytes: index.total_bytes(),
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+pub(crate) fn stored_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+        .filter_map(|(name, value)| {
+            Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect()
+}
+
+// 写入缓存的响应头
+fn entry_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut stored = stored_headers(headers);
+    stored.retain(|(name, _)| !PRIVATE_HEADERS.contains(&name.as_str()));
+    stored
+}
+
+pub(crate) fn init(dir: PathBuf) -> io::Result<()> {
+    let cache = HttpCache::open(dir, DEFAULT_MAX_BYTES)?;
+    let _ = HTTP_CACHE.set(cache);
+    Ok(())
+}
+
+pub(crate) fn http_cache() -> Option<&'static HttpCache> {
+    HTTP_CACHE.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::test_support::{proxy_url, serve, setup, temp_dir};
+    use crate::proxy_plugin::tile_cache;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use warp::Filter;
+
+    const MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    // 上游收到的请求: 路径、If-None-Match、If-Modified-Since、是否带有覆盖请求头
+    type Seen = Arc<Mutex<Vec<(String, Option<String>, Option<String>, bool)>>>;
+
+    fn upstream(seen: Seen) -> u16 {
+        let filter = warp::path::full()
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and(warp::header::optional::<String>("if-modified-since"))
+            .and(warp::header::optional::<String>(OVERRIDE_HEADER))
+            .map(
+                move |path: warp::path::FullPath,
+                      etag: Option<String>,
+                      modified: Option<String>,
+                      ttl: Option<String>| {
+                    let path = path.as_str().to_string();
+                    seen.lock().unwrap().push((
+                        path.clone(),
+                        etag.clone(),
+                        modified.clone(),
+                        ttl.is_some(),
+                    ));
+                    let expires =
+                        httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+                    let builder = warp::http::Response::builder();
+                    let builder = match path.as_str() {
+                        "/max-age" => builder.header("cache-control", "max-age=60"),
+                        "/expires" => builder.header("expires", expires),
+                        "/expired" => builder.header("expires", "0"),
+                        "/no-store" => builder.header("cache-control", "no-store, max-age=60"),
+                        "/private" => builder.header("cache-control", "private, max-age=60"),
+                        "/etag" if etag.as_deref() == Some("\"v1\"") => builder.status(304),
+                        "/etag" => builder
+                            .header("etag", "\"v1\"")
+                            .header("cache-control", "no-cache"),
+                        "/modified" if modified.as_deref() == Some(MODIFIED) => builder.status(304),
+                        "/modified" => builder
+                            .header("last-modified", MODIFIED)
+                            .header("cache-control", "max-age=0"),
+                        _ => builder,
+                    };
+                    builder.body(path).unwrap()
+                },
+            );
+        serve(filter)
+    }
+
+    async fn get(port: u16, path: &str, ttl: Option<&str>) -> (Option<String>, String) {
+        let mut request = reqwest::Client::new().get(proxy_url(port, path));
+        if let Some(ttl) = ttl {
+            request = request.header(OVERRIDE_HEADER, ttl);
+        }
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let cache = response
+            .headers()
+            .get(tile_cache::CACHE_STATUS_HEADER)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = response.text().await.unwrap();
+        // 写入缓存在响应体转发完成之后
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        (cache, body)
+    }
+
+    fn hits(seen: &Seen, path: &str) -> usize {
+        seen.lock()
+            .unwrap()
+            .iter()
+            .filter(|(seen, ..)| seen == path)
+            .count()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn honours_freshness_and_revalidates() {
+        let _guard = setup(serde_json::json!({})).await;
+        let seen = Seen::default();
+        let port = upstream(seen.clone());
+
+        // max-age 和 Expires 有效期内直接返回; 私有缓存可以保存 private 响应
+        for path in ["/max-age", "/expires", "/private"] {
+            assert_eq!(get(port, path, None).await.0.as_deref(), Some("MISS"));
+            let (cache, body) = get(port, path, None).await;
+            assert_eq!(cache.as_deref(), Some("HIT"), "{}", path);
+            assert_eq!(body, path);
+            assert_eq!(hits(&seen, path), 1, "{}", path);
+        }
+        // no-store 和已过期且无校验信息的响应不保存
+        for path in ["/no-store", "/expired"] {
+            assert_eq!(get(port, path, None).await.0, None);
+            assert_eq!(get(port, path, None).await.0, None);
+            assert_eq!(hits(&seen, path), 2, "{}", path);
+        }
+
+        // 过期后带上 If-None-Match / If-Modified-Since, 上游返回 304 时使用缓存
+        for path in ["/etag", "/modified"] {
+            assert_eq!(get(port, path, None).await.0.as_deref(), Some("MISS"));
+            let (cache, body) = get(port, path, None).await;
+            assert_eq!(cache.as_deref(), Some("REVALIDATED"), "{}", path);
+            assert_eq!(body, path);
+        }
+        let seen_requests = seen.lock().unwrap().clone();
+        let conditional = |path: &str| {
+            seen_requests
+                .iter()
+                .filter(|(seen, ..)| seen == path)
+                .map(|(_, etag, modified, _)| (etag.clone(), modified.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            conditional("/etag"),
+            [(None, None), (Some("\"v1\"".to_string()), None)]
+        );
+        assert_eq!(
+            conditional("/modified"),
+            [(None, None), (None, Some(MODIFIED.to_string()))]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn override_header_sets_the_ttl() {
+        let _guard = setup(serde_json::json!({})).await;
+        let seen = Seen::default();
+        let port = upstream(seen.clone());
+
+        // 没有缓存头的响应按指定时间缓存
+        assert_eq!(get(port, "/plain", None).await.0, None);
+        assert_eq!(
+            get(port, "/plain", Some("1h")).await.0.as_deref(),
+            Some("MISS")
+        );
+        assert_eq!(
+            get(port, "/plain", Some("1h")).await.0.as_deref(),
+            Some("HIT")
+        );
+        assert_eq!(hits(&seen, "/plain"), 2);
+        // 之后的请求按保存时指定的时间判断是否过期
+        assert_eq!(get(port, "/plain", None).await.0.as_deref(), Some("HIT"));
+
+        // no-store 不读也不写缓存
+        assert_eq!(get(port, "/max-age", Some("no-store")).await.0, None);
+        assert_eq!(get(port, "/max-age", None).await.0.as_deref(), Some("MISS"));
+        assert_eq!(get(port, "/max-age", Some("no-store")).await.0, None);
+        assert_eq!(hits(&seen, "/max-age"), 3);
+
+        // 覆盖请求头不转发给上游
+        assert!(seen.lock().unwrap().iter().all(|(.., ttl)| !ttl));
+    }
+
+    #[tokio::test]
+    async fn entries_persist_without_secrets() {
+        let dir = temp_dir("http-cache-persist");
+        let _ = fs::remove_dir_all(&dir);
+        let url = "https://api.tianditu.gov.cn/v2/search?postStr=x&tk=secret-key";
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert("cache-control", HeaderValue::from_static("max-age=60"));
+        response_headers.insert("set-cookie", HeaderValue::from_static("sid=1"));
+
+        let cache = HttpCache::open(dir.clone(), DEFAULT_MAX_BYTES).unwrap();
+        cache
+            .put(url, &HeaderMap::new(), 200, &response_headers, b"{}", None)
+            .await
+            .unwrap();
+        drop(cache);
+
+        // 重新打开后恢复索引
+        let cache = HttpCache::open(dir.clone(), DEFAULT_MAX_BYTES).unwrap();
+        assert_eq!(cache.stats().entries, 1);
+        let cached = cache.get(url, &HeaderMap::new()).await.unwrap();
+        assert!(cached.is_fresh());
+        assert_eq!(cached.body, b"{}");
+        assert!(cached.headers().get("set-cookie").is_none());
+        // 只有 key 不同的地址不会命中
+        let other = url.replace("secret-key", "other-key");
+        assert!(cache.get(&other, &HeaderMap::new()).await.is_none());
+
+        for entry in fs::read_dir(&dir).unwrap().flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            assert!(!name.contains("tianditu"), "{}", name);
+            let data = fs::read_to_string(entry.path()).unwrap();
+            assert!(!data.contains("secret-key"), "{}", data);
+            assert!(!data.contains("sid=1"), "{}", data);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
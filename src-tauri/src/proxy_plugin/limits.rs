@@ -0,0 +1,349 @@
+use super::tls::host_matches;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// 按主机的限流状态, 配置变化后在下一次请求时重建
+static LIMITERS: Lazy<Mutex<HashMap<String, Arc<HostLimiter>>>> = Lazy::new(Default::default);
+// 未配置的主机各自有一份状态, 数量超过该值时清理空闲的主机
+const MAX_LIMITERS: usize = 256;
+
+/// 单个主机的限制, 未设置的项不限制
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct HostLimit {
+    // 令牌桶每秒补充的请求数
+    pub requests_per_sec: Option<f64>,
+    // 令牌桶容量, 为 0 时等于每秒请求数
+    pub burst: u32,
+    // 同时等待响应头的请求数
+    pub max_in_flight: Option<usize>,
+}
+
+/// 限流设置, 对应配置文件中的 `limits`;
+/// `hosts` 的键支持 `*.example.com` 通配, 通配匹配的所有子域名共用一份配额, 未匹配的主机各自使用 `default`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct LimitSettings {
+    pub default: HostLimit,
+    pub hosts: BTreeMap<String, HostLimit>,
+    // 排队超过该时间的请求直接失败
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        let tdt = HostLimit {
+            requests_per_sec: Some(50.0),
+            burst: 100,
+            max_in_flight: Some(16),
+        };
+        LimitSettings {
+            default: HostLimit {
+                requests_per_sec: None,
+                burst: 0,
+                max_in_flight: Some(32),
+            },
+            hosts: BTreeMap::from([
+                ("tianditu.gov.cn".to_string(), tdt.clone()),
+                ("*.tianditu.gov.cn".to_string(), tdt),
+            ]),
+            queue_timeout_ms: 10_000,
+        }
+    }
+}
+
+impl LimitSettings {
+    // 返回限流的键和限制, 匹配到 `hosts` 时以配置的键计数, 避免瓦片在多个子域名间轮换时配额成倍增加
+    fn limit_for<'a>(&'a self, host: &'a str) -> (&'a str, &'a HostLimit) {
+        self.hosts
+            .iter()
+            .find(|(pattern, _)| host_matches(pattern, host))
+            .map(|(pattern, limit)| (pattern.as_str(), limit))
+            .unwrap_or((host, &self.default))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HostLimitStats {
+    pub host: String,
+    pub requests_per_sec: Option<f64>,
+    pub max_in_flight: Option<usize>,
+    pub in_flight: usize,
+    // 当前排队中的请求数
+    pub queued: usize,
+    // 因限流而等待过的请求数
+    pub throttled: u64,
+    // 排队超时的请求数
+    pub rejected: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct HostLimiter {
+    limit: HostLimit,
+    semaphore: Option<Arc<Semaphore>>,
+    bucket: Mutex<Bucket>,
+    queued: AtomicUsize,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl HostLimiter {
+    fn new(limit: HostLimit) -> Self {
+        let tokens = capacity(&limit);
+        HostLimiter {
+            semaphore: limit
+                .max_in_flight
+                .map(|permits| Arc::new(Semaphore::new(permits.max(1)))),
+            bucket: Mutex::new(Bucket {
+                tokens,
+                updated: Instant::now(),
+            }),
+            limit,
+            queued: AtomicUsize::new(0),
+            throttled: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    // 预留一个令牌, 返回需要等待的时间; 令牌可以透支, 由等待时间抵扣
+    fn reserve(&self) -> Duration {
+        let rate = match self.limit.requests_per_sec {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return Duration::ZERO,
+        };
+        let capacity = capacity(&self.limit);
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    // 超时放弃时归还预留的令牌
+    fn release(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + 1.0).min(capacity(&self.limit));
+    }
+
+    fn in_flight(&self) -> usize {
+        match (&self.semaphore, self.limit.max_in_flight) {
+            (Some(semaphore), Some(max)) => max.max(1) - semaphore.available_permits(),
+            _ => 0,
+        }
+    }
+
+    // 没有排队和进行中的请求且令牌已补满, 删掉后重建的状态与原来相同
+    fn is_idle(&self) -> bool {
+        if self.queued.load(Ordering::Relaxed) > 0 || self.in_flight() > 0 {
+            return false;
+        }
+        let rate = match self.limit.requests_per_sec {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return true,
+        };
+        let bucket = self.bucket.lock().unwrap();
+        let refill = bucket.updated.elapsed().as_secs_f64() * rate;
+        bucket.tokens + refill >= capacity(&self.limit)
+    }
+
+    fn stats(&self, host: &str) -> HostLimitStats {
+        let in_flight = self.in_flight();
+        HostLimitStats {
+            host: host.to_string(),
+            requests_per_sec: self.limit.requests_per_sec,
+            max_in_flight: self.limit.max_in_flight,
+            in_flight,
+            queued: self.queued.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// 令牌桶容量, burst 为 0 时等于每秒请求数
+fn capacity(limit: &HostLimit) -> f64 {
+    match limit.burst {
+        0 => limit.requests_per_sec.unwrap_or(1.0).max(1.0),
+        burst => burst as f64,
+    }
+}
+
+/// 持有期间占用一个并发名额
+pub(crate) struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// 排队期间计入 queued
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn limiter(host: &str, settings: &LimitSettings) -> Arc<HostLimiter> {
+    let (key, limit) = settings.limit_for(host);
+    let mut limiters = LIMITERS.lock().unwrap();
+    match limiters.get(key) {
+        Some(limiter) if limiter.limit == *limit => limiter.clone(),
+        _ => {
+            if limiters.len() >= MAX_LIMITERS {
+                // 配置的主机保留统计, 其余空闲的主机直接删除
+                limiters.retain(|key, limiter| {
+                    settings.hosts.contains_key(key)
+                        || Arc::strong_count(limiter) > 1
+                        || !limiter.is_idle()
+                });
+            }
+            let limiter = Arc::new(HostLimiter::new(limit.clone()));
+            limiters.insert(key.to_string(), limiter.clone());
+            limiter
+        }
+    }
+}
+
+/// 按主机的并发和速率限制排队, 超时返回 Err;
+/// 名额在收到响应头后即可释放, 事件流等长连接不会一直占用
+pub(crate) async fn acquire(host: &str, settings: &LimitSettings) -> Result<Permit, Duration> {
+    let host = host.to_ascii_lowercase();
+    let limiter = limiter(&host, settings);
+    let timeout = Duration::from_millis(settings.queue_timeout_ms);
+    let deadline = Instant::now() + timeout;
+    let mut throttled = false;
+
+    let permit = match &limiter.semaphore {
+        Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                throttled = true;
+                limiter.queued.fetch_add(1, Ordering::Relaxed);
+                let _queued = QueueGuard(&limiter.queued);
+                match tokio::time::timeout(timeout, semaphore.clone().acquire_owned()).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => {
+                        limiter.throttled.fetch_add(1, Ordering::Relaxed);
+                        limiter.rejected.fetch_add(1, Ordering::Relaxed);
+                        return Err(timeout);
+                    }
+                }
+            }
+        },
+        None => None,
+    };
+
+    let wait = limiter.reserve();
+    if !wait.is_zero() {
+        if Instant::now() + wait > deadline {
+            limiter.release();
+            limiter.throttled.fetch_add(1, Ordering::Relaxed);
+            limiter.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(wait);
+        }
+        throttled = true;
+        limiter.queued.fetch_add(1, Ordering::Relaxed);
+        let _queued = QueueGuard(&limiter.queued);
+        tokio::time::sleep(wait).await;
+    }
+    if throttled {
+        limiter.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(Permit { _permit: permit })
+}
+
+pub(crate) fn stats() -> Vec<HostLimitStats> {
+    let limiters = LIMITERS.lock().unwrap();
+    let mut list: Vec<HostLimitStats> = limiters
+        .iter()
+        .map(|(host, limiter)| limiter.stats(host))
+        .collect();
+    list.sort_by(|a, b| a.host.cmp(&b.host));
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wildcard_hosts_share_one_quota() {
+        let settings = LimitSettings {
+            default: HostLimit::default(),
+            hosts: BTreeMap::from([(
+                "*.shared.test".to_string(),
+                HostLimit {
+                    requests_per_sec: Some(1.0),
+                    burst: 2,
+                    max_in_flight: None,
+                },
+            )]),
+            queue_timeout_ms: 0,
+        };
+        assert!(acquire("t0.shared.test", &settings).await.is_ok());
+        assert!(acquire("t1.shared.test", &settings).await.is_ok());
+        // 子域名轮换不会得到额外的配额
+        assert!(acquire("t2.shared.test", &settings).await.is_err());
+        // 未匹配的主机各自计数
+        assert!(acquire("a.other.test", &settings).await.is_ok());
+        assert!(acquire("b.other.test", &settings).await.is_ok());
+
+        let stats = stats();
+        let shared: Vec<_> = stats
+            .iter()
+            .filter(|stats| stats.host.ends_with("shared.test"))
+            .collect();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].host, "*.shared.test");
+        assert_eq!(shared[0].rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn idle_hosts_are_evicted() {
+        let settings = LimitSettings {
+            default: HostLimit {
+                requests_per_sec: None,
+                burst: 0,
+                max_in_flight: Some(1),
+            },
+            hosts: BTreeMap::new(),
+            queue_timeout_ms: 0,
+        };
+        let _busy = acquire("busy.evicted.test", &settings).await.unwrap();
+        for i in 0..MAX_LIMITERS * 2 {
+            drop(
+                acquire(&format!("{}.evicted.test", i), &settings)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let stats = stats();
+        let evicted: Vec<_> = stats
+            .iter()
+            .filter(|stats| stats.host.ends_with(".evicted.test"))
+            .collect();
+        assert!(evicted.len() <= MAX_LIMITERS);
+        // 仍有进行中请求的主机不会被删除
+        let busy = evicted
+            .iter()
+            .find(|stats| stats.host == "busy.evicted.test")
+            .unwrap();
+        assert_eq!(busy.in_flight, 1);
+        assert!(acquire("busy.evicted.test", &settings).await.is_err());
+    }
+}
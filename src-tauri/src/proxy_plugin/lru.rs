@@ -0,0 +1,127 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+struct Entry {
+    size: u64,
+    tick: u64,
+}
+
+/// 以访问序号实现的 LRU 索引, 记录每项的大小, 瓦片、HTTP 响应和图片缓存共用;
+/// 淘汰时按总大小从最久未访问的一项开始
+pub(crate) struct LruIndex<K> {
+    entries: HashMap<K, Entry>,
+    order: BTreeMap<u64, K>,
+    total_bytes: u64,
+    tick: u64,
+}
+
+impl<K> Default for LruIndex<K> {
+    fn default() -> Self {
+        LruIndex {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            total_bytes: 0,
+            tick: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> LruIndex<K> {
+    pub(crate) fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.contains_key(key)
+    }
+
+    pub(crate) fn touch<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            if let Some(key) = self.order.remove(&entry.tick) {
+                self.order.insert(self.tick, key);
+            }
+            entry.tick = self.tick;
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: K, size: u64) {
+        self.remove(&key);
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                size,
+                tick: self.tick,
+            },
+        );
+        self.total_bytes += size;
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.order.remove(&entry.tick);
+                self.total_bytes -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn pop_oldest(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        if let Some(entry) = self.entries.remove(&key) {
+            self.total_bytes -= entry.size;
+        }
+        Some(key)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.keys()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut index = LruIndex::default();
+        index.insert("a".to_string(), 10);
+        index.insert("b".to_string(), 20);
+        index.insert("c".to_string(), 30);
+        index.touch("a");
+        // 重新写入时替换原来的大小
+        index.insert("b".to_string(), 5);
+        assert_eq!(index.total_bytes(), 45);
+        assert_eq!(index.len(), 3);
+
+        assert_eq!(index.pop_oldest().as_deref(), Some("c"));
+        assert!(index.remove("a"));
+        assert!(!index.remove("a"));
+        assert!(!index.contains("c"));
+        assert_eq!(index.pop_oldest().as_deref(), Some("b"));
+        assert_eq!(index.pop_oldest(), None);
+        assert_eq!(index.total_bytes(), 0);
+    }
+}
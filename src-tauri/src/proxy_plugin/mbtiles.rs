@@ -0,0 +1,135 @@
+use super::offline::TileRegion;
+use super::tile_cache::{self, TileKey, MAX_TILE_ZOOM};
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+// MBTiles 1.3, 每个文件对应一个图层, 图层名写在 metadata 的 name 中
+// https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+
+// MBTiles 使用 TMS 行号, 与 XYZ 的 y 方向相反
+fn flip_y(z: u32, y: u32) -> u32 {
+    (1u32 << z) - 1 - y
+}
+
+/// 将离线存储及缓存中属于 `layer` 的瓦片导出为 MBTiles, 返回导出的瓦片数
+pub(crate) fn export(path: &Path, layer: &str, region: Option<&TileRegion>) -> Result<u64, String> {
+    let mut keys = BTreeSet::new();
+    for store in [tile_cache::offline_store(), tile_cache::tile_cache()]
+        .into_iter()
+        .flatten()
+    {
+        keys.extend(store.keys().into_iter().filter(|key| {
+            key.layer == layer && region.map(|r| r.contains(key)).unwrap_or(true)
+        }));
+    }
+    if keys.is_empty() {
+        return Err(format!("No cached tiles for layer {}", layer));
+    }
+
+    if path.exists() {
+        std::fs::remove_file(path).map_err(|e| e.to_string())?;
+    }
+    let mut conn = Connection::open(path).map_err(|e| e.to_string())?;
+    conn.execute_batch(
+        "CREATE TABLE metadata (name TEXT, value TEXT);
+         CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+         CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+    )
+    .map_err(|e| e.to_string())?;
+
+    let tx = conn.transaction().map_err(|e| e.to_string())?;
+    let mut count = 0u64;
+    let mut format = None;
+    {
+        let mut insert = tx
+            .prepare("INSERT OR REPLACE INTO tiles VALUES (?1, ?2, ?3, ?4)")
+            .map_err(|e| e.to_string())?;
+        for key in &keys {
+            let data = tile_cache::offline_store()
+                .and_then(|store| store.read_blocking(key))
+                .or_else(|| tile_cache::tile_cache().and_then(|cache| cache.read_blocking(key)));
+            let data = match data {
+                Some(data) => data,
+                None => continue,
+            };
+            if format.is_none() {
+                format = Some(tile_format(&data));
+            }
+            insert
+                .execute(params![key.z, key.x, flip_y(key.z, key.y), data])
+                .map_err(|e| e.to_string())?;
+            count += 1;
+        }
+    }
+
+    let min_zoom = keys.iter().map(|key| key.z).min().unwrap_or(0);
+    let max_zoom = keys.iter().map(|key| key.z).max().unwrap_or(0);
+    let mut metadata = vec![
+        ("name", layer.to_string()),
+        ("format", format.unwrap_or("png").to_string()),
+        ("minzoom", min_zoom.to_string()),
+        ("maxzoom", max_zoom.to_string()),
+    ];
+    if let Some(r) = region {
+        metadata.push((
+            "bounds",
+            format!("{},{},{},{}", r.west, r.south, r.east, r.north),
+        ));
+    }
+    for (name, value) in metadata {
+        tx.execute(
+            "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+            params![name, value],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+    Ok(count)
+}
+
+/// 将 MBTiles 中的瓦片导入离线存储, `layer` 为空时使用 metadata 中的 name
+pub(crate) fn import(path: &Path, layer: Option<&str>) -> Result<u64, String> {
+    let store = tile_cache::offline_store().ok_or("Offline store is not initialized")?;
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| e.to_string())?;
+    let layer = match layer {
+        Some(layer) => layer.to_string(),
+        None => conn
+            .query_row("SELECT value FROM metadata WHERE name = 'name'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(|_| "MBTiles metadata has no layer name".to_string())?,
+    };
+    if TileKey::new(&layer, 0, 0, 0).is_none() {
+        return Err(format!("Invalid layer: {}", layer));
+    }
+
+    let mut query = conn
+        .prepare("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles")
+        .map_err(|e| e.to_string())?;
+    let mut rows = query.query([]).map_err(|e| e.to_string())?;
+    let mut count = 0u64;
+    while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+        let z: u32 = row.get(0).map_err(|e| e.to_string())?;
+        let x: u32 = row.get(1).map_err(|e| e.to_string())?;
+        let tms_y: u32 = row.get(2).map_err(|e| e.to_string())?;
+        let data: Vec<u8> = row.get(3).map_err(|e| e.to_string())?;
+        if z > MAX_TILE_ZOOM || tms_y >= (1u32 << z) {
+            continue;
+        }
+        if let Some(key) = TileKey::new(&layer, z, x, flip_y(z, tms_y)) {
+            store.put_blocking(&key, &data).map_err(|e| e.to_string())?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn tile_format(data: &[u8]) -> &'static str {
+    match tile_cache::sniff_content_type(data) {
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        _ => "png",
+    }
+}
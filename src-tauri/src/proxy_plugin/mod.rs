@@ -6,19 +6,120 @@
 
 use tauri::{
     plugin::{Builder, TauriPlugin},
-    Runtime,
+    Manager, Runtime,
 };
+mod auth;
+mod client;
+mod coalesce;
 mod commands;
+mod config;
+mod headers;
+mod http_cache;
+mod limits;
+mod lru;
+mod mbtiles;
+mod offline;
+mod policy;
+mod redirect;
+mod replay;
+mod retry;
+mod stats;
+mod thumbnail;
+mod tile_cache;
+mod tiles;
+mod tls;
+mod traffic;
+mod transform;
+mod upstream;
+mod ws;
+
+#[cfg(test)]
+mod test_support;
 
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
     Builder::<R>::new("proxy-plugin")
         .setup(|app, _| {
-            commands::start_proxy_server().map_err(|e| e.to_string())?;
+            // 瓦片缓存失败不影响代理本身
+            match (app.path().app_cache_dir(), app.path().app_data_dir()) {
+                (Ok(cache_dir), Ok(data_dir)) => {
+                    if let Err(e) =
+                        tile_cache::init(cache_dir.join("tiles"), data_dir.join("offline_tiles"))
+                    {
+                        log::error!("failed to open tile cache: {}", e);
+                    }
+                    if let Err(e) = http_cache::init(cache_dir.join("http")) {
+                        log::error!("failed to open http cache: {}", e);
+                    }
+                    if let Err(e) = thumbnail::init(cache_dir.join("images")) {
+                        log::error!("failed to open image cache: {}", e);
+                    }
+                    replay::init(cache_dir.join("fixtures"));
+                }
+                (Err(e), _) | (_, Err(e)) => log::error!("failed to resolve app dirs: {}", e),
+            }
+            match app.path().app_config_dir() {
+                Ok(dir) => {
+                    if let Err(e) = config::init(dir) {
+                        log::error!("failed to load proxy config: {}", e);
+                    }
+                }
+                Err(e) => log::error!("failed to resolve config dir: {}", e),
+            }
+            let port = tauri::async_runtime::block_on(commands::start_proxy_server())
+                .map_err(|e| e.to_string())?;
+            commands::emit_ready(app, port);
+            stats::spawn_reporter(app.clone());
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             commands::get_proxy_url,
-            commands::get_proxy_port
+            commands::get_proxy_port,
+            commands::get_proxy_status,
+            commands::start_proxy,
+            commands::stop_proxy,
+            commands::restart_proxy,
+            commands::rebind_proxy,
+            commands::get_proxy_base_url,
+            commands::get_proxy_ws_url,
+            commands::get_image_url,
+            commands::get_tile_cache_stats,
+            commands::clear_tile_cache,
+            commands::list_upstreams,
+            commands::reload_proxy_config,
+            commands::get_client_settings,
+            commands::set_client_settings,
+            commands::rebuild_http_client,
+            commands::get_url_policy,
+            commands::set_url_policy,
+            commands::get_circuit_states,
+            commands::reset_circuits,
+            commands::get_rate_limits,
+            commands::set_rate_limits,
+            commands::get_rate_limit_stats,
+            commands::get_traffic_log_settings,
+            commands::set_traffic_log_settings,
+            commands::get_traffic_log,
+            commands::clear_traffic_log,
+            commands::get_traffic_har,
+            commands::export_traffic_har,
+            commands::get_replay_settings,
+            commands::set_replay_settings,
+            commands::get_transform_settings,
+            commands::set_transform_settings,
+            commands::get_tile_layers,
+            commands::get_proxy_stats,
+            commands::reset_proxy_stats,
+            commands::get_image_cache_stats,
+            commands::clear_image_cache,
+            commands::get_offline_tile_stats,
+            commands::clear_offline_tiles,
+            commands::get_http_cache_stats,
+            commands::clear_http_cache,
+            commands::download_tile_region,
+            commands::download_tile_corridor,
+            commands::cancel_tile_download,
+            commands::export_mbtiles,
+            commands::import_mbtiles
         ])
         .build()
 }
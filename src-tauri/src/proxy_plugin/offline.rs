@@ -0,0 +1,417 @@
+use super::config::{self, ProxyConfig};
+use super::tile_cache::{self, TileKey};
+use super::tiles::{self, TileLayer};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter, Runtime};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+pub(crate) const PROGRESS_EVENT: &str = "proxy://tile-download-progress";
+// 单次下载的瓦片数量上限, 防止误选过大的范围
+pub(crate) const MAX_REGION_TILES: u64 = 200_000;
+const MAX_ZOOM: u8 = 18;
+const CONCURRENCY: usize = 8;
+// Web Mercator 的纬度范围
+const MAX_LATITUDE: f64 = 85.051_128_78;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+// 每度纬度对应的公里数
+const KM_PER_DEGREE: f64 = 111.32;
+// 走廊缓冲距离上限
+const MAX_BUFFER_KM: f64 = 50.0;
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+static RUNNING_TASKS: Lazy<Mutex<HashMap<u64, Arc<AtomicBool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 经纬度范围及缩放级别, 坐标为 WGS84
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TileRegion {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+}
+
+impl TileRegion {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if !(-180.0..=180.0).contains(&self.west)
+            || !(-180.0..=180.0).contains(&self.east)
+            || !(-90.0..=90.0).contains(&self.south)
+            || !(-90.0..=90.0).contains(&self.north)
+        {
+            return Err("Region is out of range".to_string());
+        }
+        if self.west >= self.east || self.south >= self.north {
+            return Err("Region is empty".to_string());
+        }
+        if self.min_zoom > self.max_zoom || self.max_zoom > MAX_ZOOM {
+            return Err(format!("Zoom range must be within 0..={}", MAX_ZOOM));
+        }
+        Ok(())
+    }
+
+    // 指定级别下覆盖的瓦片行列范围 (含边界)
+    fn tile_range(&self, z: u8) -> (u32, u32, u32, u32) {
+        let (min_x, min_y) = lng_lat_to_tile(self.west, self.north, z);
+        let (max_x, max_y) = lng_lat_to_tile(self.east, self.south, z);
+        (min_x, min_y, max_x, max_y)
+    }
+
+    pub(crate) fn tile_count(&self) -> u64 {
+        (self.min_zoom..=self.max_zoom)
+            .map(|z| {
+                let (min_x, min_y, max_x, max_y) = self.tile_range(z);
+                (max_x - min_x + 1) as u64 * (max_y - min_y + 1) as u64
+            })
+            .sum()
+    }
+
+    pub(crate) fn contains(&self, key: &TileKey) -> bool {
+        if key.z < self.min_zoom as u32 || key.z > self.max_zoom as u32 {
+            return false;
+        }
+        let (min_x, min_y, max_x, max_y) = self.tile_range(key.z as u8);
+        (min_x..=max_x).contains(&key.x) && (min_y..=max_y).contains(&key.y)
+    }
+
+    // 范围内的瓦片 (z, x, y)
+    pub(crate) fn tiles(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+        (self.min_zoom..=self.max_zoom).flat_map(move |z| {
+            let (min_x, min_y, max_x, max_y) = self.tile_range(z);
+            (min_x..=max_x).flat_map(move |x| (min_y..=max_y).map(move |y| (z as u32, x, y)))
+        })
+    }
+}
+
+/// 沿路线的走廊, `path` 为 [经度, 纬度] 序列, 坐标为 WGS84, 缓冲距离单位为公里
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TileCorridor {
+    pub path: Vec<[f64; 2]>,
+    pub buffer_km: f64,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+}
+
+impl TileCorridor {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.path.is_empty() {
+            return Err("Path is empty".to_string());
+        }
+        if self
+            .path
+            .iter()
+            .any(|[lng, lat]| !(-180.0..=180.0).contains(lng) || !(-90.0..=90.0).contains(lat))
+        {
+            return Err("Path is out of range".to_string());
+        }
+        if !(self.buffer_km > 0.0 && self.buffer_km <= MAX_BUFFER_KM) {
+            return Err(format!("Buffer must be within 0..={} km", MAX_BUFFER_KM));
+        }
+        if self.min_zoom > self.max_zoom || self.max_zoom > MAX_ZOOM {
+            return Err(format!("Zoom range must be within 0..={}", MAX_ZOOM));
+        }
+        Ok(())
+    }
+
+    // 沿线按缓冲距离的一半取样, 取样点缓冲圆的并集近似走廊
+    fn samples(&self) -> Vec<(f64, f64)> {
+        let step = self.buffer_km / 2.0;
+        let mut samples = vec![(self.path[0][0], self.path[0][1])];
+        for pair in self.path.windows(2) {
+            let ([lng1, lat1], [lng2, lat2]) = (pair[0], pair[1]);
+            let count = (distance_km(lng1, lat1, lng2, lat2) / step).ceil().max(1.0) as usize;
+            for i in 1..=count {
+                let t = i as f64 / count as f64;
+                samples.push((lng1 + (lng2 - lng1) * t, lat1 + (lat2 - lat1) * t));
+            }
+        }
+        samples
+    }
+
+    /// 与走廊相交的瓦片 (z, x, y), 数量超过 `limit` 时返回错误
+    pub(crate) fn tiles(&self, limit: u64) -> Result<Vec<(u32, u32, u32)>, String> {
+        let samples = self.samples();
+        let mut tiles = Vec::new();
+        for z in self.min_zoom..=self.max_zoom {
+            let mut level = HashSet::new();
+            for &(lng, lat) in &samples {
+                let d_lat = self.buffer_km / KM_PER_DEGREE;
+                let d_lng = self.buffer_km / (KM_PER_DEGREE * lat.to_radians().cos().max(0.01));
+                let (min_x, min_y) = lng_lat_to_tile(lng - d_lng, lat + d_lat, z);
+                let (max_x, max_y) = lng_lat_to_tile(lng + d_lng, lat - d_lat, z);
+                for x in min_x..=max_x {
+                    for y in min_y..=max_y {
+                        // 瓦片上离取样点最近的位置在缓冲距离内才算相交
+                        let (west, north) = tile_to_lng_lat(x, y, z);
+                        let (east, south) = tile_to_lng_lat(x + 1, y + 1, z);
+                        let nearest_lng = lng.clamp(west, east);
+                        let nearest_lat = lat.clamp(south, north);
+                        if distance_km(lng, lat, nearest_lng, nearest_lat) <= self.buffer_km
+                            && level.insert((x, y))
+                            && (tiles.len() + level.len()) as u64 > limit
+                        {
+                            // 超出时立即停止, 不再计算剩余的瓦片
+                            return Err(format!(
+                                "Corridor contains more than {} tiles per layer, reduce the buffer or zoom range",
+                                limit
+                            ));
+                        }
+                    }
+                }
+            }
+            let mut level: Vec<(u32, u32)> = level.into_iter().collect();
+            level.sort_unstable();
+            tiles.extend(level.into_iter().map(|(x, y)| (z as u32, x, y)));
+        }
+        Ok(tiles)
+    }
+}
+
+pub(crate) fn lng_lat_to_tile(lng: f64, lat: f64, z: u8) -> (u32, u32) {
+    let n = (1u64 << z) as f64;
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = ((lng + 180.0) / 360.0 * n).floor();
+    let y = ((1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n).floor();
+    let max = n - 1.0;
+    (x.clamp(0.0, max) as u32, y.clamp(0.0, max) as u32)
+}
+
+// 瓦片左上角的经纬度
+fn tile_to_lng_lat(x: u32, y: u32, z: u8) -> (f64, f64) {
+    let n = (1u64 << z) as f64;
+    let lng = x as f64 / n * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * y as f64 / n)).sinh().atan().to_degrees();
+    (lng, lat)
+}
+
+// 球面距离 (公里)
+fn distance_km(lng1: f64, lat1: f64, lng2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (lng2 - lng1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DownloadProgress {
+    pub id: u64,
+    pub total: u64,
+    pub done: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub finished: bool,
+    pub cancelled: bool,
+}
+
+// 与瓦片路由使用同一套提供方、访问策略、重试、限流和回放流程
+async fn fetch_tile(config: &ProxyConfig, layer: &TileLayer, key: &TileKey) -> Result<(), String> {
+    let store = tile_cache::offline_store().ok_or("Offline store is not initialized")?;
+    let tile = tiles::fetch(config, layer, key)
+        .await
+        .map_err(|errors| errors.join("; "))?;
+    store.put(key, &tile.data).await.map_err(|e| e.to_string())
+}
+
+// 图层取自瓦片图层配置, 离线瓦片只保存主提供方的瓦片, 与缓存保持一致
+fn resolve_layers(
+    config: &ProxyConfig,
+    layers: &[String],
+    zooms: (u8, u8),
+) -> Result<Vec<(String, TileLayer)>, String> {
+    if layers.is_empty() {
+        return Err("No layers selected".to_string());
+    }
+    layers
+        .iter()
+        .map(|id| {
+            let layer = config
+                .tile_layers
+                .get(id)
+                .filter(|_| TileKey::new(id, 0, 0, 0).is_some())
+                .ok_or_else(|| format!("Invalid layer: {}", id))?;
+            let primary = TileLayer {
+                providers: layer.providers.iter().take(1).cloned().collect(),
+                ..layer.clone()
+            };
+            if primary.providers.is_empty() {
+                return Err(format!("Layer {} has no provider", id));
+            }
+            if !layer.has_zoom(zooms.0 as u32) || !layer.has_zoom(zooms.1 as u32) {
+                return Err(format!(
+                    "Layer {} supports zoom {}..={}",
+                    id, layer.min_zoom, layer.max_zoom
+                ));
+            }
+            Ok((id.clone(), primary))
+        })
+        .collect()
+}
+
+/// 后台下载指定范围内的瓦片到离线存储, 返回任务 id
+pub(crate) fn start_download<R: Runtime>(
+    app: AppHandle<R>,
+    region: TileRegion,
+    layers: Vec<String>,
+) -> Result<u64, String> {
+    region.validate()?;
+    let zooms = (region.min_zoom, region.max_zoom);
+    let layers = resolve_layers(&config::current(), &layers, zooms)?;
+    let per_layer = region.tile_count();
+    let total = per_layer * layers.len() as u64;
+    if total > MAX_REGION_TILES {
+        return Err(format!(
+            "Region contains {} tiles, the limit is {}",
+            total, MAX_REGION_TILES
+        ));
+    }
+    spawn_download(app, region.tiles().collect(), layers)
+}
+
+/// 后台下载沿路线走廊的瓦片到离线存储, 返回任务 id
+pub(crate) async fn start_corridor_download<R: Runtime>(
+    app: AppHandle<R>,
+    corridor: TileCorridor,
+    layers: Vec<String>,
+) -> Result<u64, String> {
+    corridor.validate()?;
+    let zooms = (corridor.min_zoom, corridor.max_zoom);
+    let layers = resolve_layers(&config::current(), &layers, zooms)?;
+    let limit = MAX_REGION_TILES / layers.len() as u64;
+    // 长路线的瓦片计算较慢, 不占用异步运行时的线程
+    let tiles = tauri::async_runtime::spawn_blocking(move || corridor.tiles(limit))
+        .await
+        .map_err(|e| e.to_string())??;
+    spawn_download(app, tiles, layers)
+}
+
+fn spawn_download<R: Runtime>(
+    app: AppHandle<R>,
+    tiles: Vec<(u32, u32, u32)>,
+    layers: Vec<(String, TileLayer)>,
+) -> Result<u64, String> {
+    let total = tiles.len() as u64 * layers.len() as u64;
+    let store = tile_cache::offline_store().ok_or("Offline store is not initialized")?;
+
+    let id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    RUNNING_TASKS.lock().unwrap().insert(id, cancelled.clone());
+
+    tauri::async_runtime::spawn(async move {
+        let semaphore = Arc::new(Semaphore::new(CONCURRENCY));
+        let mut progress = DownloadProgress {
+            id,
+            total,
+            done: 0,
+            skipped: 0,
+            failed: 0,
+            finished: false,
+            cancelled: false,
+        };
+        let mut tasks = JoinSet::new();
+        let mut last_emit = 0;
+        for (id, layer) in &layers {
+            let layer = Arc::new(layer.clone());
+            let keys = tiles
+                .iter()
+                .filter_map(|&(z, x, y)| TileKey::new(id, z, x, y));
+            for key in keys {
+                if cancelled.load(Ordering::SeqCst) {
+                    break;
+                }
+                if store.contains(&key) {
+                    progress.skipped += 1;
+                    continue;
+                }
+                let permit = semaphore.clone().acquire_owned().await.unwrap();
+                let layer = layer.clone();
+                tasks.spawn(async move {
+                    // 每个瓦片使用最新的配置, 下载过程中修改的策略和限流立即生效
+                    let result = fetch_tile(&config::current(), &layer, &key).await;
+                    drop(permit);
+                    if let Err(e) = &result {
+                        log::warn!("failed to download tile {:?}: {}", key, e);
+                    }
+                    result.is_ok()
+                });
+                // 收集已完成的任务并节流上报进度
+                while let Some(Ok(ok)) = tasks.try_join_next() {
+                    if ok {
+                        progress.done += 1;
+                    } else {
+                        progress.failed += 1;
+                    }
+                }
+                let handled = progress.done + progress.failed + progress.skipped;
+                if handled - last_emit >= 50 {
+                    last_emit = handled;
+                    let _ = app.emit(PROGRESS_EVENT, progress.clone());
+                }
+            }
+        }
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(true) => progress.done += 1,
+                _ => progress.failed += 1,
+            }
+        }
+        RUNNING_TASKS.lock().unwrap().remove(&id);
+        progress.cancelled = cancelled.load(Ordering::SeqCst);
+        progress.finished = true;
+        let _ = app.emit(PROGRESS_EVENT, progress);
+    });
+
+    Ok(id)
+}
+
+pub(crate) fn cancel_download(id: u64) -> bool {
+    match RUNNING_TASKS.lock().unwrap().get(&id) {
+        Some(flag) => {
+            flag.store(true, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corridor_limit_stops_early() {
+        // 北京到广州, 18 级时缓冲 50 公里有上亿个瓦片
+        let corridor = TileCorridor {
+            path: vec![[116.4, 39.9], [113.3, 23.1]],
+            buffer_km: 50.0,
+            min_zoom: 18,
+            max_zoom: 18,
+        };
+        corridor.validate().unwrap();
+        let started = std::time::Instant::now();
+        let err = corridor.tiles(MAX_REGION_TILES).unwrap_err();
+        assert!(err.contains("more than 200000 tiles"), "{}", err);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        let corridor = TileCorridor {
+            min_zoom: 8,
+            max_zoom: 10,
+            ..corridor
+        };
+        let tiles = corridor.tiles(MAX_REGION_TILES).unwrap();
+        assert!(tiles.iter().all(|&(z, _, _)| (8..=10).contains(&z)));
+        let unique: HashSet<_> = tiles.iter().collect();
+        assert_eq!(unique.len(), tiles.len());
+        assert!(corridor.tiles(tiles.len() as u64 - 1).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use super::config::ProxyConfig;
+use super::tls::host_matches;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// 代理可以访问的地址, 对应配置文件中的 `policy`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct UrlPolicy {
+    pub allowed_schemes: Vec<String>,
+    // 允许访问的主机, 支持 `*.example.com`, 默认只允许天地图, 为空时不限制;
+    // 命名上游和瓦片提供方的主机总是允许
+    pub allowed_hosts: Vec<String>,
+    // 禁止访问回环、局域网、链路本地 (含云元数据地址) 等保留地址, DNS 解析后同样检查
+    pub block_private: bool,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        UrlPolicy {
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_hosts: vec![
+                "tianditu.gov.cn".to_string(),
+                "*.tianditu.gov.cn".to_string(),
+            ],
+            block_private: true,
+        }
+    }
+}
+
+/// 被策略拦截的原因
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PolicyViolation {
+    pub host: String,
+    // 命中的规则, 例如 `allowedSchemes` 或 `blockPrivate`
+    pub rule: String,
+    pub detail: String,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} blocked by {}: {}", self.host, self.rule, self.detail)
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+const PRIVATE_V4: &[(Ipv4Addr, u8, &str)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8, "0.0.0.0/8"),
+    (Ipv4Addr::new(10, 0, 0, 0), 8, "10.0.0.0/8"),
+    (Ipv4Addr::new(100, 64, 0, 0), 10, "100.64.0.0/10"),
+    (Ipv4Addr::new(127, 0, 0, 0), 8, "127.0.0.0/8"),
+    (Ipv4Addr::new(169, 254, 0, 0), 16, "169.254.0.0/16"),
+    (Ipv4Addr::new(172, 16, 0, 0), 12, "172.16.0.0/12"),
+    (Ipv4Addr::new(192, 0, 0, 0), 24, "192.0.0.0/24"),
+    (Ipv4Addr::new(192, 168, 0, 0), 16, "192.168.0.0/16"),
+    (Ipv4Addr::new(198, 18, 0, 0), 15, "198.18.0.0/15"),
+    (Ipv4Addr::new(224, 0, 0, 0), 4, "224.0.0.0/4"),
+    (Ipv4Addr::new(240, 0, 0, 0), 4, "240.0.0.0/4"),
+];
+
+const PRIVATE_V6: &[(Ipv6Addr, u8, &str)] = &[
+    (Ipv6Addr::UNSPECIFIED, 128, "::/128"),
+    (Ipv6Addr::LOCALHOST, 128, "::1/128"),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7, "fc00::/7"),
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10, "fe80::/10"),
+    (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8, "ff00::/8"),
+];
+
+/// 内嵌 IPv4 的 IPv6 地址: IPv4 映射 `::ffff:0:0/96`、IPv4 兼容 `::/96`、
+/// NAT64 `64:ff9b::/96` 和 6to4 `2002::/16`
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let bits = u128::from(ip);
+    // `::` 和 `::1` 按 IPv6 检查
+    let compatible = bits >> 32 == 0 && bits > 1;
+    let nat64 = bits >> 32 == 0x0064_ff9b << 64;
+    if compatible || nat64 {
+        Some(Ipv4Addr::from(bits as u32))
+    } else if bits >> 112 == 0x2002 {
+        // 6to4 的 IPv4 位于第 16 到 48 位
+        Some(Ipv4Addr::from((bits >> 80) as u32))
+    } else {
+        None
+    }
+}
+
+/// 地址属于保留网段时返回网段
+pub(crate) fn private_range(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => PRIVATE_V4.iter().find_map(|(net, prefix, name)| {
+            let mask = u32::MAX << (32 - prefix);
+            (u32::from(ip) & mask == u32::from(*net)).then_some(*name)
+        }),
+        IpAddr::V6(ip) => {
+            // 内嵌 IPv4 的地址按 IPv4 检查
+            if let Some(v4) = embedded_ipv4(ip) {
+                return private_range(IpAddr::V4(v4));
+            }
+            PRIVATE_V6.iter().find_map(|(net, prefix, name)| {
+                let mask = u128::MAX << (128 - prefix);
+                (u128::from(ip) & mask == u128::from(*net)).then_some(*name)
+            })
+        }
+    }
+}
+
+fn violation(host: &str, rule: &str, detail: String) -> PolicyViolation {
+    PolicyViolation {
+        host: host.to_string(),
+        rule: rule.to_string(),
+        detail,
+    }
+}
+
+/// 检查目标地址是否允许访问, 域名解析后的地址由 [`PolicyResolver`] 检查
+pub(crate) fn check(config: &ProxyConfig, url: &Url) -> Result<(), PolicyViolation> {
+    let policy = &config.policy;
+    let host = url.host_str().unwrap_or_default();
+    if !policy
+        .allowed_schemes
+        .iter()
+        .any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+    {
+        return Err(violation(
+            host,
+            "allowedSchemes",
+            format!(
+                "scheme {} is not in [{}]",
+                url.scheme(),
+                policy.allowed_schemes.join(", ")
+            ),
+        ));
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let upstream_host = config.upstreams.values().any(|profile| {
+        Url::parse(&profile.base_url)
+            .ok()
+            .and_then(|base| base.host_str().map(|h| h.eq_ignore_ascii_case(host)))
+            .unwrap_or(false)
+    });
+    let provider_host = config
+        .tile_layers
+        .values()
+        .flat_map(|layer| &layer.providers)
+        .flat_map(|provider| provider.hosts())
+        .any(|h| h.eq_ignore_ascii_case(host));
+    if !policy.allowed_hosts.is_empty()
+        && !upstream_host
+        && !provider_host
+        && !policy
+            .allowed_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, host))
+    {
+        return Err(violation(
+            host,
+            "allowedHosts",
+            format!("host is not in [{}]", policy.allowed_hosts.join(", ")),
+        ));
+    }
+    if policy.block_private {
+        if let Some(range) = host.parse::<IpAddr>().ok().and_then(private_range) {
+            return Err(violation(
+                host,
+                "blockPrivate",
+                format!("address is in {}", range),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 解析域名, 开启 `blockPrivate` 时只要有一个地址是保留地址就拒绝, 避免连接时选中该地址
+pub(crate) async fn resolve(
+    host: &str,
+    port: u16,
+    block_private: bool,
+) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error + Send + Sync>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if block_private {
+        if let Some((addr, range)) = addrs
+            .iter()
+            .find_map(|addr| private_range(addr.ip()).map(|range| (addr, range)))
+        {
+            return Err(Box::new(violation(
+                host,
+                "blockPrivate",
+                format!("{} resolves to {} in {}", host, addr.ip(), range),
+            )));
+        }
+    }
+    Ok(addrs)
+}
+
+/// 经出站代理访问时目标域名由代理解析, 不经过 [`PolicyResolver`], 交给代理之前先在本地解析并检查
+pub(crate) async fn check_resolved(config: &ProxyConfig, url: &Url) -> Result<(), PolicyViolation> {
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    // IP 地址已由 check 检查
+    if !config.policy.block_private || host.parse::<IpAddr>().is_ok() {
+        return Ok(());
+    }
+    let port = url.port_or_known_default().unwrap_or_default();
+    match resolve(host, port, true).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(violation_error(e.as_ref()).unwrap_or_else(|| {
+            violation(
+                host,
+                "blockPrivate",
+                format!(
+                    "cannot resolve {} before using the outbound proxy: {}",
+                    host, e
+                ),
+            )
+        })),
+    }
+}
+
+/// 在域名解析之后过滤保留地址, 防止通过 DNS 指向内网
+pub(crate) struct PolicyResolver {
+    pub block_private: bool,
+    // 出站代理的主机, 不检查解析结果
+    pub trusted_hosts: Vec<String>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let trusted = self
+            .trusted_hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(name.as_str()));
+        let block_private = self.block_private && !trusted;
+        Box::pin(async move {
+            let addrs = resolve(name.as_str(), 0, block_private).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// 请求失败时, 从错误链中找出策略拦截的原因
+pub(crate) fn violation_error(err: &(dyn std::error::Error + 'static)) -> Option<PolicyViolation> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(violation) = err.downcast_ref::<PolicyViolation>() {
+            return Some(violation.clone());
+        }
+        source = err.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::upstream::UpstreamProfile;
+
+    fn rule(config: &ProxyConfig, url: &str) -> Option<String> {
+        check(config, &Url::parse(url).unwrap())
+            .err()
+            .map(|violation| violation.rule)
+    }
+
+    #[test]
+    fn schemes_outside_the_allowlist_are_rejected() {
+        let config = ProxyConfig::default();
+        assert_eq!(rule(&config, "https://api.tianditu.gov.cn/v2/search"), None);
+        assert_eq!(
+            rule(&config, "ftp://api.tianditu.gov.cn/").as_deref(),
+            Some("allowedSchemes")
+        );
+        assert_eq!(
+            rule(&config, "file:///etc/passwd").as_deref(),
+            Some("allowedSchemes")
+        );
+    }
+
+    #[test]
+    fn default_hosts_are_tianditu_and_upstreams() {
+        let mut config = ProxyConfig::default();
+        config.upstreams.insert(
+            "weather".to_string(),
+            UpstreamProfile {
+                base_url: "https://weather.example.com/api".to_string(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(rule(&config, "https://tianditu.gov.cn/"), None);
+        assert_eq!(rule(&config, "https://t5.tianditu.gov.cn/DataServer"), None);
+        assert_eq!(rule(&config, "https://weather.example.com/x"), None);
+        for url in [
+            "https://eviltianditu.gov.cn/",
+            "https://tianditu.gov.cn.example.com/",
+            "https://example.com/",
+        ] {
+            assert_eq!(
+                rule(&config, url).as_deref(),
+                Some("allowedHosts"),
+                "{}",
+                url
+            );
+        }
+
+        // 为空时不限制主机
+        config.policy.allowed_hosts.clear();
+        assert_eq!(rule(&config, "https://example.com/"), None);
+    }
+
+    #[tokio::test]
+    async fn private_ranges_are_blocked_after_resolution() {
+        for (ip, range) in [
+            ("127.0.0.1", "127.0.0.0/8"),
+            ("10.1.2.3", "10.0.0.0/8"),
+            ("169.254.169.254", "169.254.0.0/16"),
+            ("192.168.1.1", "192.168.0.0/16"),
+            ("::1", "::1/128"),
+            ("fd00::1", "fc00::/7"),
+            ("fe80::1", "fe80::/10"),
+            // IPv4 映射、NAT64、6to4 和 IPv4 兼容地址按内嵌的 IPv4 检查
+            ("::ffff:10.0.0.1", "10.0.0.0/8"),
+            ("64:ff9b::a9fe:a9fe", "169.254.0.0/16"),
+            ("2002:c0a8:0101::1", "192.168.0.0/16"),
+            ("::7f00:1", "127.0.0.0/8"),
+        ] {
+            let err = resolve(ip, 80, true).await.unwrap_err();
+            let violation = violation_error(err.as_ref()).unwrap();
+            assert_eq!(violation.rule, "blockPrivate");
+            assert!(violation.detail.ends_with(range), "{}", violation);
+            // 关闭时不拦截
+            assert!(resolve(ip, 80, false).await.is_ok());
+        }
+        for ip in [
+            "8.8.8.8",
+            "2400:3200::1",
+            "64:ff9b::808:808",
+            "2002:0808:0808::1",
+        ] {
+            assert_eq!(private_range(ip.parse().unwrap()), None, "{}", ip);
+        }
+
+        // 字面量地址在解析前由 check 拦截
+        let config = ProxyConfig {
+            policy: UrlPolicy {
+                allowed_hosts: Vec::new(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            rule(&config, "http://[64:ff9b::7f00:1]/").as_deref(),
+            Some("blockPrivate")
+        );
+        assert_eq!(
+            rule(&config, "http://[::ffff:192.168.0.1]/").as_deref(),
+            Some("blockPrivate")
+        );
+    }
+}
@@ -0,0 +1,457 @@
+use super::config::ProxyConfig;
+use super::headers;
+use super::policy::{self, PolicyViolation};
+use super::upstream::{Route, UpstreamProfile};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+// 请求头, 取值 manual / follow / rewrite, 不会转发给上游
+pub(crate) const MODE_HEADER: &str = "x-proxy-redirect";
+// 跨域跳转时不再携带的凭据
+const CREDENTIAL_HEADERS: [header::HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum RedirectMode {
+    // 原样返回 3xx
+    Manual,
+    // 由代理跟随跳转, 每一跳都检查访问策略
+    Follow,
+    // 把 Location 改写为代理地址
+    Rewrite,
+}
+
+impl RedirectMode {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "manual" => Some(RedirectMode::Manual),
+            "follow" => Some(RedirectMode::Follow),
+            "rewrite" => Some(RedirectMode::Rewrite),
+            _ => None,
+        }
+    }
+}
+
+/// 重定向设置, 对应配置文件中的 `redirect`, 命名上游可单独指定模式
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct RedirectSettings {
+    pub mode: RedirectMode,
+    pub max_hops: u32,
+}
+
+impl Default for RedirectSettings {
+    fn default() -> Self {
+        RedirectSettings {
+            mode: RedirectMode::Manual,
+            max_hops: 10,
+        }
+    }
+}
+
+pub(crate) enum SendError {
+    Request(reqwest::Error),
+    Policy(PolicyViolation),
+    // 熔断中, 附带剩余时间
+    CircuitOpen(Duration),
+    // 限流排队超时
+    Throttled(Duration),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Request(e) => write!(f, "{}", e),
+            SendError::Policy(violation) => write!(f, "{}", violation),
+            SendError::CircuitOpen(wait) => write!(f, "circuit open, retry in {:?}", wait),
+            SendError::Throttled(wait) => write!(f, "rate limited, waited {:?}", wait),
+        }
+    }
+}
+
+pub(crate) fn is_redirect(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308)
+}
+
+/// 按当前地址解析 Location, 支持相对地址
+pub(crate) fn location(headers: &HeaderMap, current: &Url) -> Option<Url> {
+    let location = headers.get(header::LOCATION)?.to_str().ok()?;
+    current.join(location).ok()
+}
+
+// 303 以及 POST 的 301/302 改为不带请求体的 GET
+fn drops_body(method: &Method, status: StatusCode) -> bool {
+    (status == StatusCode::SEE_OTHER && method != Method::HEAD)
+        || (matches!(status.as_u16(), 301 | 302) && method == Method::POST)
+}
+
+// 不带请求体的副本, 流式请求体无法复制时使用
+fn without_body(request: &reqwest::Request) -> reqwest::Request {
+    let mut copy = reqwest::Request::new(request.method().clone(), request.url().clone());
+    *copy.headers_mut() = request.headers().clone();
+    *copy.timeout_mut() = request.timeout().copied();
+    *copy.version_mut() = request.version();
+    copy
+}
+
+// 去掉命名上游注入的 key, 包括以查询参数注入和直接写在地址模板中的
+fn strip_key(url: &mut Url, profile: &UpstreamProfile) {
+    let key = profile.key.as_deref().filter(|key| !key.is_empty());
+    let injected = |name: &str, value: &str| {
+        profile.query_param.as_deref() == Some(name) || key == Some(value)
+    };
+    if !url
+        .query_pairs()
+        .any(|(name, value)| injected(&name, &value))
+    {
+        return;
+    }
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, value)| !injected(name, value))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    url.set_query(None);
+    if !pairs.is_empty() {
+        url.query_pairs_mut().extend_pairs(pairs);
+    }
+}
+
+fn redirect_request(
+    mut request: reqwest::Request,
+    status: StatusCode,
+    mut location: Url,
+    profile: Option<&UpstreamProfile>,
+) -> reqwest::Request {
+    if drops_body(request.method(), status) {
+        *request.method_mut() = Method::GET;
+        *request.body_mut() = None;
+        let headers = request.headers_mut();
+        headers.remove(header::CONTENT_TYPE);
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(header::TRANSFER_ENCODING);
+    }
+    if location.origin() != request.url().origin() {
+        for name in &CREDENTIAL_HEADERS {
+            request.headers_mut().remove(name);
+        }
+        if let Some(profile) = profile {
+            if let Some(header) = &profile.header {
+                request.headers_mut().remove(header.as_str());
+            }
+            strip_key(&mut location, profile);
+        }
+    }
+    *request.url_mut() = location;
+    request
+}
+
+/// 发送请求, `max_hops` 大于 0 时跟随跳转, 跳到其他源时不再携带 `profile` 注入的 key;
+/// 流式请求体无法重放, 307/308 时直接返回跳转响应; 经出站代理时每一跳都先在本地解析检查
+pub(crate) async fn execute(
+    client: &reqwest::Client,
+    config: &ProxyConfig,
+    mut request: reqwest::Request,
+    max_hops: u32,
+    profile: Option<&UpstreamProfile>,
+) -> Result<reqwest::Response, SendError> {
+    let outbound = profile
+        .and_then(|profile| profile.outbound.as_ref())
+        .unwrap_or(&config.client.outbound);
+    let mut hops = 0;
+    loop {
+        if outbound.may_proxy(request.url()) {
+            policy::check_resolved(config, request.url())
+                .await
+                .map_err(SendError::Policy)?;
+        }
+        if hops >= max_hops {
+            return client.execute(request).await.map_err(SendError::Request);
+        }
+        let replay = request.try_clone();
+        let bare = without_body(&request);
+        let current = request.url().clone();
+        let response = client.execute(request).await.map_err(SendError::Request)?;
+        let status = response.status();
+        let location = match location(response.headers(), &current) {
+            Some(location) if is_redirect(status) => location,
+            _ => return Ok(response),
+        };
+        let next = match replay {
+            Some(next) => next,
+            None if drops_body(bare.method(), status) => bare,
+            None => return Ok(response),
+        };
+        policy::check(config, &location).map_err(SendError::Policy)?;
+        request = redirect_request(next, status, location, profile);
+        hops += 1;
+    }
+}
+
+// 请求头段中去掉凭据, 跳到其他源时使用
+fn without_credentials(headers_part: &str) -> String {
+    let header_map = match headers::decode_header_segment(headers_part) {
+        Ok(header_map) => header_map,
+        Err(_) => return "_".to_string(),
+    };
+    let pairs: Vec<(String, String)> = header_map
+        .iter()
+        .filter(|(name, _)| !CREDENTIAL_HEADERS.contains(name))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    headers::encode_header_segment(&pairs)
+}
+
+/// 把跳转地址改写为代理地址, `current` 为返回跳转的地址, `proxy_base` 为带 token 的代理地址前缀;
+/// 跳到其他源时请求头段中不再保留凭据, 命名上游的跳转地址不在上游之内时退回到 `/proxy/_/{url}`
+pub(crate) fn rewrite_location(
+    location: &Url,
+    current: &Url,
+    route: &Route,
+    proxy_base: &str,
+) -> String {
+    let headers_part = match route {
+        Route::Proxy(headers_part) if location.origin() == current.origin() => headers_part.clone(),
+        Route::Proxy(headers_part) => without_credentials(headers_part),
+        Route::Upstream(name, profile) => {
+            // 不把注入的 key 暴露给前端, 请求时会重新注入
+            let mut target = location.clone();
+            strip_key(&mut target, profile);
+            let base_url = profile.base_url.trim_end_matches('/');
+            if let Some(rest) = target.as_str().strip_prefix(base_url) {
+                if rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') {
+                    return format!(
+                        "{}/upstream/{}/{}",
+                        proxy_base,
+                        name,
+                        rest.trim_start_matches('/')
+                    );
+                }
+            }
+            return format!(
+                "{}/proxy/_/{}",
+                proxy_base,
+                urlencoding::encode(target.as_str())
+            );
+        }
+    };
+    format!(
+        "{}/proxy/{}/{}",
+        proxy_base,
+        headers_part,
+        urlencoding::encode(location.as_str())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::commands;
+    use crate::proxy_plugin::test_support::{serve, setup};
+    use warp::Filter;
+
+    // 返回收到的查询参数和请求头
+    fn echo(
+    ) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone + Send + Sync + 'static
+    {
+        warp::path("echo")
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::headers_cloned())
+            .map(|query: String, headers: warp::http::HeaderMap| {
+                let mut body = format!("?{}\n", query);
+                for (name, value) in &headers {
+                    let value = value.to_str().unwrap_or_default();
+                    body.push_str(&format!("{}: {}\n", name, value));
+                }
+                body
+            })
+    }
+
+    fn found(location: String) -> warp::http::Response<String> {
+        warp::http::Response::builder()
+            .status(302)
+            .header("location", location)
+            .body(String::new())
+            .unwrap()
+    }
+
+    // 两个不同源的上游, 第一个的 /same 跳到同源的 /echo, /cross 带着查询参数跳到第二个
+    fn start_upstreams() -> (u16, u16) {
+        let other = serve(echo());
+        let same = warp::path("same").map(|| found("/echo".to_string()));
+        let cross = warp::path("cross")
+            .and(warp::query::raw())
+            .map(move |query: String| found(format!("http://localhost:{}/echo?{}", other, query)));
+        (serve(echo().or(same).or(cross)), other)
+    }
+
+    // 以查询参数和请求头两种方式注入 key 的命名上游
+    fn upstream_config(port: u16, mode: &str) -> serde_json::Value {
+        serde_json::json!({
+            "upstreams": {
+                "api": {
+                    "baseUrl": format!("http://127.0.0.1:{}", port),
+                    "key": "secret",
+                    "queryParam": "key",
+                    "header": "x-api-key",
+                    "redirect": mode,
+                }
+            }
+        })
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+    }
+
+    fn upstream_url(path: &str) -> String {
+        format!(
+            "{}/upstream/api/{}",
+            commands::get_proxy_base_url().unwrap(),
+            path
+        )
+    }
+
+    // 拆出改写后代理地址中的请求头段和目标地址
+    fn split_rewritten(location: &str) -> (HeaderMap, String) {
+        let prefix = format!("{}/proxy/", commands::get_proxy_base_url().unwrap());
+        let (segment, target) = location
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.split_once('/'))
+            .unwrap();
+        let target = urlencoding::decode(target).unwrap().into_owned();
+        (headers::decode_header_segment(segment).unwrap(), target)
+    }
+
+    #[test]
+    fn manual_is_the_default() {
+        assert_eq!(RedirectSettings::default().mode, RedirectMode::Manual);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unconfigured_redirects_are_returned() {
+        let (port, _) = start_upstreams();
+        let _guard = setup(serde_json::json!({})).await;
+        let url =
+            commands::get_proxy_url(&format!("http://127.0.0.1:{}/same", port), None).unwrap();
+        let response = client().get(url).send().await.unwrap();
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.headers()["location"], "/echo");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn follow_drops_credentials_across_origins() {
+        let (port, other) = start_upstreams();
+        let _guard = setup(upstream_config(port, "follow")).await;
+        let client = client();
+        let request = |url: String| {
+            client
+                .get(url)
+                .header("authorization", "Bearer t")
+                .header("cookie", "a=1")
+                .header("x-custom", "1")
+                .send()
+        };
+
+        let response = request(upstream_url("same?x=1")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("x-api-key: secret"), "{}", body);
+        assert!(body.contains("authorization: Bearer t"), "{}", body);
+        assert!(
+            body.contains(&format!("host: 127.0.0.1:{}", port)),
+            "{}",
+            body
+        );
+
+        let response = request(upstream_url("cross?x=1")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.text().await.unwrap();
+        assert!(
+            body.contains(&format!("host: localhost:{}", other)),
+            "{}",
+            body
+        );
+        assert!(body.starts_with("?x=1\n"), "{}", body);
+        assert!(body.contains("x-custom: 1"), "{}", body);
+        for leaked in ["secret", "x-api-key", "authorization", "cookie"] {
+            assert!(!body.contains(leaked), "{} leaked: {}", leaked, body);
+        }
+
+        // 代理路由通过请求头选择跟随
+        let url = commands::get_proxy_url(
+            &format!("http://127.0.0.1:{}/cross?x=1", port),
+            Some(vec![("authorization".to_string(), "Bearer t".to_string())]),
+        )
+        .unwrap();
+        let response = client.get(url).header(MODE_HEADER, "follow").send();
+        let body = response.await.unwrap().text().await.unwrap();
+        assert!(
+            body.contains(&format!("host: localhost:{}", other)),
+            "{}",
+            body
+        );
+        assert!(!body.contains("authorization"), "{}", body);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rewrite_drops_credentials_across_origins() {
+        let (port, other) = start_upstreams();
+        let _guard = setup(upstream_config(port, "rewrite")).await;
+        let client = client();
+        let segment = vec![
+            ("authorization".to_string(), "Bearer t".to_string()),
+            ("x-custom".to_string(), "1".to_string()),
+        ];
+        let proxied = |path: &str| {
+            let url = format!("http://127.0.0.1:{}/{}", port, path);
+            let url = commands::get_proxy_url(&url, Some(segment.clone())).unwrap();
+            client.get(url).header(MODE_HEADER, "rewrite").send()
+        };
+
+        let response = proxied("same").await.unwrap();
+        assert_eq!(response.status(), 302);
+        let location = response.headers()["location"].to_str().unwrap();
+        let (headers, target) = split_rewritten(location);
+        assert_eq!(target, format!("http://127.0.0.1:{}/echo", port));
+        assert_eq!(headers["authorization"], "Bearer t");
+
+        let response = proxied("cross?x=1").await.unwrap();
+        assert_eq!(response.status(), 302);
+        let location = response.headers()["location"].to_str().unwrap().to_string();
+        let (headers, target) = split_rewritten(&location);
+        assert_eq!(target, format!("http://localhost:{}/echo?x=1", other));
+        assert!(!headers.contains_key("authorization"));
+        assert_eq!(headers["x-custom"], "1");
+        let body = client
+            .get(location)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(body.contains("x-custom: 1"), "{}", body);
+        assert!(!body.contains("authorization"), "{}", body);
+
+        // 命名上游之内的跳转仍走命名上游, 之外的不带 key
+        let response = client.get(upstream_url("same")).send().await.unwrap();
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.headers()["location"], upstream_url("echo"));
+        let response = client.get(upstream_url("cross?x=1")).send().await.unwrap();
+        let location = response.headers()["location"].to_str().unwrap();
+        let (headers, target) = split_rewritten(location);
+        assert!(headers.is_empty());
+        assert_eq!(target, format!("http://localhost:{}/echo?x=1", other));
+    }
+}
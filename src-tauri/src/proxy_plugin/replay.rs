@@ -0,0 +1,384 @@
+use super::coalesce::{self, SharedResponse};
+use super::http_cache;
+use super::traffic;
+use once_cell::sync::OnceCell;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 超过该大小的响应不录制
+const MAX_FIXTURE_BYTES: u64 = 16 * 1024 * 1024;
+
+static DEFAULT_DIR: OnceCell<PathBuf> = OnceCell::new();
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ReplayMode {
+    #[default]
+    Off,
+    // 正常请求上游, 同时把响应写入夹具目录
+    Record,
+    // 只从夹具目录返回响应, 不访问网络
+    Replay,
+}
+
+/// 录制回放设置, 对应配置文件中的 `replay`;
+/// 请求按方法、地址和请求头匹配, 请求体不参与匹配
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct ReplaySettings {
+    pub mode: ReplayMode,
+    // 夹具目录, 未设置时使用缓存目录下的 fixtures
+    pub dir: Option<String>,
+    // 匹配时忽略的查询参数
+    pub ignore_params: Vec<String>,
+    // 匹配时忽略的请求头, 支持 `sec-*` 前缀通配
+    pub ignore_headers: Vec<String>,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        ReplaySettings {
+            mode: ReplayMode::Off,
+            dir: None,
+            ignore_params: vec!["tk".to_string()],
+            ignore_headers: [
+                "user-agent",
+                "accept-encoding",
+                "accept-language",
+                "authorization",
+                "cookie",
+                "cache-control",
+                "pragma",
+                "if-none-match",
+                "if-modified-since",
+                "referer",
+                "origin",
+                "priority",
+                "sec-*",
+            ]
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        }
+    }
+}
+
+impl ReplaySettings {
+    fn dir(&self) -> Option<PathBuf> {
+        match &self.dir {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => DEFAULT_DIR.get().cloned(),
+        }
+    }
+
+    fn ignores_param(&self, name: &str) -> bool {
+        self.ignore_params
+            .iter()
+            .any(|param| param.eq_ignore_ascii_case(name))
+    }
+
+    fn ignores_header(&self, name: &str) -> bool {
+        self.ignore_headers
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name
+                    .get(..prefix.len())
+                    .is_some_and(|head| head.eq_ignore_ascii_case(prefix)),
+                None => pattern.eq_ignore_ascii_case(name),
+            })
+    }
+}
+
+/// 夹具目录可能被共享或提交, 地址中的密钥和忽略的参数替换为占位符, 不保存 Cookie 和认证相关的头
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FixtureMeta {
+    // 规范化后的请求, 便于查看夹具对应的请求
+    request: String,
+    method: String,
+    url: String,
+    // 跟随跳转后的最终地址
+    response_url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    // 毫秒时间戳
+    recorded_at: u64,
+}
+
+/// 一次请求对应的夹具
+pub(crate) struct Fixture {
+    dir: PathBuf,
+    id: String,
+    request: String,
+    method: String,
+    url: String,
+    ignore_params: Vec<String>,
+}
+
+/// 未开启录制回放或没有夹具目录时返回 None
+pub(crate) fn fixture(
+    settings: &ReplaySettings,
+    method: &reqwest::Method,
+    url: &str,
+    headers: &HeaderMap,
+) -> Option<Fixture> {
+    if settings.mode == ReplayMode::Off {
+        return None;
+    }
+    let dir = settings.dir()?;
+    let request = normalize(settings, method, url, headers);
+    let id = Sha256::digest(request.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Some(Fixture {
+        dir,
+        id,
+        request,
+        method: method.to_string(),
+        url: url.to_string(),
+        ignore_params: settings.ignore_params.clone(),
+    })
+}
+
+// 查询参数按名称排序并去掉忽略的参数, 请求头转为小写后排序
+fn normalize(
+    settings: &ReplaySettings,
+    method: &reqwest::Method,
+    url: &str,
+    headers: &HeaderMap,
+) -> String {
+    let url = match Url::parse(url) {
+        Ok(mut parsed) => {
+            let mut pairs: Vec<(String, String)> = parsed
+                .query_pairs()
+                .filter(|(name, _)| !settings.ignores_param(name))
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+            pairs.sort();
+            parsed.set_fragment(None);
+            parsed.set_query(None);
+            if !pairs.is_empty() {
+                parsed.query_pairs_mut().extend_pairs(pairs);
+            }
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    };
+    let mut lines: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| !settings.ignores_header(name.as_str()))
+        .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect();
+    lines.sort();
+    let mut request = format!("{} {}", method, url);
+    for line in lines {
+        request.push('\n');
+        request.push_str(&line);
+    }
+    request
+}
+
+impl Fixture {
+    fn redact_url(&self, url: &str) -> String {
+        traffic::redact_url_params(url, &self.ignore_params)
+    }
+
+    // 规范化请求的第一行是方法和地址, 其余为请求头
+    fn redacted_request(&self) -> String {
+        let mut lines = self.request.lines();
+        let mut request = match lines.next().and_then(|line| line.split_once(' ')) {
+            Some((method, url)) => format!("{} {}", method, self.redact_url(url)),
+            None => String::new(),
+        };
+        for line in lines {
+            let (name, _) = line.split_once(": ").unwrap_or((line, ""));
+            request.push('\n');
+            if traffic::is_secret_header(name) {
+                request.push_str(&format!("{}: [REDACTED]", name));
+            } else {
+                request.push_str(line);
+            }
+        }
+        request
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.json", self.id))
+    }
+
+    fn body_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.body", self.id))
+    }
+
+    /// 读取录制的响应, 没有录制过时返回 None
+    pub(crate) async fn load(&self) -> Option<SharedResponse> {
+        let meta = tokio::fs::read(self.meta_path()).await.ok()?;
+        let meta: FixtureMeta = serde_json::from_slice(&meta).ok()?;
+        let body = tokio::fs::read(self.body_path()).await.ok()?;
+        let mut headers = HeaderMap::with_capacity(meta.headers.len());
+        for (name, value) in &meta.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        Some(SharedResponse {
+            status: StatusCode::from_u16(meta.status).ok()?,
+            headers,
+            url: Url::parse(&meta.response_url).ok()?,
+            body: body.into(),
+        })
+    }
+
+    /// 读取完整响应写入夹具目录, 事件流和过大的响应不录制
+    pub(crate) async fn record(&self, response: reqwest::Response) -> reqwest::Response {
+        match coalesce::buffer_response(response, MAX_FIXTURE_BYTES).await {
+            Ok(shared) => {
+                if let Err(e) = self.save(&shared).await {
+                    log::warn!(
+                        "failed to record fixture for {}: {}",
+                        self.redact_url(&self.url),
+                        e
+                    );
+                }
+                shared.to_response()
+            }
+            Err(response) => {
+                log::debug!("not recording {}", self.redact_url(&self.url));
+                response
+            }
+        }
+    }
+
+    async fn save(&self, response: &SharedResponse) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let recorded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        let headers = http_cache::stored_headers(&response.headers)
+            .into_iter()
+            .filter(|(name, _)| !traffic::is_secret_header(name))
+            .collect();
+        let meta = FixtureMeta {
+            request: self.redacted_request(),
+            method: self.method.clone(),
+            url: self.redact_url(&self.url),
+            response_url: self.redact_url(response.url.as_str()),
+            status: response.status.as_u16(),
+            headers,
+            recorded_at,
+        };
+        let meta = serde_json::to_vec_pretty(&meta).map_err(io::Error::other)?;
+        // 先写响应体, 读取时以元数据为准
+        self.write(self.body_path(), &response.body).await?;
+        self.write(self.meta_path(), &meta).await
+    }
+
+    async fn write(&self, path: PathBuf, data: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(format!(
+            "{}.{}.tmp",
+            self.id,
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+}
+
+pub(crate) fn init(dir: PathBuf) {
+    let _ = DEFAULT_DIR.set(dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy_plugin::commands;
+    use crate::proxy_plugin::config;
+    use crate::proxy_plugin::replay::ReplayMode;
+    use crate::proxy_plugin::test_support::{proxy_url, serve, setup, temp_dir};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use warp::Filter;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recorded_responses_replay_without_the_network() {
+        let dir = temp_dir("fixtures");
+        let _ = std::fs::remove_dir_all(&dir);
+        let _guard = setup(serde_json::json!({
+            "replay": { "mode": "record", "dir": dir.to_string_lossy() }
+        }))
+        .await;
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let port = serve(warp::path("data").map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            warp::http::Response::builder()
+                .header("content-type", "application/json")
+                .header("set-cookie", "session=cookie-secret")
+                .body(r#"{"ok":true}"#)
+                .unwrap()
+        }));
+        let client = reqwest::Client::new();
+        let get = |path: &str, agent: &str| {
+            client
+                .get(proxy_url(port, path))
+                .header("user-agent", agent)
+                .header("authorization", "Bearer auth-secret")
+                .send()
+        };
+
+        let response = get("/data?tk=key-secret&a=1", "recorder").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // 夹具中不保存密钥
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let text = String::from_utf8_lossy(&std::fs::read(entry.unwrap().path()).unwrap())
+                .into_owned();
+            for secret in ["key-secret", "auth-secret", "cookie-secret"] {
+                assert!(!text.contains(secret), "{} in {}", secret, text);
+            }
+        }
+
+        // 忽略的参数和请求头不影响匹配
+        config::update(|config| config.replay.mode = ReplayMode::Replay).unwrap();
+        let response = get("/data?a=1&tk=other-key", "player").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("set-cookie").is_none());
+        assert_eq!(response.text().await.unwrap(), r#"{"ok":true}"#);
+
+        // 没有录制时返回错误, 不访问上游
+        let response = get("/data?a=2", "player").await.unwrap();
+        assert_eq!(response.status(), 504);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("fixture_not_found"), "{}", body);
+
+        // 没有夹具目录时同样不访问上游
+        config::update(|config| config.replay.dir = None).unwrap();
+        let response = get("/data?a=1", "player").await.unwrap();
+        assert_eq!(response.status(), 504);
+        let ws_url = commands::get_proxy_ws_url(&format!("ws://127.0.0.1:{}/", port), None)
+            .unwrap()
+            .replacen("ws://", "http://", 1);
+        let response = client
+            .get(ws_url)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 504);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+}
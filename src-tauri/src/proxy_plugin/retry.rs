@@ -0,0 +1,472 @@
+use super::config::ProxyConfig;
+use super::limits;
+use super::policy;
+use super::redirect::{self, SendError};
+use super::tls;
+use super::upstream::UpstreamProfile;
+use once_cell::sync::Lazy;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+// 使用 tokio 的时钟, 测试时可以暂停和快进
+use tokio::time::Instant;
+
+// 按主机记录的熔断状态
+static CIRCUITS: Lazy<Mutex<HashMap<String, Circuit>>> = Lazy::new(Default::default);
+
+/// 重试策略, 对应配置文件中的 `retry`, 命名上游可单独指定;
+/// 只重试幂等请求, 流式请求体无法重放时不重试
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // 这些状态码的响应会重试
+    pub retry_status: Vec<u16>,
+    // Retry-After 超过该时间时不再等待, 直接返回上游的响应
+    pub max_retry_after_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 2,
+            base_delay_ms: 200,
+            max_delay_ms: 5000,
+            retry_status: vec![429, 502, 503, 504],
+            max_retry_after_secs: 10,
+        }
+    }
+}
+
+/// 熔断设置, 对应配置文件中的 `breaker`, `failureThreshold` 为 0 时不熔断
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct BreakerSettings {
+    // 连续失败多少次后熔断
+    pub failure_threshold: u32,
+    // 熔断后多久放行一个试探请求
+    pub open_secs: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        BreakerSettings {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum CircuitState {
+    Closed,
+    Open,
+    // 熔断时间已过, 正在放行试探请求
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CircuitInfo {
+    pub host: String,
+    pub state: CircuitState,
+    pub failures: u32,
+    // 熔断中时距离下一次试探的秒数
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    opened_at: Option<Instant>,
+    probe_started: Option<Instant>,
+}
+
+impl Circuit {
+    fn state(&self, open_for: Duration) -> CircuitState {
+        match self.opened_at {
+            Some(opened_at) if opened_at.elapsed() < open_for => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+}
+
+fn circuit_key(url: &Url) -> String {
+    url.host_str().unwrap_or_default().to_ascii_lowercase()
+}
+
+// 熔断中返回剩余时间; 熔断时间已过时同一时间只放行一个试探请求
+fn acquire(host: &str, settings: &BreakerSettings) -> Result<(), Duration> {
+    let open_for = Duration::from_secs(settings.open_secs);
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = match circuits.get_mut(host) {
+        Some(circuit) => circuit,
+        None => return Ok(()),
+    };
+    match (circuit.state(open_for), circuit.opened_at) {
+        (CircuitState::Open, Some(opened_at)) => Err(open_for - opened_at.elapsed()),
+        (CircuitState::HalfOpen, _) => {
+            // 试探请求可能被前端取消, 超时后允许再次试探
+            if let Some(started) = circuit.probe_started {
+                if started.elapsed() < open_for {
+                    return Err(open_for - started.elapsed());
+                }
+            }
+            circuit.probe_started = Some(Instant::now());
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn record_success(host: &str) {
+    CIRCUITS.lock().unwrap().remove(host);
+}
+
+fn record_failure(host: &str, settings: &BreakerSettings) {
+    if settings.failure_threshold == 0 {
+        return;
+    }
+    let mut circuits = CIRCUITS.lock().unwrap();
+    let circuit = circuits.entry(host.to_string()).or_default();
+    circuit.failures += 1;
+    // 试探失败或连续失败次数达到阈值时重新计时
+    if circuit.probe_started.is_some() || circuit.failures >= settings.failure_threshold {
+        circuit.opened_at = Some(Instant::now());
+        circuit.probe_started = None;
+    }
+}
+
+pub(crate) fn circuits(settings: &BreakerSettings) -> Vec<CircuitInfo> {
+    let open_for = Duration::from_secs(settings.open_secs);
+    let circuits = CIRCUITS.lock().unwrap();
+    let mut list: Vec<CircuitInfo> = circuits
+        .iter()
+        .map(|(host, circuit)| {
+            let state = circuit.state(open_for);
+            let retry_after_secs = match (state, circuit.opened_at) {
+                (CircuitState::Open, Some(opened_at)) => {
+                    Some((open_for - opened_at.elapsed()).as_secs() + 1)
+                }
+                _ => None,
+            };
+            CircuitInfo {
+                host: host.clone(),
+                state,
+                failures: circuit.failures,
+                retry_after_secs,
+            }
+        })
+        .collect();
+    list.sort_by(|a, b| a.host.cmp(&b.host));
+    list
+}
+
+pub(crate) fn reset_circuits() {
+    CIRCUITS.lock().unwrap().clear();
+}
+
+fn idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+// 网关错误说明主机不可用, 计入熔断; 429 只重试
+fn host_failure(status: u16) -> bool {
+    matches!(status, 502..=504)
+}
+
+// 连接失败和超时可以重试, 证书和访问策略的错误重试也不会成功
+fn transient(err: &reqwest::Error) -> bool {
+    (err.is_connect() || err.is_timeout())
+        && tls::verification_error(err).is_none()
+        && policy::violation_error(err).is_none()
+}
+
+/// 解析 Retry-After, 支持秒数和 HTTP 日期
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+// 指数退避, 在 [0, 上限] 内随机取值避免多个请求同时重试
+fn backoff(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let ceiling = policy
+        .base_delay_ms
+        .saturating_mul(1u64 << attempt.min(20))
+        .min(policy.max_delay_ms);
+    let mut bytes = [0u8; 8];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        return Duration::from_millis(ceiling);
+    }
+    Duration::from_millis(u64::from_le_bytes(bytes) % (ceiling + 1))
+}
+
+/// 发送请求并按策略重试, 同时维护目标主机的熔断状态
+pub(crate) async fn execute(
+    client: &reqwest::Client,
+    config: &ProxyConfig,
+    mut request: reqwest::Request,
+    max_hops: u32,
+    policy: &RetryPolicy,
+    profile: Option<&UpstreamProfile>,
+) -> Result<reqwest::Response, SendError> {
+    let host = circuit_key(request.url());
+    let breaker = &config.breaker;
+    let retries = if idempotent(request.method()) {
+        policy.max_retries
+    } else {
+        0
+    };
+    let max_wait = Duration::from_secs(policy.max_retry_after_secs);
+    let mut attempt = 0;
+    loop {
+        acquire(&host, breaker).map_err(SendError::CircuitOpen)?;
+        let permit = limits::acquire(&host, &config.limits)
+            .await
+            .map_err(SendError::Throttled)?;
+        let replay = if attempt < retries {
+            request.try_clone()
+        } else {
+            None
+        };
+        let result = redirect::execute(client, config, request, max_hops, profile).await;
+        drop(permit);
+        let delay = match &result {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                if host_failure(status) {
+                    record_failure(&host, breaker);
+                } else {
+                    record_success(&host);
+                }
+                if !policy.retry_status.contains(&status) {
+                    return result;
+                }
+                retry_after(response.headers())
+            }
+            Err(SendError::Request(e)) if transient(e) => {
+                record_failure(&host, breaker);
+                None
+            }
+            Err(_) => return result,
+        };
+        let next = match replay {
+            Some(next) => next,
+            None => return result,
+        };
+        let delay = delay.unwrap_or_else(|| backoff(policy, attempt));
+        if delay > max_wait {
+            return result;
+        }
+        log::debug!(
+            "retrying {} in {:?} (attempt {})",
+            next.url(),
+            delay,
+            attempt + 1
+        );
+        drop(result);
+        tokio::time::sleep(delay).await;
+        request = next;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::test_support::{proxy_url, serve, setup};
+    use std::sync::Arc;
+    use warp::Filter;
+
+    #[test]
+    fn backoff_is_jittered_below_the_ceiling() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..32 {
+            let ceiling = (policy.base_delay_ms << attempt.min(20)).min(policy.max_delay_ms);
+            let delays: Vec<Duration> = (0..200).map(|_| backoff(&policy, attempt)).collect();
+            assert!(
+                delays
+                    .iter()
+                    .all(|delay| delay.as_millis() as u64 <= ceiling),
+                "{}",
+                attempt
+            );
+            // 随机取值, 不会每次都相同
+            assert!(
+                delays.iter().any(|delay| *delay != delays[0]),
+                "{}",
+                attempt
+            );
+        }
+        let fixed = RetryPolicy {
+            base_delay_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(backoff(&fixed, 3), Duration::ZERO);
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_dates() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(retry_after(&headers("3")), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = retry_after(&headers(&later)).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        // 已经过去的时间不等待
+        let earlier = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(retry_after(&headers(&earlier)), Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn only_idempotent_methods_are_retried() {
+        for method in [Method::GET, Method::HEAD, Method::PUT, Method::DELETE] {
+            assert!(idempotent(&method), "{}", method);
+        }
+        for method in [Method::POST, Method::PATCH, Method::CONNECT] {
+            assert!(!idempotent(&method), "{}", method);
+        }
+    }
+
+    fn state(host: &str, settings: &BreakerSettings) -> Option<CircuitState> {
+        circuits(settings)
+            .into_iter()
+            .find(|circuit| circuit.host == host)
+            .map(|circuit| circuit.state)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn breaker_opens_half_opens_and_closes() {
+        let host = "breaker.test";
+        let settings = BreakerSettings {
+            failure_threshold: 2,
+            open_secs: 30,
+        };
+        record_failure(host, &settings);
+        assert_eq!(state(host, &settings), Some(CircuitState::Closed));
+        assert!(acquire(host, &settings).is_ok());
+
+        // 连续失败达到阈值后熔断
+        record_failure(host, &settings);
+        assert_eq!(state(host, &settings), Some(CircuitState::Open));
+        assert_eq!(acquire(host, &settings), Err(Duration::from_secs(30)));
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert_eq!(acquire(host, &settings), Err(Duration::from_secs(1)));
+
+        // 熔断时间过后只放行一个试探请求
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(state(host, &settings), Some(CircuitState::HalfOpen));
+        assert!(acquire(host, &settings).is_ok());
+        assert_eq!(acquire(host, &settings), Err(Duration::from_secs(30)));
+
+        // 试探失败时重新计时
+        record_failure(host, &settings);
+        assert_eq!(state(host, &settings), Some(CircuitState::Open));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(acquire(host, &settings).is_ok());
+        // 试探请求被取消时, 超时后允许再次试探
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(acquire(host, &settings).is_ok());
+
+        // 试探成功后恢复
+        record_success(host);
+        assert_eq!(state(host, &settings), None);
+        assert!(acquire(host, &settings).is_ok());
+
+        // 阈值为 0 时不熔断
+        let disabled = BreakerSettings {
+            failure_threshold: 0,
+            ..settings
+        };
+        for _ in 0..10 {
+            record_failure(host, &disabled);
+        }
+        assert!(acquire(host, &disabled).is_ok());
+        assert_eq!(state(host, &disabled), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flaky_upstream_is_retried() {
+        let _guard = setup(serde_json::json!({
+            "retry": {"maxRetries": 2, "baseDelayMs": 10, "maxDelayMs": 20, "maxRetryAfterSecs": 1},
+            "breaker": {"failureThreshold": 0}
+        }))
+        .await;
+        // 每个路径前两次返回 503, 之后返回 200
+        let hits = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+        let counter = hits.clone();
+        let upstream = warp::path::full().and(warp::body::bytes());
+        let port = serve(upstream.map(move |path: warp::path::FullPath, _| {
+            let mut hits = counter.lock().unwrap();
+            let hit = hits.entry(path.as_str().to_string()).or_default();
+            *hit += 1;
+            let builder = warp::http::Response::builder();
+            let builder = match (path.as_str(), *hit) {
+                (_, 3..) => builder.status(200),
+                ("/retry-after", _) => builder.status(503).header("retry-after", "1"),
+                ("/too-long", _) => builder.status(503).header("retry-after", "60"),
+                _ => builder.status(503),
+            };
+            builder.body(path.as_str().to_string()).unwrap()
+        }));
+        let hits = |path: &str| hits.lock().unwrap().get(path).copied().unwrap_or(0);
+        let client = reqwest::Client::new();
+
+        let response = client.get(proxy_url(port, "/flaky")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(hits("/flaky"), 3);
+
+        // 按 Retry-After 等待
+        let started = std::time::Instant::now();
+        let response = client
+            .get(proxy_url(port, "/retry-after"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        assert_eq!(hits("/retry-after"), 3);
+
+        // Retry-After 超过上限时直接返回上游的响应
+        let response = client
+            .get(proxy_url(port, "/too-long"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(hits("/too-long"), 1);
+
+        // 非幂等请求不重试
+        let response = client
+            .post(proxy_url(port, "/post"))
+            .body("x")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(hits("/post"), 1);
+    }
+}
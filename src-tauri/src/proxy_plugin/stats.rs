@@ -0,0 +1,252 @@
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Runtime};
+use warp::hyper::body::{Body, Bytes, HttpBody};
+
+pub(crate) const STATS_EVENT: &str = "proxy://stats";
+// 计算延迟分位数时保留的最近请求数
+const LATENCY_SAMPLES: usize = 1024;
+// 单独统计的主机数上限, 之后出现的新主机合并计入 OTHER_HOSTS
+const MAX_HOSTS: usize = 256;
+const OTHER_HOSTS: &str = "other";
+
+// 本次启动的时间, 停止时清空, 重启和换端口时重新计时
+static STARTED: Mutex<Option<Instant>> = Mutex::new(None);
+static STATS: Lazy<Mutex<Counters>> = Lazy::new(Default::default);
+
+/// 统计设置, 对应配置文件中的 `stats`, `eventIntervalSecs` 为 0 时不推送事件
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct StatsSettings {
+    pub event_interval_secs: u64,
+}
+
+impl Default for StatsSettings {
+    fn default() -> Self {
+        StatsSettings {
+            event_interval_secs: 5,
+        }
+    }
+}
+
+/// 字节数均以前端为视角: 入为请求体, 出为返回给前端的响应体
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HostStats {
+    pub host: String,
+    pub requests: u64,
+    // 按状态码类别计数, 如 2xx、5xx
+    pub status: BTreeMap<String, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProxyStats {
+    // 本次启动以来的时间, 未运行时为 0
+    pub uptime_secs: u64,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // 收到响应头的耗时
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    // 经过缓存的请求中命中的比例, 过期后重新校验和返回旧数据也算命中
+    pub cache_hit_ratio: Option<f64>,
+    // 连续返回 5xx 的请求数
+    pub error_streak: u64,
+    // 主机数超过上限后, 新出现的主机合并为一条 `other`
+    pub hosts: Vec<HostStats>,
+}
+
+#[derive(Default)]
+struct HostCounters {
+    requests: u64,
+    status: BTreeMap<String, u64>,
+    bytes_in: u64,
+    // 响应体边转发边计数
+    bytes_out: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Counters {
+    hosts: HashMap<String, HostCounters>,
+    latencies: VecDeque<f64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    error_streak: u64,
+}
+
+impl Counters {
+    fn host(&mut self, host: &str) -> &mut HostCounters {
+        let mut host = host.to_ascii_lowercase();
+        if !self.hosts.contains_key(&host) && self.hosts.len() >= MAX_HOSTS {
+            host = OTHER_HOSTS.to_string();
+        }
+        self.hosts.entry(host).or_default()
+    }
+}
+
+pub(crate) fn mark_started() {
+    *STARTED.lock().unwrap() = Some(Instant::now());
+}
+
+pub(crate) fn mark_stopped() {
+    *STARTED.lock().unwrap() = None;
+}
+
+/// 记录一次请求, 返回的响应体读取时统计发出的字节数
+pub(crate) fn record(
+    host: &str,
+    request_size: Option<u64>,
+    started: Instant,
+    reply: warp::reply::Response,
+) -> warp::reply::Response {
+    let latency = started.elapsed().as_secs_f64() * 1000.0;
+    let status = reply.status().as_u16();
+    let cache = reply
+        .headers()
+        .get(super::tile_cache::CACHE_STATUS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let mut stats = STATS.lock().unwrap();
+    if stats.latencies.len() == LATENCY_SAMPLES {
+        stats.latencies.pop_front();
+    }
+    stats.latencies.push_back(latency);
+    match cache.as_deref() {
+        Some("MISS") => stats.cache_misses += 1,
+        Some(_) => stats.cache_hits += 1,
+        None => {}
+    }
+    if status >= 500 {
+        stats.error_streak += 1;
+    } else {
+        stats.error_streak = 0;
+    }
+    let counters = stats.host(host);
+    counters.requests += 1;
+    *counters
+        .status
+        .entry(format!("{}xx", status / 100))
+        .or_default() += 1;
+    counters.bytes_in += request_size.unwrap_or(0);
+    let bytes_out = counters.bytes_out.clone();
+    drop(stats);
+
+    let (parts, body) = reply.into_parts();
+    let body = Body::wrap_stream(CountingBody {
+        inner: body,
+        bytes_out,
+    });
+    warp::reply::Response::from_parts(parts, body)
+}
+
+struct CountingBody {
+    inner: Body,
+    bytes_out: Arc<AtomicU64>,
+}
+
+impl Stream for CountingBody {
+    type Item = Result<Bytes, warp::hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes_out
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+// 最近邻秩法
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    let ms = sorted[rank.clamp(1, sorted.len()) - 1];
+    Some((ms * 1000.0).round() / 1000.0)
+}
+
+pub(crate) fn snapshot() -> ProxyStats {
+    let stats = STATS.lock().unwrap();
+    let mut latencies: Vec<f64> = stats.latencies.iter().copied().collect();
+    latencies.sort_by(f64::total_cmp);
+    let lookups = stats.cache_hits + stats.cache_misses;
+    let mut hosts: Vec<HostStats> = stats
+        .hosts
+        .iter()
+        .map(|(host, counters)| HostStats {
+            host: host.clone(),
+            requests: counters.requests,
+            status: counters.status.clone(),
+            bytes_in: counters.bytes_in,
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+        })
+        .collect();
+    hosts.sort_by(|a, b| a.host.cmp(&b.host));
+    ProxyStats {
+        uptime_secs: STARTED
+            .lock()
+            .unwrap()
+            .map_or(0, |started| started.elapsed().as_secs()),
+        requests: hosts.iter().map(|host| host.requests).sum(),
+        bytes_in: hosts.iter().map(|host| host.bytes_in).sum(),
+        bytes_out: hosts.iter().map(|host| host.bytes_out).sum(),
+        p50_ms: percentile(&latencies, 0.5),
+        p95_ms: percentile(&latencies, 0.95),
+        cache_hit_ratio: (lookups > 0).then(|| stats.cache_hits as f64 / lookups as f64),
+        error_streak: stats.error_streak,
+        hosts,
+    }
+}
+
+/// 清空计数, 不影响运行时间
+pub(crate) fn reset() {
+    *STATS.lock().unwrap() = Counters::default();
+}
+
+/// 按配置的间隔推送统计事件, 修改间隔后下一次推送生效
+pub(crate) fn spawn_reporter<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let interval = super::config::current().stats.event_interval_secs;
+            if interval == 0 {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let _ = app.emit(STATS_EVENT, snapshot());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_tail_hosts_are_folded_into_other() {
+        let mut counters = Counters::default();
+        for i in 0..MAX_HOSTS + 10 {
+            counters.host(&format!("{}.example.com", i)).requests += 1;
+        }
+        assert_eq!(counters.hosts.len(), MAX_HOSTS + 1);
+        assert_eq!(counters.hosts[OTHER_HOSTS].requests, 10);
+        // 已统计的主机继续单独计数, 不区分大小写
+        counters.host("0.EXAMPLE.com").requests += 1;
+        assert_eq!(counters.hosts["0.example.com"].requests, 2);
+        assert_eq!(counters.hosts.len(), MAX_HOSTS + 1);
+    }
+}
@@ -0,0 +1,209 @@
+use super::{commands, config, http_cache, thumbnail, tile_cache};
+use std::path::PathBuf;
+use std::sync::{Arc, Once};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, MutexGuard};
+use warp::{Filter, Reply};
+
+// 配置和代理服务都是全局的, 依赖它们的测试逐个执行
+static LOCK: Mutex<()> = Mutex::const_new(());
+static STORES: Once = Once::new();
+
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("proxy-test-{}-{}", std::process::id(), name))
+}
+
+/// 写入配置并确保代理在运行, 返回的锁在测试结束前不要释放;
+/// 未指定 policy 时放开主机和私有地址限制, 以便访问本地上游
+pub(crate) async fn setup(mut proxy_config: serde_json::Value) -> MutexGuard<'static, ()> {
+    let guard = LOCK.lock().await;
+    STORES.call_once(|| {
+        let _ = std::fs::remove_dir_all(temp_dir("cache"));
+        tile_cache::init(temp_dir("cache").join("tiles"), temp_dir("offline")).unwrap();
+        http_cache::init(temp_dir("cache").join("http")).unwrap();
+        thumbnail::init(temp_dir("cache").join("images")).unwrap();
+    });
+    if proxy_config.get("policy").is_none() {
+        proxy_config["policy"] = serde_json::json!({ "allowedHosts": [], "blockPrivate": false });
+    }
+    let dir = temp_dir("config");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("proxy.json"), proxy_config.to_string()).unwrap();
+    config::init(dir).unwrap();
+    // 代理服务运行在全局运行时上, 不随单个测试的运行时结束
+    tauri::async_runtime::spawn(commands::start_proxy_server())
+        .await
+        .unwrap()
+        .unwrap();
+    guard
+}
+
+/// 在随机端口上启动本地上游, 返回端口
+pub(crate) fn serve<F, R>(filter: F) -> u16
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let (addr, server) = warp::serve(filter).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr.port()
+}
+
+/// 经过代理访问本地上游的地址
+pub(crate) fn proxy_url(port: u16, path: &str) -> String {
+    commands::get_proxy_url(&format!("http://127.0.0.1:{}{}", port, path), None).unwrap()
+}
+
+/// 本地出站代理收到的一次连接
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Proxied {
+    // http (转发)、connect 或 socks5
+    pub kind: &'static str,
+    pub target: String,
+    // Proxy-Authorization 的值或 SOCKS5 的 `用户名:密码`
+    pub auth: Option<String>,
+}
+
+pub(crate) type ProxyLog = Arc<std::sync::Mutex<Vec<Proxied>>>;
+
+/// 在随机端口上启动出站代理, 支持 HTTP 转发、CONNECT 和 SOCKS5, 返回端口和连接记录
+pub(crate) async fn outbound_proxy() -> (u16, ProxyLog) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let log = ProxyLog::default();
+    let records = log.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let log = records.clone();
+            tokio::spawn(async move {
+                let _ = handle_proxied(stream, log).await;
+            });
+        }
+    });
+    (port, log)
+}
+
+async fn handle_proxied(mut client: TcpStream, log: ProxyLog) -> std::io::Result<()> {
+    let mut first = [0u8; 1];
+    client.peek(&mut first).await?;
+    let (record, mut upstream) = if first[0] == 5 {
+        socks5_accept(&mut client).await?
+    } else {
+        http_accept(&mut client).await?
+    };
+    log.lock().unwrap().push(record);
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+async fn http_accept(client: &mut TcpStream) -> std::io::Result<(Proxied, TcpStream)> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head).into_owned();
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default().to_string();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let mut auth = None;
+    // 转发时去掉连接相关的请求头, 每个连接只转发一个请求
+    let mut forwarded = format!("{}\r\n", request_line);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        match name.to_ascii_lowercase().as_str() {
+            "proxy-authorization" => auth = Some(value.trim().to_string()),
+            "connection" | "proxy-connection" => {}
+            _ => forwarded.push_str(&format!("{}\r\n", line)),
+        }
+    }
+    forwarded.push_str("connection: close\r\n\r\n");
+    if method == "CONNECT" {
+        let upstream = TcpStream::connect(target).await?;
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        let record = Proxied {
+            kind: "connect",
+            target: target.to_string(),
+            auth,
+        };
+        return Ok((record, upstream));
+    }
+    let authority = target
+        .split_once("://")
+        .map(|(_, rest)| rest.split('/').next().unwrap_or_default())
+        .unwrap_or_default()
+        .to_string();
+    let mut upstream = TcpStream::connect(&authority).await?;
+    upstream.write_all(forwarded.as_bytes()).await?;
+    let record = Proxied {
+        kind: "http",
+        target: authority,
+        auth,
+    };
+    Ok((record, upstream))
+}
+
+async fn socks5_accept(client: &mut TcpStream) -> std::io::Result<(Proxied, TcpStream)> {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+    let mut auth = None;
+    if methods.contains(&2) {
+        client.write_all(&[5, 2]).await?;
+        let mut version = [0u8; 2];
+        client.read_exact(&mut version).await?;
+        let mut username = vec![0u8; version[1] as usize];
+        client.read_exact(&mut username).await?;
+        let mut len = [0u8; 1];
+        client.read_exact(&mut len).await?;
+        let mut password = vec![0u8; len[0] as usize];
+        client.read_exact(&mut password).await?;
+        auth = Some(format!(
+            "{}:{}",
+            String::from_utf8_lossy(&username),
+            String::from_utf8_lossy(&password)
+        ));
+        client.write_all(&[1, 0]).await?;
+    } else {
+        client.write_all(&[5, 0]).await?;
+    }
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip).await?;
+            format!("[{}]", std::net::Ipv6Addr::from(ip))
+        }
+        _ => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            client.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port).await?;
+    let target = format!("{}:{}", host, u16::from_be_bytes(port));
+    let upstream = TcpStream::connect(&target).await?;
+    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    let record = Proxied {
+        kind: "socks5",
+        target,
+        auth,
+    };
+    Ok((record, upstream))
+}
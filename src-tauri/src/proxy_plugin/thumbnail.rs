@@ -0,0 +1,512 @@
+use super::lru::LruIndex;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageReader, Limits};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use warp::hyper::body::{Body, HttpBody};
+
+const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+// 输出图片的边长上限
+const MAX_DIMENSION: u32 = 4096;
+// 源图片的大小上限
+const MAX_SOURCE_BYTES: usize = 32 * 1024 * 1024;
+const MAX_SOURCE_SIDE: u32 = 16384;
+const MAX_SOURCE_PIXELS: u64 = 50_000_000;
+const DEFAULT_QUALITY: u8 = 80;
+
+static IMAGE_CACHE: OnceCell<ImageCache> = OnceCell::new();
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ImageFit {
+    // 等比缩放到框内, 不放大
+    #[default]
+    Contain,
+    // 等比缩放后居中裁剪, 填满整个框
+    Cover,
+    // 拉伸到指定尺寸
+    Fill,
+}
+
+impl ImageFit {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "contain" => Some(ImageFit::Contain),
+            "cover" => Some(ImageFit::Cover),
+            "fill" => Some(ImageFit::Fill),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImageFit::Contain => "contain",
+            ImageFit::Cover => "cover",
+            ImageFit::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ImageFormat {
+    // 无损压缩, 保留透明度, 照片的体积较大
+    Webp,
+    // 有损压缩, 质量由 `quality` 指定
+    #[default]
+    Jpeg,
+}
+
+impl ImageFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "webp" => Some(ImageFormat::Webp),
+            "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "webp",
+            ImageFormat::Jpeg => "jpeg",
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// `/img` 的缩放参数, 对应查询参数 `w` `h` `fit` `format` `q`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct ImageOptions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: ImageFit,
+    pub format: ImageFormat,
+    // 只用于 jpeg, 1-100
+    pub quality: Option<u8>,
+}
+
+impl ImageOptions {
+    pub(crate) fn from_query(query: &HashMap<String, String>) -> Result<Self, ImageError> {
+        let dimension = |name: &str| -> Result<Option<u32>, ImageError> {
+            match query.get(name) {
+                Some(value) => value.parse().map(Some).map_err(|_| {
+                    ImageError::InvalidOptions(format!("{} must be a positive integer", name))
+                }),
+                None => Ok(None),
+            }
+        };
+        let options = ImageOptions {
+            width: dimension("w")?,
+            height: dimension("h")?,
+            fit: match query.get("fit") {
+                Some(value) => ImageFit::parse(value).ok_or_else(|| {
+                    ImageError::InvalidOptions("fit must be contain, cover or fill".to_string())
+                })?,
+                None => ImageFit::default(),
+            },
+            format: match query.get("format") {
+                Some(value) => ImageFormat::parse(value).ok_or_else(|| {
+                    ImageError::InvalidOptions("format must be webp or jpeg".to_string())
+                })?,
+                None => ImageFormat::default(),
+            },
+            quality: match query.get("q") {
+                Some(value) => Some(value.parse().map_err(|_| {
+                    ImageError::InvalidOptions("q must be within 1..=100".to_string())
+                })?),
+                None => None,
+            },
+        };
+        options.validate()?;
+        Ok(options)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ImageError> {
+        let invalid = |message: String| Err(ImageError::InvalidOptions(message));
+        if self.width.is_none() && self.height.is_none() {
+            return invalid("w or h is required".to_string());
+        }
+        for size in [self.width, self.height].into_iter().flatten() {
+            if size == 0 || size > MAX_DIMENSION {
+                return invalid(format!("w and h must be within 1..={}", MAX_DIMENSION));
+            }
+        }
+        if self.fit != ImageFit::Contain && (self.width.is_none() || self.height.is_none()) {
+            return invalid(format!("fit={} requires both w and h", self.fit.as_str()));
+        }
+        if let Some(quality) = self.quality {
+            if self.format != ImageFormat::Jpeg {
+                return invalid("q is only supported for jpeg".to_string());
+            }
+            if !(1..=100).contains(&quality) {
+                return invalid("q must be within 1..=100".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_query(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(width) = self.width {
+            params.push(format!("w={}", width));
+        }
+        if let Some(height) = self.height {
+            params.push(format!("h={}", height));
+        }
+        params.push(format!("fit={}", self.fit.as_str()));
+        params.push(format!("format={}", self.format.as_str()));
+        if let Some(quality) = self.quality {
+            params.push(format!("q={}", quality));
+        }
+        params.join("&")
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ImageError {
+    InvalidOptions(String),
+    TooLarge(String),
+    Unsupported(String),
+    // 解码或编码失败
+    Processing(String),
+}
+
+impl ImageError {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ImageError::InvalidOptions(_) => "invalid_image_options",
+            ImageError::TooLarge(_) => "image_too_large",
+            ImageError::Unsupported(_) => "unsupported_image",
+            ImageError::Processing(_) => "image_processing_failed",
+        }
+    }
+
+    pub(crate) fn status(&self) -> u16 {
+        match self {
+            ImageError::InvalidOptions(_) => 400,
+            ImageError::TooLarge(_) => 413,
+            ImageError::Unsupported(_) => 415,
+            ImageError::Processing(_) => 422,
+        }
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::InvalidOptions(message)
+            | ImageError::TooLarge(message)
+            | ImageError::Unsupported(message)
+            | ImageError::Processing(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// 读取源图片, 超过大小上限时返回错误
+pub(crate) async fn read_source(mut body: Body) -> Result<Vec<u8>, ImageError> {
+    let too_large =
+        || ImageError::TooLarge(format!("Source image exceeds {} bytes", MAX_SOURCE_BYTES));
+    if body
+        .size_hint()
+        .exact()
+        .is_some_and(|size| size > MAX_SOURCE_BYTES as u64)
+    {
+        return Err(too_large());
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ImageError::Processing(e.to_string()))?;
+        if data.len() + chunk.len() > MAX_SOURCE_BYTES {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// 解码、缩放并重新编码, 耗时较长, 应在阻塞线程中调用
+pub(crate) fn render(data: &[u8], options: &ImageOptions) -> Result<Vec<u8>, ImageError> {
+    let open = || {
+        ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|e| ImageError::Processing(e.to_string()))
+    };
+    let reader = open()?;
+    if reader.format().is_none() {
+        return Err(ImageError::Unsupported(
+            "Unrecognized image format".to_string(),
+        ));
+    }
+    // 先只读尺寸, 过大的图片不解码
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| ImageError::Unsupported(e.to_string()))?;
+    if width > MAX_SOURCE_SIDE
+        || height > MAX_SOURCE_SIDE
+        || width as u64 * height as u64 > MAX_SOURCE_PIXELS
+    {
+        return Err(ImageError::TooLarge(format!(
+            "Source image is {}x{}, the limit is {} pixels",
+            width, height, MAX_SOURCE_PIXELS
+        )));
+    }
+    let mut reader = open()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_SIDE);
+    limits.max_image_height = Some(MAX_SOURCE_SIDE);
+    reader.limits(limits);
+    let source = reader
+        .decode()
+        .map_err(|e| ImageError::Processing(e.to_string()))?;
+
+    let image = resize(&source, options);
+    let mut output = Vec::new();
+    let result = match options.format {
+        ImageFormat::Webp => DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut output)),
+        ImageFormat::Jpeg => {
+            let quality = options.quality.unwrap_or(DEFAULT_QUALITY);
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality))
+        }
+    };
+    result.map_err(|e| ImageError::Processing(e.to_string()))?;
+    Ok(output)
+}
+
+fn resize(source: &DynamicImage, options: &ImageOptions) -> DynamicImage {
+    let filter = FilterType::CatmullRom;
+    match (options.fit, options.width, options.height) {
+        (ImageFit::Cover, Some(width), Some(height)) => {
+            source.resize_to_fill(width, height, filter)
+        }
+        (ImageFit::Fill, Some(width), Some(height)) => source.resize_exact(width, height, filter),
+        _ => {
+            let width = options.width.unwrap_or(u32::MAX);
+            let height = options.height.unwrap_or(u32::MAX);
+            if source.width() <= width && source.height() <= height {
+                source.clone()
+            } else {
+                source.resize(width, height, filter)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ImageCacheStats {
+    pub dir: String,
+    pub entries: usize,
+    pub total_bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// 缩放后的图片缓存, 文件名为来源地址、请求头段和缩放参数的哈希
+pub(crate) struct ImageCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<LruIndex<String>>,
+}
+
+impl ImageCache {
+    pub(crate) fn open(dir: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let cache = ImageCache {
+            dir,
+            max_bytes,
+            index: Mutex::new(LruIndex::default()),
+        };
+        cache.load_index()?;
+        Ok(cache)
+    }
+
+    // 启动时扫描缓存目录, 按修改时间恢复 LRU 顺序
+    fn load_index(&self) -> io::Result<()> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("img") {
+                // 清理写入中断留下的临时文件
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let key = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+            if let Ok(meta) = entry.metadata() {
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, key, meta.len()));
+            }
+        }
+        found.sort_by_key(|entry| entry.0);
+        let mut index = self.index.lock().unwrap();
+        for (_, key, size) in found {
+            index.insert(key, size);
+        }
+        drop(index);
+        self.evict();
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.img", key))
+    }
+
+    pub(crate) fn key(url: &str, headers_part: &str, options: &ImageOptions) -> String {
+        let variant = format!("{}\n{}\n{}", url, headers_part, options.to_query());
+        Sha256::digest(variant.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        if !self.index.lock().unwrap().contains(key) {
+            return None;
+        }
+        match tokio::fs::read(self.path(key)).await {
+            Ok(data) => {
+                self.index.lock().unwrap().touch(key);
+                Some(data)
+            }
+            Err(_) => None,
+        }
+    }
+
+    pub(crate) async fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(format!(
+            "{}.{}.tmp",
+            key,
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, self.path(key)).await?;
+        self.index
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.len() as u64);
+        self.evict();
+        Ok(())
+    }
+
+    fn evict(&self) {
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            while index.total_bytes() > self.max_bytes {
+                match index.pop_oldest() {
+                    Some(key) => evicted.push(key),
+                    None => break,
+                }
+            }
+        }
+        for key in evicted {
+            let _ = fs::remove_file(self.path(&key));
+        }
+    }
+
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        *index = LruIndex::default();
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            if entry.path().is_file() {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> ImageCacheStats {
+        let index = self.index.lock().unwrap();
+        ImageCacheStats {
+            dir: self.dir.to_string_lossy().into_owned(),
+            entries: index.len(),
+            total_bytes: index.total_bytes(),
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+pub(crate) fn init(dir: PathBuf) -> io::Result<()> {
+    let cache = ImageCache::open(dir, DEFAULT_MAX_BYTES)?;
+    let _ = IMAGE_CACHE.set(cache);
+    Ok(())
+}
+
+pub(crate) fn image_cache() -> Option<&'static ImageCache> {
+    IMAGE_CACHE.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat as Codec, Rgb, RgbImage};
+
+    // 带渐变和噪点的照片式图片
+    fn photo() -> Vec<u8> {
+        let image = RgbImage::from_fn(256, 256, |x, y| {
+            let noise = ((x * 7919 + y * 104_729) % 61) as u8;
+            Rgb([x as u8, y as u8, noise.wrapping_mul(4)])
+        });
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), Codec::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn thumbnails_default_to_jpeg() {
+        let query = HashMap::from([("w".to_string(), "128".to_string())]);
+        let options = ImageOptions::from_query(&query).unwrap();
+        assert_eq!(options.format, ImageFormat::Jpeg);
+        let source = photo();
+        let output = render(&source, &options).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), Codec::Jpeg);
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 128);
+
+        let with_quality = |quality| ImageOptions {
+            quality: Some(quality),
+            ..options.clone()
+        };
+        let low = render(&source, &with_quality(30)).unwrap();
+        let high = render(&source, &with_quality(95)).unwrap();
+        assert!(low.len() < high.len(), "{} >= {}", low.len(), high.len());
+
+        let webp = ImageOptions {
+            format: ImageFormat::Webp,
+            ..options
+        };
+        let output = render(&source, &webp).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), Codec::WebP);
+        assert!(with_quality(80).validate().is_ok());
+        assert!(ImageOptions {
+            quality: Some(80),
+            ..webp
+        }
+        .validate()
+        .is_err());
+    }
+}
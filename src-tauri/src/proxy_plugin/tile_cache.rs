@@ -0,0 +1,393 @@
+use super::lru::LruIndex;
+use once_cell::sync::OnceCell;
+use reqwest::Url;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// 默认缓存上限 512MB
+pub(crate) const DEFAULT_MAX_BYTES: u64 = 512 * 1024 * 1024;
+// 超过该时间的瓦片视为过期, 会尝试重新拉取, 拉取失败时仍然返回旧瓦片
+pub(crate) const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+pub(crate) const MAX_TILE_ZOOM: u32 = 30;
+// 响应头, 取值 HIT / MISS / STALE / REVALIDATED
+pub(crate) const CACHE_STATUS_HEADER: &str = "x-proxy-cache";
+
+static TILE_CACHE: OnceCell<TileCache> = OnceCell::new();
+// 离线下载/导入的瓦片单独存放, 不参与淘汰
+static OFFLINE_STORE: OnceCell<TileCache> = OnceCell::new();
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub(crate) struct TileKey {
+    pub layer: String,
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileKey {
+    pub(crate) fn new(layer: &str, z: u32, x: u32, y: u32) -> Option<Self> {
+        if z > MAX_TILE_ZOOM || x >= (1 << z) || y >= (1 << z) {
+            return None;
+        }
+        if layer.is_empty()
+            || !layer
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return None;
+        }
+        Some(TileKey {
+            layer: layer.to_string(),
+            z,
+            x,
+            y,
+        })
+    }
+
+    /// 从天地图瓦片地址中解析出 layer/z/x/y, 例如
+    /// `https://t0.tianditu.gov.cn/DataServer?T=vec_w&x=1&y=2&l=3&tk=...`
+    pub(crate) fn from_url(url: &Url) -> Option<Self> {
+        let host = url.host_str()?;
+        if !host.ends_with(".tianditu.gov.cn") || !url.path().eq_ignore_ascii_case("/DataServer") {
+            return None;
+        }
+        let (mut layer, mut x, mut y, mut z) = (None, None, None, None);
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "T" => layer = Some(value.into_owned()),
+                "x" => x = value.parse().ok(),
+                "y" => y = value.parse().ok(),
+                "l" => z = value.parse().ok(),
+                _ => {}
+            }
+        }
+        TileKey::new(&layer?, z?, x?, y?)
+    }
+
+    fn relative_path(&self) -> PathBuf {
+        Path::new(&self.layer)
+            .join(self.z.to_string())
+            .join(self.x.to_string())
+            .join(self.y.to_string())
+    }
+}
+
+pub(crate) struct CachedTile {
+    pub data: Vec<u8>,
+    pub fresh: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TileCacheStats {
+    pub dir: String,
+    pub tiles: usize,
+    pub total_bytes: u64,
+    pub max_bytes: u64,
+}
+
+pub(crate) struct TileCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    index: Mutex<LruIndex<TileKey>>,
+}
+
+impl TileCache {
+    pub(crate) fn open(dir: PathBuf, max_bytes: u64, max_age: Duration) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let cache = TileCache {
+            dir,
+            max_bytes,
+            max_age,
+            index: Mutex::new(LruIndex::default()),
+        };
+        cache.load_index()?;
+        Ok(cache)
+    }
+
+    // 启动时扫描缓存目录, 按修改时间恢复 LRU 顺序
+    fn load_index(&self) -> io::Result<()> {
+        let mut found = Vec::new();
+        for layer in read_dirs(&self.dir)? {
+            for z in read_dirs(&layer)? {
+                for x in read_dirs(&z)? {
+                    for y in fs::read_dir(&x)?.flatten() {
+                        let path = y.path();
+                        // 上次退出时没写完的临时文件, 直接删掉
+                        if path.extension().is_some_and(|ext| ext == "tmp") {
+                            let _ = fs::remove_file(&path);
+                            continue;
+                        }
+                        let key = match tile_key_from_path(&path) {
+                            Some(key) => key,
+                            None => continue,
+                        };
+                        if let Ok(meta) = y.metadata() {
+                            if meta.is_file() {
+                                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                                found.push((modified, key, meta.len()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|entry| entry.0);
+        let mut index = self.index.lock().unwrap();
+        for (_, key, size) in found {
+            index.insert(key, size);
+        }
+        drop(index);
+        self.evict();
+        Ok(())
+    }
+
+    fn path_of(&self, key: &TileKey) -> PathBuf {
+        self.dir.join(key.relative_path())
+    }
+
+    pub(crate) fn contains(&self, key: &TileKey) -> bool {
+        self.index.lock().unwrap().contains(key)
+    }
+
+    pub(crate) async fn get(&self, key: &TileKey) -> Option<CachedTile> {
+        if !self.contains(key) {
+            return None;
+        }
+        let path = self.path_of(key);
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(_) => {
+                // 文件已被外部删除, 同步索引
+                self.index.lock().unwrap().remove(key);
+                return None;
+            }
+        };
+        let fresh = tokio::fs::metadata(&path)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map(|age| age <= self.max_age)
+            .unwrap_or(false);
+        self.index.lock().unwrap().touch(key);
+        Some(CachedTile { data, fresh })
+    }
+
+    // 同步读取, 供导出等阻塞任务使用
+    pub(crate) fn read_blocking(&self, key: &TileKey) -> Option<Vec<u8>> {
+        if !self.contains(key) {
+            return None;
+        }
+        fs::read(self.path_of(key)).ok()
+    }
+
+    pub(crate) fn keys(&self) -> Vec<TileKey> {
+        self.index.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub(crate) async fn put(&self, key: &TileKey, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() || data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let path = self.path_of(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // 先写临时文件再重命名, 避免读到写了一半的瓦片
+        let tmp = temp_path(&path);
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        self.record(key, data.len() as u64);
+        Ok(())
+    }
+
+    pub(crate) fn put_blocking(&self, key: &TileKey, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() || data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let path = self.path_of(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = temp_path(&path);
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        self.record(key, data.len() as u64);
+        Ok(())
+    }
+
+    fn record(&self, key: &TileKey, size: u64) {
+        self.index.lock().unwrap().insert(key.clone(), size);
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let mut evicted = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            while index.total_bytes() > self.max_bytes {
+                match index.pop_oldest() {
+                    Some(key) => evicted.push(key),
+                    None => break,
+                }
+            }
+        }
+        for key in evicted {
+            let _ = fs::remove_file(self.path_of(&key));
+        }
+    }
+
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        *index = LruIndex::default();
+        for layer in read_dirs(&self.dir)? {
+            fs::remove_dir_all(layer)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> TileCacheStats {
+        let index = self.index.lock().unwrap();
+        TileCacheStats {
+            dir: self.dir.to_string_lossy().into_owned(),
+            tiles: index.len(),
+            total_bytes: index.total_bytes(),
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!(
+        "{}.tmp",
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn read_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect())
+}
+
+fn tile_key_from_path(path: &Path) -> Option<TileKey> {
+    let mut parts = path.iter().rev().map(|p| p.to_str());
+    let y = parts.next()??.parse().ok()?;
+    let x = parts.next()??.parse().ok()?;
+    let z = parts.next()??.parse().ok()?;
+    let layer = parts.next()??;
+    TileKey::new(layer, z, x, y)
+}
+
+// 根据文件头判断瓦片格式
+pub(crate) fn sniff_content_type(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if data.starts_with(b"\xFF\xD8\xFF") {
+        "image/jpeg"
+    } else if data.len() > 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+pub(crate) fn init(cache_dir: PathBuf, offline_dir: PathBuf) -> io::Result<()> {
+    let cache = TileCache::open(cache_dir, DEFAULT_MAX_BYTES, DEFAULT_MAX_AGE)?;
+    let _ = TILE_CACHE.set(cache);
+    let store = TileCache::open(offline_dir, u64::MAX, Duration::MAX)?;
+    let _ = OFFLINE_STORE.set(store);
+    Ok(())
+}
+
+pub(crate) fn tile_cache() -> Option<&'static TileCache> {
+    TILE_CACHE.get()
+}
+
+pub(crate) fn offline_store() -> Option<&'static TileCache> {
+    OFFLINE_STORE.get()
+}
+
+// 先查离线瓦片, 再查缓存
+pub(crate) async fn lookup(key: &TileKey) -> Option<CachedTile> {
+    if let Some(store) = offline_store() {
+        if let Some(tile) = store.get(key).await {
+            return Some(tile);
+        }
+    }
+    tile_cache()?.get(key).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::test_support::temp_dir;
+
+    fn open(name: &str, max_bytes: u64) -> TileCache {
+        let dir = temp_dir(name);
+        let _ = fs::remove_dir_all(&dir);
+        TileCache::open(dir, max_bytes, DEFAULT_MAX_AGE).unwrap()
+    }
+
+    fn key(y: u32) -> TileKey {
+        TileKey::new("vec_w", 3, 1, y).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tiles_round_trip() {
+        let cache = open("tile-round-trip", 1024);
+        assert!(cache.get(&key(1)).await.is_none());
+        cache.put(&key(1), b"tile").await.unwrap();
+        let tile = cache.get(&key(1)).await.unwrap();
+        assert_eq!(tile.data, b"tile");
+        assert!(tile.fresh);
+        assert_eq!(cache.read_blocking(&key(1)).unwrap(), b"tile");
+
+        // 重新打开后从目录恢复索引
+        let reopened = TileCache::open(temp_dir("tile-round-trip"), 1024, DEFAULT_MAX_AGE).unwrap();
+        assert_eq!(reopened.keys(), vec![key(1)]);
+        assert_eq!(reopened.stats().total_bytes, 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn least_recently_used_tiles_are_evicted() {
+        let cache = open("tile-eviction", 10);
+        cache.put(&key(1), b"aaaa").await.unwrap();
+        cache.put(&key(2), b"bbbb").await.unwrap();
+        // 读取后 1 变为最近使用, 超出上限时淘汰 2
+        assert!(cache.get(&key(1)).await.is_some());
+        cache.put(&key(3), b"cccc").await.unwrap();
+        assert!(cache.contains(&key(1)));
+        assert!(!cache.contains(&key(2)));
+        assert!(cache.contains(&key(3)));
+        assert!(!cache.path_of(&key(2)).exists());
+        assert_eq!(cache.stats().total_bytes, 8);
+
+        // 超过上限的单个瓦片不缓存
+        cache.put(&key(4), b"too large tile").await.unwrap();
+        assert!(!cache.contains(&key(4)));
+    }
+
+    #[test]
+    fn leftover_temp_files_are_removed() {
+        let cache = open("tile-leftovers", 1024);
+        cache.put_blocking(&key(1), b"tile").unwrap();
+        let tmp = temp_path(&cache.path_of(&key(2)));
+        fs::write(&tmp, b"half").unwrap();
+
+        let reopened = TileCache::open(temp_dir("tile-leftovers"), 1024, DEFAULT_MAX_AGE).unwrap();
+        assert!(!tmp.exists());
+        assert_eq!(reopened.keys(), vec![key(1)]);
+        assert_eq!(reopened.stats().total_bytes, 4);
+    }
+}
@@ -0,0 +1,359 @@
+use super::client;
+use super::coalesce::SharedResponse;
+use super::config::ProxyConfig;
+use super::policy;
+use super::redirect::RedirectMode;
+use super::replay::{self, ReplayMode};
+use super::retry;
+use super::tile_cache::{self, TileKey};
+use super::traffic;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// 响应头, 实际提供瓦片的主机
+pub(crate) const PROVIDER_HEADER: &str = "x-tile-provider";
+const TDT_UPSTREAM: &str = "tdt-tile";
+const TDT_TEMPLATE: &str =
+    "https://t{s}.tianditu.gov.cn/DataServer?T={layer}&x={x}&y={y}&l={z}&tk={key}";
+
+/// 瓦片提供方, `url` 中的 `{z}` `{x}` `{y}` 替换为行列号, `{s}` 按行列在 `subdomains` 中轮换,
+/// `{key}` 替换为 `upstream` 指定的命名上游的 key
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct TileProvider {
+    pub url: String,
+    pub subdomains: Vec<String>,
+    // 同时决定使用的出站代理和重试策略
+    pub upstream: Option<String>,
+    pub attribution: String,
+}
+
+/// 瓦片图层, 对应配置文件中的 `tileLayers`, 前端通过 `/tiles/{layer}/{z}/{x}/{y}` 访问
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct TileLayer {
+    pub name: String,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    // 依次尝试, 第一个为主提供方, 其余为出错时的备用
+    pub providers: Vec<TileProvider>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TileLayerInfo {
+    pub id: String,
+    pub name: String,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    // 瓦片地址模板, 可直接交给地图库
+    pub url: String,
+    // 按提供方顺序, 去掉重复项
+    pub attributions: Vec<String>,
+}
+
+pub(crate) struct FetchedTile {
+    pub data: Vec<u8>,
+    // 提供方在列表中的位置, 0 为主提供方
+    pub provider: usize,
+    pub host: String,
+    // 跳转后的最终地址
+    pub url: Url,
+}
+
+impl FetchedTile {
+    /// 分发给同时请求该瓦片的等待者
+    pub(crate) fn to_shared(&self) -> SharedResponse {
+        let mut headers = HeaderMap::new();
+        if let Ok(value) = HeaderValue::from_str(&self.host) {
+            headers.insert(PROVIDER_HEADER, value);
+        }
+        SharedResponse {
+            status: StatusCode::OK,
+            headers,
+            url: self.url.clone(),
+            body: self.data.clone().into(),
+        }
+    }
+}
+
+impl TileLayer {
+    pub(crate) fn defaults() -> BTreeMap<String, TileLayer> {
+        let tdt = |layer: &str, name: &str| TileLayer {
+            name: name.to_string(),
+            min_zoom: 1,
+            max_zoom: 18,
+            providers: vec![TileProvider {
+                url: TDT_TEMPLATE.replace("{layer}", layer),
+                subdomains: (0..8).map(|i| i.to_string()).collect(),
+                upstream: Some(TDT_UPSTREAM.to_string()),
+                attribution: "© 天地图".to_string(),
+            }],
+        };
+        BTreeMap::from([
+            ("vec_w".to_string(), tdt("vec_w", "矢量底图")),
+            ("cva_w".to_string(), tdt("cva_w", "矢量注记")),
+            ("img_w".to_string(), tdt("img_w", "影像底图")),
+            ("cia_w".to_string(), tdt("cia_w", "影像注记")),
+        ])
+    }
+
+    pub(crate) fn info(&self, id: &str, proxy_base: &str) -> TileLayerInfo {
+        let mut attributions: Vec<String> = Vec::new();
+        for provider in &self.providers {
+            if !provider.attribution.is_empty() && !attributions.contains(&provider.attribution) {
+                attributions.push(provider.attribution.clone());
+            }
+        }
+        TileLayerInfo {
+            id: id.to_string(),
+            name: self.name.clone(),
+            min_zoom: self.min_zoom,
+            max_zoom: self.max_zoom,
+            url: format!("{}/tiles/{}/{{z}}/{{x}}/{{y}}", proxy_base, id),
+            attributions,
+        }
+    }
+
+    pub(crate) fn has_zoom(&self, z: u32) -> bool {
+        (self.min_zoom..=self.max_zoom).contains(&z)
+    }
+
+    /// 主提供方的瓦片地址, 缓存命中时以此记录流量
+    pub(crate) fn primary_url(&self, config: &ProxyConfig, key: &TileKey) -> Option<String> {
+        self.providers.first()?.tile_url(config, key).ok()
+    }
+}
+
+impl TileProvider {
+    // 同一瓦片总是落在同一子域名上, 便于复用缓存
+    fn subdomain(&self, key: &TileKey) -> &str {
+        match self.subdomains.len() {
+            0 => "",
+            len => &self.subdomains[(key.x as usize + key.y as usize) % len],
+        }
+    }
+
+    fn tile_url(&self, config: &ProxyConfig, key: &TileKey) -> Result<String, String> {
+        let mut url = self
+            .url
+            .replace("{s}", self.subdomain(key))
+            .replace("{z}", &key.z.to_string())
+            .replace("{x}", &key.x.to_string())
+            .replace("{y}", &key.y.to_string());
+        if url.contains("{key}") {
+            let name = self
+                .upstream
+                .as_deref()
+                .ok_or("No upstream for the tile provider key")?;
+            let token = config
+                .upstreams
+                .get(name)
+                .and_then(|profile| profile.key.as_deref())
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| {
+                    format!(
+                        "Upstream {} has no key, set upstreams.{}.key in proxy.json",
+                        name, name
+                    )
+                })?;
+            url = url.replace("{key}", &urlencoding::encode(token));
+        }
+        Ok(url)
+    }
+
+    fn host(&self, subdomain: &str) -> Option<String> {
+        let url = self.url.replace("{s}", subdomain).replace(['{', '}'], "");
+        Url::parse(&url).ok()?.host_str().map(str::to_string)
+    }
+
+    /// 模板展开后可能访问的主机
+    pub(crate) fn hosts(&self) -> Vec<String> {
+        if self.subdomains.is_empty() {
+            return self.host("").into_iter().collect();
+        }
+        self.subdomains
+            .iter()
+            .filter_map(|subdomain| self.host(subdomain))
+            .collect()
+    }
+
+    async fn fetch(&self, config: &ProxyConfig, key: &TileKey) -> Result<(Url, Vec<u8>), String> {
+        let url = self.tile_url(config, key)?;
+        let parsed = Url::parse(&url).map_err(|e| e.to_string())?;
+        policy::check(config, &parsed).map_err(|violation| violation.to_string())?;
+
+        let mut exchange = traffic::begin(Method::GET.as_str(), &url, None);
+        // 录制回放与代理请求使用同一套夹具
+        let fixture = replay::fixture(&config.replay, &Method::GET, &url, &HeaderMap::new());
+        let response = match (&fixture, config.replay.mode) {
+            (Some(fixture), ReplayMode::Replay) => fixture
+                .load()
+                .await
+                .ok_or("Fixture not found")?
+                .to_response(),
+            // 没有夹具目录时同样不访问网络
+            (None, ReplayMode::Replay) => return Err("Fixture not found".to_string()),
+            _ => {
+                let client = match &self.upstream {
+                    Some(name) => client::for_upstream(name)?,
+                    None => client::shared()?,
+                };
+                let profile = self
+                    .upstream
+                    .as_ref()
+                    .and_then(|name| config.upstreams.get(name));
+                let retry_policy = profile
+                    .and_then(|profile| profile.retry.as_ref())
+                    .unwrap_or(&config.retry);
+                let request = client.get(parsed).build().map_err(|e| e.to_string())?;
+                if let Some(exchange) = exchange.as_mut() {
+                    exchange.request_headers(request.headers());
+                }
+                // 瓦片没有可改写的跳转地址, 只在跟随模式下跳转
+                let redirect_mode = profile
+                    .and_then(|profile| profile.redirect)
+                    .unwrap_or(config.redirect.mode);
+                let max_hops = match redirect_mode {
+                    RedirectMode::Follow => config.redirect.max_hops,
+                    _ => 0,
+                };
+                let response =
+                    retry::execute(&client, config, request, max_hops, retry_policy, profile)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                match &fixture {
+                    Some(fixture) => fixture.record(response).await,
+                    None => response,
+                }
+            }
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        let url = response.url().clone();
+        let data = if status.is_success() {
+            response.bytes().await.map_err(|e| e.to_string())?
+        } else {
+            Default::default()
+        };
+        if let Some(exchange) = exchange {
+            exchange.complete(status.as_u16(), &headers, data.len() as u64);
+        }
+        if !status.is_success() {
+            return Err(format!("HTTP {}", status));
+        }
+        // 天地图在 key 无效或超出配额时会返回 XML 错误信息
+        if !tile_cache::sniff_content_type(&data).starts_with("image/") {
+            return Err("Response is not an image".to_string());
+        }
+        Ok((url, data.to_vec()))
+    }
+}
+
+/// 依次尝试各提供方, 全部失败时返回每个提供方的错误
+pub(crate) async fn fetch(
+    config: &ProxyConfig,
+    layer: &TileLayer,
+    key: &TileKey,
+) -> Result<FetchedTile, Vec<String>> {
+    let mut errors = Vec::new();
+    for (index, provider) in layer.providers.iter().enumerate() {
+        let host = provider.host(provider.subdomain(key)).unwrap_or_default();
+        match provider.fetch(config, key).await {
+            Ok((url, data)) => {
+                return Ok(FetchedTile {
+                    data,
+                    provider: index,
+                    host,
+                    url,
+                })
+            }
+            Err(e) => {
+                log::debug!("tile {:?} from {} failed: {}", key, host, e);
+                errors.push(format!("{}: {}", host, e));
+            }
+        }
+    }
+    Err(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::config;
+    use crate::proxy_plugin::test_support::{serve, setup};
+    use warp::Filter;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn response(
+        status: u16,
+        location: Option<String>,
+        body: &[u8],
+    ) -> warp::http::Response<Vec<u8>> {
+        let mut builder = warp::http::Response::builder().status(status);
+        if let Some(location) = location {
+            builder = builder.header("location", location);
+        }
+        builder.body(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn provider_fetch_honours_redirect_mode() {
+        // 跳转后的另一个源, 收到 key 时拒绝
+        let other = serve(
+            warp::path("tile")
+                .and(warp::query::raw())
+                .map(|query: String| match query.contains("secret") {
+                    true => response(403, None, b""),
+                    false => response(200, None, PNG),
+                }),
+        );
+        let port = serve(
+            warp::path("tile")
+                .and(warp::query::raw())
+                .map(move |query: String| {
+                    let location = format!("http://localhost:{}/tile?{}", other, query);
+                    response(302, Some(location), b"")
+                }),
+        );
+        let layer = TileLayer {
+            name: "test".to_string(),
+            min_zoom: 0,
+            max_zoom: 18,
+            providers: vec![TileProvider {
+                url: format!(
+                    "http://127.0.0.1:{}/tile?x={{x}}&y={{y}}&l={{z}}&tk={{key}}",
+                    port
+                ),
+                subdomains: Vec::new(),
+                upstream: Some("tiles".to_string()),
+                attribution: String::new(),
+            }],
+        };
+        let key = TileKey::new("test", 3, 1, 2).unwrap();
+        let _guard = setup(serde_json::json!({
+            "upstreams": {
+                "tiles": { "baseUrl": format!("http://127.0.0.1:{}", port), "key": "secret" }
+            }
+        }))
+        .await;
+
+        // 默认不跟随跳转
+        let errors = fetch(&config::current(), &layer, &key).await.err().unwrap();
+        assert!(errors[0].ends_with("HTTP 302 Found"), "{:?}", errors);
+
+        config::update(|config| config.redirect.mode = RedirectMode::Follow).unwrap();
+        let tile = fetch(&config::current(), &layer, &key).await.ok().unwrap();
+        assert_eq!(tile.data, PNG);
+
+        // 命名上游的设置优先于全局设置
+        config::update(|config| {
+            config.upstreams.get_mut("tiles").unwrap().redirect = Some(RedirectMode::Manual);
+        })
+        .unwrap();
+        assert!(fetch(&config::current(), &layer, &key).await.is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, SubjectPublicKeyInfoDer, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// 证书校验策略, 默认校验所有证书
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct TlsPolicy {
+    // 允许不校验证书的主机, 支持 `*.example.com`
+    pub insecure_hosts: Vec<String>,
+    // 额外信任的 CA 证书 (PEM)
+    pub ca_files: Vec<String>,
+    // 证书锁定, 主机 -> `sha256/<base64>` 形式的 SPKI 摘要
+    pub pins: BTreeMap<String, Vec<String>>,
+}
+
+/// 主机匹配, 支持精确匹配和 `*.` 前缀通配
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            host.len() > suffix.len()
+                && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+        }
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+#[derive(Debug)]
+struct PolicyVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    provider: Arc<CryptoProvider>,
+    insecure_hosts: Vec<String>,
+    pins: Vec<(String, Vec<[u8; 32]>)>,
+}
+
+impl ServerCertVerifier for PolicyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => IpAddr::from(*ip).to_string(),
+            _ => String::new(),
+        };
+        let insecure = self
+            .insecure_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &host));
+        if !insecure {
+            self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        // 锁定的主机即使在例外列表中也要校验摘要
+        for (pattern, pins) in &self.pins {
+            if host_matches(pattern, &host) {
+                let digest = spki_of(end_entity)
+                    .map(|spki| <[u8; 32]>::from(Sha256::digest(spki.as_ref())))
+                    .ok_or(rustls::Error::InvalidCertificate(
+                        CertificateError::BadEncoding,
+                    ))?;
+                if !pins.contains(&digest) {
+                    return Err(rustls::Error::InvalidCertificate(
+                        CertificateError::ApplicationVerificationFailure,
+                    ));
+                }
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// 从 X.509 证书中取出 SubjectPublicKeyInfo, 证书无法解析时返回 None
+fn spki_of(cert: &CertificateDer<'_>) -> Option<SubjectPublicKeyInfoDer<'static>> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(cert.subject_public_key_info())
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    let encoded = pin
+        .strip_prefix("sha256/")
+        .ok_or_else(|| format!("Unsupported pin format: {}", pin))?;
+    BASE64
+        .decode(encoded)
+        .ok()
+        .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+        .ok_or_else(|| format!("Invalid pin: {}", pin))
+}
+
+fn verifier(policy: &TlsPolicy, provider: Arc<CryptoProvider>) -> Result<PolicyVerifier, String> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for path in &policy.ca_files {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            let cert = cert.map_err(|e| format!("{}: {}", path, e))?;
+            roots.add(cert).map_err(|e| format!("{}: {}", path, e))?;
+        }
+    }
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut pins = Vec::new();
+    for (host, host_pins) in &policy.pins {
+        let digests = host_pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<_>, _>>()?;
+        pins.push((host.clone(), digests));
+    }
+    Ok(PolicyVerifier {
+        webpki,
+        provider,
+        insecure_hosts: policy.insecure_hosts.clone(),
+        pins,
+    })
+}
+
+/// 按策略生成 rustls 配置, 供共享客户端使用
+pub(crate) fn client_config(policy: &TlsPolicy) -> Result<ClientConfig, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = verifier(policy, provider.clone())?;
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// 证书校验失败时返回失败原因
+pub(crate) fn verification_error(err: &(dyn std::error::Error + 'static)) -> Option<String> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(rustls::Error::InvalidCertificate(e)) = err.downcast_ref::<rustls::Error>() {
+            return Some(match e {
+                CertificateError::ApplicationVerificationFailure => {
+                    "certificate does not match pinned public key".to_string()
+                }
+                e => format!("{:?}", e),
+            });
+        }
+        // io::Error 的 source 会跳过内部错误本身
+        if let Some(inner) = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            if let Some(reason) = verification_error(inner) {
+                return Some(reason);
+            }
+        }
+        source = err.source();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::test_support::setup;
+    use crate::proxy_plugin::{commands, config};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // 测试用的 CA 和由它签发的 localhost 证书, 有效期一百年
+    const CA_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/proxy_plugin/fixtures/tls/ca.pem"
+    );
+    const CERT: &str = include_str!("fixtures/tls/localhost.pem");
+    const KEY: &str = include_str!("fixtures/tls/localhost.key");
+    // openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    const PIN: &str = "sha256/id0fALBwvkK4GP323sSveTHlujvAxfK8a8s54TDQfg0=";
+    const WRONG_PIN: &str = "sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    fn cert() -> CertificateDer<'static> {
+        rustls_pemfile::certs(&mut CERT.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn verify(
+        policy: &TlsPolicy,
+        cert: &CertificateDer<'_>,
+        host: &str,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let name = ServerName::try_from(host.to_string()).unwrap();
+        verifier(policy, provider).unwrap().verify_server_cert(
+            cert,
+            &[],
+            &name,
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    fn pinned(insecure: bool, pin: &str) -> TlsPolicy {
+        TlsPolicy {
+            insecure_hosts: match insecure {
+                true => vec!["localhost".to_string()],
+                false => Vec::new(),
+            },
+            ca_files: match insecure {
+                true => Vec::new(),
+                false => vec![CA_FILE.to_string()],
+            },
+            pins: BTreeMap::from([("localhost".to_string(), vec![pin.to_string()])]),
+        }
+    }
+
+    #[test]
+    fn spki_digest_matches_openssl() {
+        let spki = spki_of(&cert()).unwrap();
+        let digest = BASE64.encode(Sha256::digest(spki.as_ref()));
+        assert_eq!(format!("sha256/{}", digest), PIN);
+        assert_eq!(
+            parse_pin(PIN).unwrap().to_vec(),
+            Sha256::digest(spki.as_ref()).to_vec()
+        );
+        assert!(parse_pin("sha1/AAAA").is_err());
+        assert!(parse_pin("sha256/AAAA").is_err());
+    }
+
+    #[test]
+    fn malformed_certificates_are_errors() {
+        let der = cert();
+        for len in [0, 1, 2, 4, 10, der.len() / 2, der.len() - 1] {
+            let truncated = CertificateDer::from(der[..len].to_vec());
+            assert!(spki_of(&truncated).is_none(), "{}", len);
+        }
+        let garbage: [&[u8]; 4] = [
+            &[0xff; 64],
+            // 长度字段超出数据
+            &[0x30, 0x84, 0xff, 0xff, 0xff, 0xff],
+            &[0x30, 0x80],
+            &[0x30, 0x03, 0x02, 0x01, 0x01],
+        ];
+        for data in garbage {
+            let cert = CertificateDer::from(data.to_vec());
+            assert!(spki_of(&cert).is_none(), "{:?}", data);
+            // 例外主机跳过证书链校验, 锁定的摘要无法计算时同样拒绝
+            assert_eq!(
+                verify(&pinned(true, PIN), &cert, "localhost").unwrap_err(),
+                rustls::Error::InvalidCertificate(CertificateError::BadEncoding)
+            );
+        }
+    }
+
+    #[test]
+    fn pins_and_insecure_hosts() {
+        let cert = cert();
+        // 测试 CA 默认不受信任
+        assert!(verify(&TlsPolicy::default(), &cert, "localhost").is_err());
+        let trusted = TlsPolicy {
+            ca_files: vec![CA_FILE.to_string()],
+            ..Default::default()
+        };
+        assert!(verify(&trusted, &cert, "localhost").is_ok());
+        assert!(verify(&trusted, &cert, "example.com").is_err());
+
+        // 例外只对列出的主机生效
+        let insecure = TlsPolicy {
+            insecure_hosts: vec!["localhost".to_string(), "*.example.com".to_string()],
+            ..Default::default()
+        };
+        assert!(verify(&insecure, &cert, "localhost").is_ok());
+        assert!(verify(&insecure, &cert, "api.example.com").is_ok());
+        assert!(verify(&insecure, &cert, "example.com").is_err());
+        assert!(verify(&insecure, &cert, "localhost.example.org").is_err());
+
+        let mismatch =
+            rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure);
+        for insecure in [true, false] {
+            assert!(verify(&pinned(insecure, PIN), &cert, "localhost").is_ok());
+            assert_eq!(
+                verify(&pinned(insecure, WRONG_PIN), &cert, "localhost").unwrap_err(),
+                mismatch
+            );
+        }
+        // 其他主机的锁定不影响当前主机
+        let mut other = pinned(false, WRONG_PIN);
+        other.pins = BTreeMap::from([("example.com".to_string(), vec![WRONG_PIN.to_string()])]);
+        assert!(verify(&other, &cert, "localhost").is_ok());
+    }
+
+    // 只返回固定响应的 HTTPS 上游
+    async fn tls_upstream() -> u16 {
+        let key = rustls_pemfile::private_key(&mut KEY.as_bytes())
+            .unwrap()
+            .unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert()], key)
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response =
+                        "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pin_mismatch_is_a_structured_502() {
+        let _guard = setup(serde_json::json!({
+            "tls": {"caFiles": [CA_FILE], "pins": {"localhost": [WRONG_PIN]}}
+        }))
+        .await;
+        let port = tls_upstream().await;
+        let target = format!("https://localhost:{}/", port);
+        let url = commands::get_proxy_url(&target, None).unwrap();
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 502);
+        let body = response.text().await.unwrap();
+        assert!(
+            body.contains("\"error\":\"tls_verification_failed\""),
+            "{}",
+            body
+        );
+        assert!(body.contains("\"host\":\"localhost\""), "{}", body);
+        assert!(
+            body.contains("does not match pinned public key"),
+            "{}",
+            body
+        );
+
+        config::update(|config| config.tls = pinned(false, PIN)).unwrap();
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+}
@@ -0,0 +1,608 @@
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use warp::hyper::body::{Body, Bytes, HttpBody};
+
+const REDACTED: &str = "[REDACTED]";
+// 名称包含这些字样的请求头视为密钥
+const SECRET_MARKERS: [&str; 6] = ["auth", "cookie", "token", "secret", "key", "password"];
+// 这些查询参数视为密钥, 参数名按全名匹配, 避免误伤 keyword 之类的参数
+const SECRET_PARAMS: [&str; 11] = [
+    "tk",
+    "key",
+    "ak",
+    "sk",
+    "apikey",
+    "api_key",
+    "token",
+    "access_token",
+    "sig",
+    "signature",
+    "password",
+];
+
+// 值为地址的响应头, 查询参数中的密钥同样替换
+const URL_HEADERS: [&str; 3] = ["location", "content-location", "referer"];
+
+static ENTRIES: Lazy<Mutex<VecDeque<Arc<Mutex<TrafficEntry>>>>> = Lazy::new(Default::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 流量记录设置, 对应配置文件中的 `trafficLog`, 默认关闭
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct TrafficLogSettings {
+    pub enabled: bool,
+    // 最多保留的记录数, 超出后丢弃最早的记录
+    pub capacity: usize,
+}
+
+impl Default for TrafficLogSettings {
+    fn default() -> Self {
+        TrafficLogSettings {
+            enabled: false,
+            capacity: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TrafficEntry {
+    pub id: u64,
+    // 毫秒时间戳
+    pub started_at: u64,
+    pub method: String,
+    pub url: String,
+    // 实际发送给上游的请求头, 由缓存直接返回时为空
+    pub request_headers: Vec<(String, String)>,
+    pub request_size: Option<u64>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_size: u64,
+    // HIT / MISS / STALE / REVALIDATED
+    pub cache: Option<String>,
+    // 收到响应头的耗时
+    pub wait_ms: f64,
+    // 读取响应体的耗时
+    pub receive_ms: f64,
+    pub completed: bool,
+}
+
+/// 单次请求的记录
+pub(crate) struct Exchange {
+    entry: TrafficEntry,
+    started: Instant,
+}
+
+/// 未开启记录时返回 None
+pub(crate) fn begin(method: &str, url: &str, request_size: Option<u64>) -> Option<Exchange> {
+    if !super::config::current().traffic_log.enabled {
+        return None;
+    }
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    Some(Exchange {
+        entry: TrafficEntry {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            started_at,
+            method: method.to_string(),
+            url: redact_url(url),
+            request_headers: Vec::new(),
+            request_size,
+            status: 0,
+            response_headers: Vec::new(),
+            response_size: 0,
+            cache: None,
+            wait_ms: 0.0,
+            receive_ms: 0.0,
+            completed: false,
+        },
+        started: Instant::now(),
+    })
+}
+
+impl Exchange {
+    pub(crate) fn request_headers(&mut self, headers: &reqwest::header::HeaderMap) {
+        self.entry.request_headers = headers
+            .iter()
+            .map(|(name, value)| redact_header(name.as_str(), value.as_bytes()))
+            .collect();
+    }
+
+    /// 记录响应头并加入记录, 响应体读完后补充大小和耗时
+    pub(crate) fn finish(mut self, reply: warp::reply::Response) -> warp::reply::Response {
+        let (parts, body) = reply.into_parts();
+        self.entry.status = parts.status.as_u16();
+        self.entry.response_headers = parts
+            .headers
+            .iter()
+            .map(|(name, value)| redact_header(name.as_str(), value.as_bytes()))
+            .collect();
+        self.entry.cache = parts
+            .headers
+            .get(super::tile_cache::CACHE_STATUS_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        self.entry.wait_ms = elapsed_ms(self.started);
+        let expected = parts
+            .headers
+            .get(warp::http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+
+        let entry = Arc::new(Mutex::new(self.entry));
+        push(entry.clone());
+        let body = Body::wrap_stream(CountingBody {
+            inner: body,
+            entry,
+            received: Instant::now(),
+            bytes: 0,
+            expected,
+            completed: false,
+        });
+        warp::reply::Response::from_parts(parts, body)
+    }
+
+    /// 响应体已完整读取时直接加入记录, 用于瓦片等不经过代理响应的请求
+    pub(crate) fn complete(mut self, status: u16, headers: &reqwest::header::HeaderMap, size: u64) {
+        self.entry.status = status;
+        self.entry.response_headers = headers
+            .iter()
+            .map(|(name, value)| redact_header(name.as_str(), value.as_bytes()))
+            .collect();
+        self.entry.wait_ms = elapsed_ms(self.started);
+        self.entry.response_size = size;
+        self.entry.completed = true;
+        push(Arc::new(Mutex::new(self.entry)));
+    }
+}
+
+fn push(entry: Arc<Mutex<TrafficEntry>>) {
+    let capacity = super::config::current().traffic_log.capacity.max(1);
+    let mut entries = ENTRIES.lock().unwrap();
+    entries.push_back(entry);
+    while entries.len() > capacity {
+        entries.pop_front();
+    }
+}
+
+fn elapsed_ms(since: Instant) -> f64 {
+    round_ms(since.elapsed().as_secs_f64() * 1000.0)
+}
+
+// 毫秒保留三位小数
+fn round_ms(ms: f64) -> f64 {
+    (ms * 1000.0).round() / 1000.0
+}
+
+// 统计响应体大小, 响应结束或客户端断开时写回记录
+struct CountingBody {
+    inner: Body,
+    entry: Arc<Mutex<TrafficEntry>>,
+    received: Instant,
+    bytes: u64,
+    // 有 Content-Length 时 hyper 读够长度后不再读取流的结尾
+    expected: Option<u64>,
+    completed: bool,
+}
+
+impl Stream for CountingBody {
+    type Item = Result<Bytes, warp::hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => self.bytes += chunk.len() as u64,
+            Poll::Ready(None) => self.completed = true,
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.response_size = self.bytes;
+        entry.receive_ms = elapsed_ms(self.received);
+        entry.completed = self.completed || self.expected == Some(self.bytes);
+    }
+}
+
+/// 名称像是密钥或凭据的请求头和响应头
+pub(crate) fn is_secret_header(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    SECRET_MARKERS.iter().any(|marker| lower.contains(marker))
+}
+
+fn redact_header(name: &str, value: &[u8]) -> (String, String) {
+    let value = if is_secret_header(name) {
+        REDACTED.to_string()
+    } else if URL_HEADERS
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+    {
+        redact_location(&String::from_utf8_lossy(value))
+    } else {
+        String::from_utf8_lossy(value).into_owned()
+    };
+    (name.to_string(), value)
+}
+
+/// 查询参数中的密钥替换为占位符
+pub(crate) fn redact_url(url: &str) -> String {
+    redact_url_params(url, &[])
+}
+
+// 跳转地址可以是相对地址, 借助占位的基地址处理查询参数
+fn redact_location(location: &str) -> String {
+    if Url::parse(location).is_ok() {
+        return redact_url(location);
+    }
+    let (rest, fragment) = match location.split_once('#') {
+        Some((rest, fragment)) => (rest, format!("#{}", fragment)),
+        None => (location, String::new()),
+    };
+    let (path, query) = match rest.split_once('?') {
+        Some(parts) => parts,
+        None => return location.to_string(),
+    };
+    let redacted = redact_url(&format!("http://relative.invalid/?{}", query));
+    let query = Url::parse(&redacted)
+        .ok()
+        .and_then(|url| url.query().map(str::to_string))
+        .unwrap_or_default();
+    format!("{}?{}{}", path, query, fragment)
+}
+
+/// 查询参数中的密钥和 `extra` 列出的参数替换为占位符,
+/// 代理地址路径中编码的目标地址同样处理
+pub(crate) fn redact_url_params(url: &str, extra: &[String]) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    let nested = parsed.path_segments().is_some_and(|mut segments| {
+        segments.any(|segment| {
+            urlencoding::decode(segment).is_ok_and(|decoded| decoded.contains("://"))
+        })
+    });
+    if nested {
+        let mut path: Vec<String> = parsed
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .map(str::to_string)
+            .collect();
+        for index in 0..path.len() {
+            let target = match urlencoding::decode(&path[index]) {
+                Ok(decoded) if decoded.contains("://") => redact_url_params(&decoded, extra),
+                _ => continue,
+            };
+            path[index] = urlencoding::encode(&target).into_owned();
+            // `/proxy/{headers}/{url}` 的请求头段可能带有凭据
+            if index >= 2 && path[index - 2] == "proxy" && path[index - 1] != "_" {
+                path[index - 1] = urlencoding::encode(REDACTED).into_owned();
+            }
+        }
+        parsed.set_path(&path.join("/"));
+    }
+    if parsed.query().is_none() {
+        return match nested {
+            true => parsed.to_string(),
+            false => url.to_string(),
+        };
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(name, value)| {
+            let secret = SECRET_PARAMS
+                .iter()
+                .any(|param| name.eq_ignore_ascii_case(param))
+                || extra.iter().any(|param| name.eq_ignore_ascii_case(param));
+            let value = if secret {
+                REDACTED.to_string()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+/// 最近的记录, 按时间倒序
+pub(crate) fn entries(limit: Option<usize>) -> Vec<TrafficEntry> {
+    let entries = ENTRIES.lock().unwrap();
+    entries
+        .iter()
+        .rev()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|entry| entry.lock().unwrap().clone())
+        .collect()
+}
+
+pub(crate) fn clear() {
+    ENTRIES.lock().unwrap().clear();
+}
+
+// 毫秒时间戳转为 ISO 8601 (UTC)
+fn iso8601(millis: u64) -> String {
+    let secs = millis / 1000;
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // 按公历由天数推算年月日
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        millis % 1000
+    )
+}
+
+fn har_headers(headers: &[(String, String)]) -> Vec<serde_json::Value> {
+    headers
+        .iter()
+        .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+        .collect()
+}
+
+fn har_entry(entry: &TrafficEntry) -> serde_json::Value {
+    let query: Vec<serde_json::Value> = Url::parse(&entry.url)
+        .map(|url| {
+            url.query_pairs()
+                .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mime_type = entry
+        .response_headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_default();
+    let redirect_url = entry
+        .response_headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_default();
+    let status_text = reqwest::StatusCode::from_u16(entry.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+    serde_json::json!({
+        "startedDateTime": iso8601(entry.started_at),
+        "time": round_ms(entry.wait_ms + entry.receive_ms),
+        "request": {
+            "method": entry.method,
+            "url": entry.url,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": har_headers(&entry.request_headers),
+            "queryString": query,
+            "headersSize": -1,
+            "bodySize": entry.request_size.map_or(-1, |size| size as i64),
+        },
+        "response": {
+            "status": entry.status,
+            "statusText": status_text,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": har_headers(&entry.response_headers),
+            "content": {
+                "size": entry.response_size,
+                "mimeType": mime_type,
+            },
+            "redirectURL": redirect_url,
+            "headersSize": -1,
+            "bodySize": entry.response_size,
+        },
+        "cache": {},
+        "timings": {
+            "send": 0,
+            "wait": entry.wait_ms,
+            "receive": entry.receive_ms,
+        },
+        "_proxyCache": entry.cache,
+        "_completed": entry.completed,
+    })
+}
+
+/// 按 HAR 1.2 格式导出全部记录
+pub(crate) fn to_har() -> serde_json::Value {
+    let mut entries = entries(None);
+    entries.reverse();
+    serde_json::json!({
+        "log": {
+            "version": "1.2",
+            "creator": {
+                "name": "proxy-plugin",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "entries": entries.iter().map(har_entry).collect::<Vec<_>>(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::test_support::{serve, setup};
+    use crate::proxy_plugin::{commands, config, tiles};
+    use warp::Filter;
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert_eq!(
+            redact_url("https://t0.tianditu.gov.cn/DataServer?T=vec_w&tk=abc&keyword=k"),
+            "https://t0.tianditu.gov.cn/DataServer?T=vec_w&tk=%5BREDACTED%5D&keyword=k"
+        );
+        assert_eq!(redact_url("https://example.com"), "https://example.com");
+        assert_eq!(redact_header("Authorization", b"Bearer abc").1, REDACTED);
+        assert_eq!(redact_header("X-Api-Key", b"abc").1, REDACTED);
+        assert_eq!(redact_header("Set-Cookie", b"sid=abc").1, REDACTED);
+
+        // 跳转地址, 包括代理地址中编码的目标地址和请求头段
+        let (_, location) = redact_header("location", b"https://example.com/next?token=abc&page=2");
+        assert_eq!(
+            location,
+            "https://example.com/next?token=%5BREDACTED%5D&page=2"
+        );
+        let (_, location) = redact_header("location", b"/next?tk=abc&page=2#top");
+        assert_eq!(location, "/next?tk=%5BREDACTED%5D&page=2#top");
+        assert_eq!(redact_header("location", b"/next").1, "/next");
+        let target = "https://example.com/next?tk=abc&page=2";
+        let proxied = format!(
+            "http://127.0.0.1:1/proxy/b64.YWJj/{}",
+            urlencoding::encode(target)
+        );
+        let (_, location) = redact_header("Location", proxied.as_bytes());
+        assert_eq!(
+            location,
+            format!(
+                "http://127.0.0.1:1/proxy/%5BREDACTED%5D/{}",
+                urlencoding::encode("https://example.com/next?tk=%5BREDACTED%5D&page=2")
+            )
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn entries_are_redacted_and_exported_as_har() {
+        let _guard = setup(serde_json::json!({"trafficLog": {"enabled": true}})).await;
+        let port = serve(
+            warp::path("next")
+                .map(|| {
+                    warp::http::Response::builder()
+                        .status(302)
+                        .header("location", "/done?tk=abc&page=2")
+                        .header("set-cookie", "sid=abc")
+                        .body(Vec::new())
+                        .unwrap()
+                })
+                .or(warp::path!("tile" / u32 / u32 / u32).map(|_, _, _| {
+                    warp::http::Response::new(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec())
+                })),
+        );
+        config::update(|config| {
+            config.upstreams.get_mut("tdt-tile").unwrap().key = Some("abc".to_string());
+            let layer = tiles::TileLayer {
+                name: "logged".to_string(),
+                min_zoom: 0,
+                max_zoom: 18,
+                providers: vec![tiles::TileProvider {
+                    url: format!(
+                        "http://127.0.0.1:{}/tile/{{z}}/{{x}}/{{y}}?tk={{key}}",
+                        port
+                    ),
+                    upstream: Some("tdt-tile".to_string()),
+                    ..Default::default()
+                }],
+            };
+            config.tile_layers.insert("logged".to_string(), layer);
+        })
+        .unwrap();
+        clear();
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let url = commands::get_proxy_url(
+            &format!("http://127.0.0.1:{}/next?tk=abc&q=1", port),
+            Some(vec![(
+                "authorization".to_string(),
+                "Bearer abc".to_string(),
+            )]),
+        )
+        .unwrap();
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), 302);
+        response.bytes().await.unwrap();
+        // 第二次请求由瓦片缓存返回, 同样记录
+        let tile = format!(
+            "{}/tiles/logged/4/5/6.png",
+            commands::get_proxy_base_url().unwrap()
+        );
+        for _ in 0..2 {
+            let response = client.get(&tile).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            response.bytes().await.unwrap();
+        }
+
+        let logged = entries(None);
+        let serialized = serde_json::to_string(&logged).unwrap();
+        assert!(!serialized.contains("abc"), "{}", serialized);
+        assert!(!serialized.contains("YWJj"), "{}", serialized);
+        let next = logged
+            .iter()
+            .find(|entry| entry.url.contains("/next"))
+            .unwrap();
+        assert!(next.url.ends_with("?tk=%5BREDACTED%5D&q=1"), "{}", next.url);
+        let header = |headers: &[(String, String)], name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(
+            header(&next.request_headers, "authorization").as_deref(),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            header(&next.response_headers, "set-cookie").as_deref(),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            header(&next.response_headers, "location").as_deref(),
+            Some("/done?tk=%5BREDACTED%5D&page=2")
+        );
+        let tiles: Vec<_> = logged
+            .iter()
+            .filter(|entry| entry.url.contains("/tile/4/5/6"))
+            .collect();
+        // 最新的在前: 缓存命中, 未命中时的上游请求
+        let caches: Vec<_> = tiles.iter().map(|entry| entry.cache.as_deref()).collect();
+        assert_eq!(caches, [Some("HIT"), None]);
+        assert!(tiles
+            .iter()
+            .all(|entry| entry.url.contains("tk=%5BREDACTED%5D")));
+
+        let har = to_har();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["creator"]["name"], "proxy-plugin");
+        assert!(har["log"]["creator"]["version"].is_string());
+        let har_entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(har_entries.len(), logged.len());
+        for entry in har_entries {
+            for timing in ["send", "wait", "receive"] {
+                assert!(entry["timings"][timing].is_number(), "{}", entry);
+            }
+            assert!(entry["startedDateTime"].as_str().unwrap().ends_with('Z'));
+        }
+        assert!(!har.to_string().contains("abc"));
+    }
+}
@@ -0,0 +1,475 @@
+use super::config;
+use super::upstream::Route;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use futures_util::{stream, StreamExt};
+use reqwest::header::HeaderMap as ReqwestHeaderMap;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use warp::http::header::{self, HeaderMap, HeaderValue};
+use warp::hyper::body::{Body, Bytes, HttpBody};
+
+// 请求头, 取值 charset / jsonp (可用逗号组合) / all / off, 不会转发给上游;
+// 响应头中列出实际做了的转换
+pub(crate) const TRANSFORM_HEADER: &str = "x-proxy-transform";
+// 超过该大小的响应不转换
+const MAX_TRANSFORM_BYTES: usize = 8 * 1024 * 1024;
+// 需要转换时只接受能解压的编码
+const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+// 在 HTML 和 XML 开头查找编码声明的范围
+const SNIFF_BYTES: usize = 1024;
+
+/// 响应转换设置, 对应配置文件中的 `transform`, 命名上游可单独指定, 默认都不开启
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct TransformSettings {
+    // 文本响应转为 UTF-8 并修正 Content-Type
+    pub charset: bool,
+    // JSONP 回调解包为 JSON
+    pub jsonp: bool,
+}
+
+impl TransformSettings {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let mut settings = TransformSettings::default();
+        for item in value.split(',') {
+            match item.trim().to_ascii_lowercase().as_str() {
+                "charset" => settings.charset = true,
+                "jsonp" => settings.jsonp = true,
+                "all" => {
+                    settings.charset = true;
+                    settings.jsonp = true;
+                }
+                "off" | "none" => return Some(TransformSettings::default()),
+                _ => return None,
+            }
+        }
+        Some(settings)
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.charset || self.jsonp
+    }
+}
+
+/// 取出请求的转换设置: 请求头 > 命名上游 > 全局配置
+pub(crate) fn resolve(
+    header_map: &mut ReqwestHeaderMap,
+    headers: &mut HeaderMap,
+    route: &Route,
+) -> TransformSettings {
+    let segment = header_map
+        .remove(TRANSFORM_HEADER)
+        .and_then(|value| value.to_str().ok().and_then(TransformSettings::parse));
+    let webview = headers
+        .remove(TRANSFORM_HEADER)
+        .and_then(|value| value.to_str().ok().and_then(TransformSettings::parse));
+    let settings = segment
+        .or(webview)
+        .or(route.profile().and_then(|profile| profile.transform))
+        .unwrap_or(config::current().transform);
+    if settings.enabled() {
+        header_map.insert(
+            reqwest::header::ACCEPT_ENCODING,
+            reqwest::header::HeaderValue::from_static(ACCEPT_ENCODING),
+        );
+    }
+    settings
+}
+
+/// 转换文本响应, 不需要或无法转换时原样返回
+pub(crate) async fn apply(
+    settings: TransformSettings,
+    reply: warp::reply::Response,
+) -> warp::reply::Response {
+    let status = reply.status();
+    if !settings.enabled()
+        || !status.is_success()
+        || status == warp::http::StatusCode::NO_CONTENT
+        || status == warp::http::StatusCode::PARTIAL_CONTENT
+    {
+        return reply;
+    }
+    let content_type = ContentType::parse(reply.headers());
+    if !content_type.is_text() {
+        return reply;
+    }
+    let length = reply
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if length.is_some_and(|length| length > MAX_TRANSFORM_BYTES) {
+        return reply;
+    }
+
+    let (mut parts, body) = reply.into_parts();
+    let data = match read_body(body).await {
+        Ok(data) => data,
+        Err(body) => return warp::reply::Response::from_parts(parts, body),
+    };
+    let encoding = parts
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let decoded = match decompress(encoding.as_deref(), &data) {
+        Some(decoded) => decoded,
+        None => {
+            log::debug!("cannot decode {:?} response for transform", encoding);
+            return warp::reply::Response::from_parts(parts, Body::from(data));
+        }
+    };
+
+    let mut applied = Vec::new();
+    let mut text = decoded;
+    let mut charset = content_type.charset.clone();
+    if settings.charset {
+        if let Some(source) = content_type.encoding(&text) {
+            if source != UTF_8 {
+                let (decoded, _, had_errors) = source.decode(&text);
+                if had_errors {
+                    log::debug!("invalid {} sequences replaced", source.name());
+                }
+                text = decoded.into_owned().into_bytes();
+                applied.push("charset");
+            }
+            charset = Some("utf-8".to_string());
+        }
+    }
+    let mut mime = content_type.mime.clone();
+    if settings.jsonp && content_type.is_script() {
+        if let Some(json) = std::str::from_utf8(&text).ok().and_then(unwrap_jsonp) {
+            text = json.as_bytes().to_vec();
+            mime = "application/json".to_string();
+            applied.push("jsonp");
+        }
+    }
+    if applied.is_empty() {
+        return warp::reply::Response::from_parts(parts, Body::from(data));
+    }
+
+    let content_type = match charset {
+        Some(charset) => format!("{}; charset={}", mime, charset),
+        None => mime,
+    };
+    if let Ok(value) = HeaderValue::from_str(&content_type) {
+        parts.headers.insert(header::CONTENT_TYPE, value);
+    }
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, text.len().into());
+    parts.headers.insert(
+        TRANSFORM_HEADER,
+        HeaderValue::from_str(&applied.join(",")).unwrap(),
+    );
+    warp::reply::Response::from_parts(parts, Body::from(text))
+}
+
+// 读取完整响应体, 超过大小或读取出错时把已读取的部分拼回去原样转发
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Body> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => {
+                size += chunk.len();
+                chunks.push(chunk);
+                if size > MAX_TRANSFORM_BYTES {
+                    let head = stream::iter(chunks.into_iter().map(Ok));
+                    return Err(Body::wrap_stream(head.chain(body)));
+                }
+            }
+            Err(e) => {
+                let head = stream::iter(chunks.into_iter().map(Ok));
+                return Err(Body::wrap_stream(
+                    head.chain(stream::once(async { Err(e) })),
+                ));
+            }
+        }
+    }
+    Ok(chunks.concat())
+}
+
+// 按 Content-Encoding 解压, 不支持的编码或解压失败时返回 None
+fn decompress(encoding: Option<&str>, data: &[u8]) -> Option<Vec<u8>> {
+    let encoding = encoding.map(|value| value.trim().to_ascii_lowercase());
+    let reader: Box<dyn Read + '_> = match encoding.as_deref() {
+        None | Some("") | Some("identity") => return Some(data.to_vec()),
+        Some("gzip") | Some("x-gzip") => Box::new(MultiGzDecoder::new(data)),
+        // 按规范应为 zlib 格式, 部分服务器发送的是裸 deflate
+        Some("deflate") => match data.first() {
+            Some(first) if first & 0x0f == 0x08 => Box::new(ZlibDecoder::new(data)),
+            _ => Box::new(DeflateDecoder::new(data)),
+        },
+        Some("br") => Box::new(brotli_decompressor::Decompressor::new(data, 4096)),
+        Some(_) => return None,
+    };
+    // 限制解压后的大小, 防止压缩炸弹
+    let mut decoded = Vec::new();
+    reader
+        .take(MAX_TRANSFORM_BYTES as u64 + 1)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    (decoded.len() <= MAX_TRANSFORM_BYTES).then_some(decoded)
+}
+
+struct ContentType {
+    // 小写, 不含参数
+    mime: String,
+    charset: Option<String>,
+}
+
+impl ContentType {
+    fn parse(headers: &HeaderMap) -> Self {
+        let value = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let mut params = value.split(';');
+        let mime = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let charset = params.find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("charset")
+                .then(|| value.trim().trim_matches('"').to_string())
+        });
+        ContentType { mime, charset }
+    }
+
+    // 事件流边收边转发, 不做转换
+    fn is_text(&self) -> bool {
+        (self.mime.starts_with("text/") && self.mime != "text/event-stream")
+            || self.is_script()
+            || self.mime.ends_with("+json")
+            || self.mime.ends_with("+xml")
+            || self.mime == "application/xml"
+    }
+
+    // 可能是 JSONP 的类型
+    fn is_script(&self) -> bool {
+        matches!(
+            self.mime.as_str(),
+            "application/javascript"
+                | "application/x-javascript"
+                | "application/json"
+                | "text/javascript"
+                | "text/plain"
+        )
+    }
+
+    // 依次看 Content-Type 的 charset、BOM、HTML 和 XML 中的编码声明
+    fn encoding(&self, data: &[u8]) -> Option<&'static Encoding> {
+        if let Some(encoding) = self
+            .charset
+            .as_ref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+        {
+            return Some(encoding);
+        }
+        if let Some((encoding, _)) = Encoding::for_bom(data) {
+            return Some(encoding);
+        }
+        let pattern = match self.mime.as_str() {
+            "text/html" => "charset=",
+            mime if mime == "application/xml" || mime.ends_with("+xml") || mime == "text/xml" => {
+                "encoding="
+            }
+            _ => return None,
+        };
+        let head = &data[..data.len().min(SNIFF_BYTES)];
+        let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+        let start = head.find(pattern)? + pattern.len();
+        let label: String = head[start..]
+            .trim_start_matches(['"', '\''])
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+            .collect();
+        Encoding::for_label(label.as_bytes())
+    }
+}
+
+// `callback({...});` 或 `/**/ callback({...})` 解包后仍须是合法 JSON
+fn unwrap_jsonp(text: &str) -> Option<&str> {
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    let text = text.strip_prefix("/**/").unwrap_or(text).trim_start();
+    let open = text.find('(')?;
+    let callback = text[..open].trim_end();
+    let valid_callback = callback
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && callback
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '$' | '.'));
+    if !valid_callback {
+        return None;
+    }
+    let rest = text[open + 1..].trim_end();
+    let rest = rest.strip_suffix(';').unwrap_or(rest).trim_end();
+    let json = rest.strip_suffix(')')?.trim();
+    serde_json::from_str::<serde::de::IgnoredAny>(json).ok()?;
+    Some(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    // 夹具由 python 的 str.encode('gbk') 和 str.encode('gb2312') 生成
+    const GBK_HTML: &[u8] = include_bytes!("fixtures/gbk.html");
+    const GB2312_XML: &[u8] = include_bytes!("fixtures/gb2312.xml");
+    const GBK_JSONP: &[u8] = include_bytes!("fixtures/gbk.jsonp");
+
+    const ALL: TransformSettings = TransformSettings {
+        charset: true,
+        jsonp: true,
+    };
+
+    fn response(
+        content_type: &str,
+        encoding: Option<&str>,
+        body: Vec<u8>,
+    ) -> warp::reply::Response {
+        let mut builder = warp::http::Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len());
+        if let Some(encoding) = encoding {
+            builder = builder.header(header::CONTENT_ENCODING, encoding);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    async fn transform(
+        settings: TransformSettings,
+        reply: warp::reply::Response,
+    ) -> (HeaderMap, String) {
+        let (parts, body) = apply(settings, reply).await.into_parts();
+        let body = read_body(body).await.ok().unwrap();
+        (parts.headers, String::from_utf8(body).unwrap())
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn html_meta_charset_is_transcoded() {
+        let (headers, body) = transform(ALL, response("text/html", None, GBK_HTML.to_vec())).await;
+        assert!(body.contains("<title>天地图</title>"), "{}", body);
+        assert!(body.contains("矢量底图和影像注记"));
+        assert_eq!(
+            header(&headers, "content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(header(&headers, TRANSFORM_HEADER), Some("charset"));
+        assert_eq!(
+            header(&headers, "content-length"),
+            Some(body.len().to_string().as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn xml_declaration_is_transcoded() {
+        let reply = response("application/xml", None, GB2312_XML.to_vec());
+        let (headers, body) = transform(ALL, reply).await;
+        assert!(body.contains("<city>北京市</city><district>海淀区</district>"));
+        assert_eq!(
+            header(&headers, "content-type"),
+            Some("application/xml; charset=utf-8")
+        );
+    }
+
+    #[tokio::test]
+    async fn header_charset_wins_and_jsonp_is_unwrapped() {
+        let reply = response(
+            "application/javascript; charset=GBK",
+            None,
+            GBK_JSONP.to_vec(),
+        );
+        let (headers, body) = transform(ALL, reply).await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["result"]["address"], "广东省广州市天河区");
+        assert_eq!(
+            header(&headers, "content-type"),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(header(&headers, TRANSFORM_HEADER), Some("charset,jsonp"));
+
+        // 只解包时不改动编码
+        let settings = TransformSettings {
+            charset: false,
+            jsonp: true,
+        };
+        let reply = response("text/javascript", None, b"cb({\"a\":1})".to_vec());
+        let (headers, body) = transform(settings, reply).await;
+        assert_eq!(body, "{\"a\":1}");
+        assert_eq!(header(&headers, "content-type"), Some("application/json"));
+    }
+
+    #[tokio::test]
+    async fn compressed_bodies_are_decoded() {
+        let reply = response("text/html", Some("gzip"), gzip(GBK_HTML));
+        let (headers, body) = transform(ALL, reply).await;
+        assert!(body.contains("矢量底图和影像注记"));
+        assert_eq!(header(&headers, "content-encoding"), None);
+
+        // 裸 deflate
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(GB2312_XML).unwrap();
+        let reply = response("text/xml", Some("deflate"), encoder.finish().unwrap());
+        let (_, body) = transform(ALL, reply).await;
+        assert!(body.contains("北京市"));
+
+        // 无法解压时原样返回
+        let reply = response("text/html", Some("zstd"), GBK_HTML.to_vec());
+        let (headers, body) = apply(ALL, reply).await.into_parts();
+        assert_eq!(header(&headers.headers, "content-encoding"), Some("zstd"));
+        assert_eq!(read_body(body).await.ok().unwrap(), GBK_HTML);
+    }
+
+    #[tokio::test]
+    async fn untouched_responses_keep_their_bytes() {
+        // 已是 UTF-8 或未开启转换时不修改响应
+        let reply = response("text/html; charset=utf-8", Some("gzip"), gzip(b"<p>ok</p>"));
+        let (parts, body) = apply(ALL, reply).await.into_parts();
+        assert_eq!(header(&parts.headers, "content-encoding"), Some("gzip"));
+        assert_eq!(header(&parts.headers, TRANSFORM_HEADER), None);
+        assert_eq!(read_body(body).await.ok().unwrap(), gzip(b"<p>ok</p>"));
+
+        let reply = response("text/html", None, GBK_HTML.to_vec());
+        let (_, body) = apply(TransformSettings::default(), reply)
+            .await
+            .into_parts();
+        assert_eq!(read_body(body).await.ok().unwrap(), GBK_HTML);
+
+        // 不是合法的 JSONP 时不解包
+        assert_eq!(unwrap_jsonp("alert(1); cb({})"), None);
+        assert_eq!(unwrap_jsonp("/**/ cb([1, 2]);"), Some("[1, 2]"));
+    }
+
+    #[test]
+    fn settings_parse() {
+        assert_eq!(TransformSettings::parse("charset, JSONP"), Some(ALL));
+        assert_eq!(TransformSettings::parse("all"), Some(ALL));
+        assert_eq!(
+            TransformSettings::parse("off"),
+            Some(TransformSettings::default())
+        );
+        assert_eq!(TransformSettings::parse("gzip"), None);
+    }
+}
@@ -0,0 +1,179 @@
+use super::client::OutboundProxy;
+use super::redirect::RedirectMode;
+use super::retry::RetryPolicy;
+use super::transform::TransformSettings;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// 命名上游, 前端通过 `/upstream/{name}/{path}` 访问, key 只保存在配置文件中
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct UpstreamProfile {
+    pub base_url: String,
+    pub key: Option<String>,
+    // key 以查询参数注入时的参数名, 例如天地图的 tk
+    pub query_param: Option<String>,
+    // key 以请求头注入时的请求头名称
+    pub header: Option<String>,
+    // 未指定时使用全局的重定向设置
+    pub redirect: Option<RedirectMode>,
+    // 未指定时使用全局的重试策略
+    pub retry: Option<RetryPolicy>,
+    // 未指定时使用全局的出站代理
+    pub outbound: Option<OutboundProxy>,
+    // 未指定时使用全局的响应转换设置
+    pub transform: Option<TransformSettings>,
+}
+
+/// 请求的来源路由, 决定跳转地址的改写方式以及使用哪个上游的设置
+pub(crate) enum Route {
+    // `/proxy/{headers}/{url}`, 保存原请求的请求头段
+    Proxy(String),
+    // `/upstream/{name}/{path}`
+    Upstream(String, Box<UpstreamProfile>),
+}
+
+impl Route {
+    pub(crate) fn profile(&self) -> Option<&UpstreamProfile> {
+        match self {
+            Route::Proxy(_) => None,
+            Route::Upstream(_, profile) => Some(profile.as_ref()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpstreamInfo {
+    pub name: String,
+    pub base_url: String,
+    pub has_key: bool,
+}
+
+impl UpstreamProfile {
+    pub(crate) fn defaults() -> BTreeMap<String, UpstreamProfile> {
+        // 天地图 key 不随程序分发, 需在 proxy.json 中填写
+        let tdt = |base_url: &str| UpstreamProfile {
+            base_url: base_url.to_string(),
+            key: None,
+            query_param: Some("tk".to_string()),
+            header: None,
+            redirect: None,
+            retry: None,
+            outbound: None,
+            transform: None,
+        };
+        BTreeMap::from([
+            ("tdt".to_string(), tdt("https://api.tianditu.gov.cn")),
+            ("tdt-tile".to_string(), tdt("https://t0.tianditu.gov.cn")),
+        ])
+    }
+
+    pub(crate) fn info(&self, name: &str) -> UpstreamInfo {
+        UpstreamInfo {
+            name: name.to_string(),
+            base_url: self.base_url.clone(),
+            has_key: self.key.as_deref().is_some_and(|key| !key.is_empty()),
+        }
+    }
+
+    /// 配置了注入方式时返回 key, key 缺失或为空时报错
+    pub(crate) fn key(&self, name: &str) -> Result<Option<&str>, String> {
+        if self.query_param.is_none() && self.header.is_none() {
+            return Ok(None);
+        }
+        match self.key.as_deref().filter(|key| !key.is_empty()) {
+            Some(key) => Ok(Some(key)),
+            None => Err(format!(
+                "Upstream {} has no key, set upstreams.{}.key in proxy.json",
+                name, name
+            )),
+        }
+    }
+
+    /// 拼接目标地址并注入 key, `path` 与 `query` 保持原有编码
+    pub(crate) fn target_url(
+        &self,
+        name: &str,
+        path: &str,
+        query: Option<&str>,
+    ) -> Result<String, String> {
+        let mut url = format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let mut pairs: Vec<String> = query
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.to_string())
+            .collect();
+        if let (Some(param), Some(key)) = (&self.query_param, self.key(name)?) {
+            // 前端传入的同名参数会被覆盖
+            pairs.retain(|pair| pair.split('=').next() != Some(param.as_str()));
+            pairs.push(format!(
+                "{}={}",
+                urlencoding::encode(param),
+                urlencoding::encode(key)
+            ));
+        }
+        if !pairs.is_empty() {
+            url.push('?');
+            url.push_str(&pairs.join("&"));
+        }
+        Url::parse(&url).map_err(|e| format!("Invalid upstream URL: {}", e))?;
+        Ok(url)
+    }
+
+    pub(crate) fn inject_headers(&self, name: &str, headers: &mut HeaderMap) -> Result<(), String> {
+        if let (Some(header), Some(key)) = (&self.header, self.key(name)?) {
+            let name = HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format!("Invalid upstream header name: {}", header))?;
+            let value =
+                HeaderValue::from_str(key).map_err(|_| "Invalid upstream key".to_string())?;
+            headers.insert(name, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_key_is_an_error() {
+        let profiles = UpstreamProfile::defaults();
+        let tdt = &profiles["tdt"];
+        assert!(tdt.key.is_none());
+        assert!(!tdt.info("tdt").has_key);
+        let err = tdt.target_url("tdt", "v2/search", Some("a=1")).unwrap_err();
+        assert!(err.contains("upstreams.tdt.key"), "{}", err);
+
+        let mut tdt = tdt.clone();
+        tdt.key = Some(String::new());
+        assert!(tdt.target_url("tdt", "v2/search", None).is_err());
+        tdt.key = Some("secret".to_string());
+        assert_eq!(
+            tdt.target_url("tdt", "v2/search", Some("tk=x&a=1"))
+                .unwrap(),
+            "https://api.tianditu.gov.cn/v2/search?a=1&tk=secret"
+        );
+
+        // 不注入 key 的上游不需要 key
+        let plain = UpstreamProfile {
+            base_url: "https://example.com".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            plain.target_url("plain", "a", None).unwrap(),
+            "https://example.com/a"
+        );
+        let mut headers = HeaderMap::new();
+        plain.inject_headers("plain", &mut headers).unwrap();
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,477 @@
+use super::config;
+use super::headers;
+use super::policy::{self, PolicyViolation};
+use super::tls;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::header::{HeaderMap as ReqwestHeaderMap, HeaderName as ReqwestHeaderName};
+use reqwest::Url;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message as UpstreamMessage;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use warp::ws::{Message, WebSocket};
+
+type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// 出站代理 CONNECT 响应头的最大长度
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+// 握手相关的请求头由 tungstenite 生成, 不使用 webview 传入的值
+const HANDSHAKE_HEADERS: [&str; 7] = [
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "content-length",
+];
+
+pub(crate) enum ConnectError {
+    Policy(PolicyViolation),
+    Tls(String),
+    Other(String),
+}
+
+fn resolve_error(e: Box<dyn std::error::Error + Send + Sync>) -> ConnectError {
+    match e.downcast::<PolicyViolation>() {
+        Ok(violation) => ConnectError::Policy(*violation),
+        Err(e) => ConnectError::Other(e.to_string()),
+    }
+}
+
+// 按访问策略解析并连接, 依次尝试各个地址; 配置了出站代理时经代理建立隧道
+async fn connect_tcp(url: &Url, host: &str, port: u16) -> Result<TcpStream, ConnectError> {
+    let config = config::current();
+    let block_private = config.policy.block_private;
+    let timeout = Duration::from_secs(config.client.connect_timeout_secs);
+    let proxy = config
+        .client
+        .outbound
+        .for_url(url)
+        .map_err(ConnectError::Other)?;
+    if let Some(proxy) = proxy {
+        // 由代理解析的域名同样先在本地检查, socks5 使用本地解析的地址
+        let addr = if block_private || proxy.scheme() == "socks5" {
+            let addrs = policy::resolve(host, port, block_private)
+                .await
+                .map_err(resolve_error)?;
+            addrs.first().map(|addr| addr.ip())
+        } else {
+            None
+        };
+        return match tokio::time::timeout(timeout, tunnel(&proxy, host, port, addr)).await {
+            Ok(result) => result.map_err(ConnectError::Other),
+            Err(_) => Err(ConnectError::Other(format!(
+                "connect to {}:{} through outbound proxy timed out",
+                host, port
+            ))),
+        };
+    }
+    let addrs = policy::resolve(host, port, block_private)
+        .await
+        .map_err(resolve_error)?;
+    let mut last_error = format!("{} has no address", host);
+    for addr in addrs {
+        match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = e.to_string(),
+            Err(_) => last_error = format!("connect to {} timed out", addr),
+        }
+    }
+    Err(ConnectError::Other(last_error))
+}
+
+// 经出站代理建立到目标的隧道, 支持 HTTP CONNECT 和 SOCKS5;
+// `addr` 为本地解析的地址, socks5 时代替域名发给代理
+async fn tunnel(
+    proxy: &Url,
+    host: &str,
+    port: u16,
+    addr: Option<IpAddr>,
+) -> Result<TcpStream, String> {
+    let proxy_host = proxy
+        .host_str()
+        .ok_or("Invalid outbound proxy URL")?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    // 与 reqwest 一致, SOCKS5 代理默认使用 1080 端口
+    let proxy_port = proxy.port_or_known_default().unwrap_or(1080);
+    let credentials = match proxy.username() {
+        "" => None,
+        username => Some((
+            urlencoding::decode(username)
+                .map_err(|e| e.to_string())?
+                .into_owned(),
+            urlencoding::decode(proxy.password().unwrap_or_default())
+                .map_err(|e| e.to_string())?
+                .into_owned(),
+        )),
+    };
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .map_err(|e| format!("cannot connect to outbound proxy: {}", e))?;
+    match proxy.scheme() {
+        "http" => http_connect(&mut stream, host, port, credentials).await?,
+        "socks5" => socks5_connect(&mut stream, host, port, addr, credentials).await?,
+        "socks5h" => socks5_connect(&mut stream, host, port, None, credentials).await?,
+        scheme => {
+            return Err(format!(
+                "{} outbound proxy is not supported for WebSocket",
+                scheme
+            ))
+        }
+    }
+    Ok(stream)
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<(), String> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some((username, password)) = credentials {
+        let encoded = BASE64.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encoded));
+    }
+    request.push_str("\r\n");
+    let io_error = |e: std::io::Error| format!("outbound proxy: {}", e);
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(io_error)?;
+    // 逐字节读取响应头, 不读到隧道中的数据
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE {
+            return Err("outbound proxy response is too large".to_string());
+        }
+        stream.read_exact(&mut byte).await.map_err(io_error)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("outbound proxy refused CONNECT: {}", status_line)),
+    }
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    addr: Option<IpAddr>,
+    credentials: Option<(String, String)>,
+) -> Result<(), String> {
+    let io_error = |e: std::io::Error| format!("outbound proxy: {}", e);
+    // 不认证, 有用户名时同时提供用户名密码认证
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[5, 2, 0, 2],
+        None => &[5, 1, 0],
+    };
+    stream.write_all(greeting).await.map_err(io_error)?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.map_err(io_error)?;
+    match (reply, credentials) {
+        ([5, 0], _) => {}
+        ([5, 2], Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err("outbound proxy credentials are too long".to_string());
+            }
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await.map_err(io_error)?;
+            stream.read_exact(&mut reply).await.map_err(io_error)?;
+            if reply[1] != 0 {
+                return Err("outbound proxy authentication failed".to_string());
+            }
+        }
+        _ => return Err("outbound proxy rejected the authentication methods".to_string()),
+    }
+    let mut request = vec![5, 1, 0];
+    match addr.or_else(|| host.parse().ok()) {
+        Some(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        // 由代理解析域名
+        None if host.len() <= 255 => {
+            request.extend_from_slice(&[3, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+        None => return Err("host name is too long for SOCKS5".to_string()),
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await.map_err(io_error)?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(io_error)?;
+    if reply[1] != 0 {
+        return Err(format!(
+            "outbound proxy refused the connection (SOCKS5 reply {})",
+            reply[1]
+        ));
+    }
+    // 跳过代理绑定的地址和端口
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await.map_err(io_error)?;
+            len[0] as usize
+        }
+        _ => return Err("invalid SOCKS5 reply".to_string()),
+    };
+    let mut skipped = vec![0u8; bound + 2];
+    stream.read_exact(&mut skipped).await.map_err(io_error)?;
+    Ok(())
+}
+
+/// 连接上游 WebSocket, header_map 中的请求头优先于 webview 传入的请求头
+pub(crate) async fn connect_upstream(
+    url: &Url,
+    mut header_map: ReqwestHeaderMap,
+    client_headers: &warp::http::HeaderMap,
+) -> Result<(UpstreamSocket, Option<String>), ConnectError> {
+    // 访问策略按对应的 http(s) 地址检查
+    let mut http_url = url.clone();
+    let secure = url.scheme() == "wss";
+    let _ = http_url.set_scheme(if secure { "https" } else { "http" });
+    let config = config::current();
+    policy::check(&config, &http_url).map_err(ConnectError::Policy)?;
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| ConnectError::Other("Missing host".to_string()))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| ConnectError::Other("Missing port".to_string()))?;
+
+    let excluded: Vec<ReqwestHeaderName> = HANDSHAKE_HEADERS
+        .iter()
+        .map(|name| ReqwestHeaderName::from_static(name))
+        .chain([
+            ReqwestHeaderName::from_static("referer"),
+            ReqwestHeaderName::from_static("origin"),
+        ])
+        .collect();
+    let client_headers = headers::convert_to_reqwest_headers(client_headers);
+    headers::merge_headers(&mut header_map, &client_headers, &excluded);
+    for name in &excluded[..HANDSHAKE_HEADERS.len()] {
+        header_map.remove(name);
+    }
+
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| ConnectError::Other(e.to_string()))?;
+    for (name, value) in header_map.iter() {
+        request.headers_mut().append(name.clone(), value.clone());
+    }
+
+    let connector = if secure {
+        let mut tls_config = tls::client_config(&config.tls).map_err(ConnectError::Other)?;
+        // tungstenite 只支持 HTTP/1.1 上的 WebSocket
+        tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Connector::Rustls(Arc::new(tls_config))
+    } else {
+        Connector::Plain
+    };
+    let stream = connect_tcp(url, &host, port).await?;
+    let (socket, response) =
+        tokio_tungstenite::client_async_tls_with_config(request, stream, None, Some(connector))
+            .await
+            .map_err(|e| match tls::verification_error(&e) {
+                Some(reason) => ConnectError::Tls(reason),
+                None => ConnectError::Other(e.to_string()),
+            })?;
+    let protocol = response
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    Ok((socket, protocol))
+}
+
+fn to_upstream(message: Message) -> Option<UpstreamMessage> {
+    if let Ok(text) = message.to_str() {
+        return Some(UpstreamMessage::Text(text.to_string()));
+    }
+    if message.is_binary() {
+        return Some(UpstreamMessage::Binary(message.into_bytes()));
+    }
+    if message.is_close() {
+        let frame = message.close_frame().map(|(code, reason)| CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.to_string().into(),
+        });
+        return Some(UpstreamMessage::Close(frame));
+    }
+    // ping/pong 由两端各自应答
+    None
+}
+
+fn to_client(message: UpstreamMessage) -> Option<Message> {
+    match message {
+        UpstreamMessage::Text(text) => Some(Message::text(text)),
+        UpstreamMessage::Binary(data) => Some(Message::binary(data)),
+        UpstreamMessage::Close(Some(frame)) => Some(Message::close_with(
+            u16::from(frame.code),
+            frame.reason.into_owned(),
+        )),
+        UpstreamMessage::Close(None) => Some(Message::close()),
+        _ => None,
+    }
+}
+
+/// 双向转发消息, 任意一端关闭后结束
+pub(crate) async fn pipe(client: WebSocket, upstream: UpstreamSocket) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let client_to_upstream = async {
+        while let Some(Ok(message)) = client_rx.next().await {
+            if let Some(message) = to_upstream(message) {
+                if upstream_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = upstream_tx.close().await;
+    };
+    let upstream_to_client = async {
+        while let Some(Ok(message)) = upstream_rx.next().await {
+            if let Some(message) = to_client(message) {
+                if client_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = client_tx.close().await;
+    };
+    tokio::select! {
+        _ = client_to_upstream => {}
+        _ = upstream_to_client => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_plugin::commands;
+    use crate::proxy_plugin::test_support::{outbound_proxy, serve, setup, Proxied};
+    use warp::Filter;
+
+    fn echo() -> u16 {
+        serve(warp::ws().map(|ws: warp::ws::Ws| {
+            ws.on_upgrade(|socket| async move {
+                let (tx, rx) = socket.split();
+                let _ = rx.forward(tx).await;
+            })
+        }))
+    }
+
+    async fn round_trip(url: &str) -> String {
+        let ws_url = commands::get_proxy_ws_url(url, None).unwrap();
+        let (mut socket, _) = tokio_tungstenite::connect_async(ws_url).await.unwrap();
+        socket
+            .send(UpstreamMessage::Text("ping".into()))
+            .await
+            .unwrap();
+        let reply = socket.next().await.unwrap().unwrap();
+        reply.into_text().unwrap().to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn websockets_go_through_the_outbound_proxy() {
+        let (proxy_port, log) = outbound_proxy().await;
+        let _guard = setup(serde_json::json!({
+            "client": { "outbound": {
+                "mode": "manual",
+                "url": format!("http://127.0.0.1:{}", proxy_port),
+                "username": "user",
+                "password": "pass"
+            } }
+        }))
+        .await;
+        let port = echo();
+
+        assert_eq!(
+            round_trip(&format!("ws://localhost:{}/", port)).await,
+            "ping"
+        );
+        assert_eq!(
+            log.lock().unwrap().last(),
+            Some(&Proxied {
+                kind: "connect",
+                target: format!("localhost:{}", port),
+                auth: Some("Basic dXNlcjpwYXNz".to_string()),
+            })
+        );
+
+        let use_proxy = |url: String| {
+            config::update(|config| config.client.outbound.url = Some(url)).unwrap();
+        };
+        use_proxy(format!("socks5h://127.0.0.1:{}", proxy_port));
+        assert_eq!(
+            round_trip(&format!("ws://localhost:{}/", port)).await,
+            "ping"
+        );
+        assert_eq!(
+            log.lock().unwrap().last(),
+            Some(&Proxied {
+                kind: "socks5",
+                target: format!("localhost:{}", port),
+                auth: Some("user:pass".to_string()),
+            })
+        );
+
+        // socks5 由本地解析, 代理只收到地址
+        use_proxy(format!("socks5://127.0.0.1:{}", proxy_port));
+        assert_eq!(
+            round_trip(&format!("ws://127.0.0.1:{}/", port)).await,
+            "ping"
+        );
+        assert_eq!(
+            log.lock()
+                .unwrap()
+                .last()
+                .map(|proxied| proxied.target.clone()),
+            Some(format!("127.0.0.1:{}", port))
+        );
+
+        // 不经过代理的主机直接连接
+        config::update(|config| config.client.outbound.no_proxy = vec!["127.0.0.1".to_string()])
+            .unwrap();
+        let connections = log.lock().unwrap().len();
+        assert_eq!(
+            round_trip(&format!("ws://127.0.0.1:{}/", port)).await,
+            "ping"
+        );
+        assert_eq!(log.lock().unwrap().len(), connections);
+    }
+}
@@ -1,18 +1,20 @@
 import {
-  tdtPositionUrl,
-  tdtXYZVECUrl,
-  tdtXYZCVAUrl,
-  tdtSearchUrl,
-  tdtViewSearchUrl,
-  tdtXYZCIAUrl,
-  tdtDriveUrl,
-  tdtGeoSearchUrl,
+  tdtApiHost,
+  tdtTileHost,
+  tdtPositionPath,
+  tdtXYZVECPath,
+  tdtXYZCVAPath,
+  tdtSearchPath,
+  tdtViewSearchPath,
+  tdtXYZCIAPath,
+  tdtDriveSearchPath,
+  tdtGeoSearchPath,
 } from "@/constants/tdt";
 import { AddressType, GeoAdressType } from "@/data/address";
 import { TDTDrivePath, TDTDriveSubPath } from "@/data/drivePath";
 import { useDisplayStore } from "@/store/displayStore";
 import { LRUCache } from "@/utils/lruCache";
-import { getProxyPort, getProxyUrl } from "@/utils/proxyUrl";
+import { getTileUrl, getUpstreamUrl } from "@/utils/proxyUrl";
 import { fetch } from "@tauri-apps/plugin-http";
 
 const lruCache = new LRUCache<string>({
@@ -22,8 +24,24 @@ const lruCache = new LRUCache<string>({
   cleanupInterval: 60000,
 });
 
-function encodeUrl(url: string): string {
-  return encodeURIComponent(url).replace(/%7B/g, "{").replace(/%7D/g, "}");
+// 网页版直接访问天地图, key 在构建时通过 VITE_TDT_KEY 提供, 见 .env.example
+function tdtWebUrl(host: string, path: string): string {
+  const key = import.meta.env.VITE_TDT_KEY;
+  if (!key) {
+    throw new Error(
+      "VITE_TDT_KEY is not set, copy .env.example to .env.local and fill in the Tianditu key",
+    );
+  }
+  return `${host}/${path}&tk=${encodeURIComponent(key)}`;
+}
+
+// 应用内经命名上游 tdt 访问, key 由代理注入
+export async function tdtApiUrl(path: string): Promise<string> {
+  const displayStore = useDisplayStore();
+  if (displayStore.isWeb) {
+    return tdtWebUrl(tdtApiHost, path);
+  }
+  return await getUpstreamUrl("tdt", path);
 }
 
 export async function getCurrentLngLat(): Promise<{
@@ -50,9 +68,11 @@ export async function getLngLatAddress(lonlat: {
   lng: number;
   lat: number;
 }): Promise<AddressType | undefined> {
-  const url = tdtPositionUrl
-    .replace("{lng}", lonlat.lng.toPrecision())
-    .replace("{lat}", lonlat.lat.toPrecision());
+  const url = await tdtApiUrl(
+    tdtPositionPath
+      .replace("{lng}", lonlat.lng.toPrecision())
+      .replace("{lat}", lonlat.lat.toPrecision())
+  );
   const displayStore = useDisplayStore();
   let _fetch = window.fetch;
   let options = undefined;
@@ -93,36 +113,27 @@ export async function getCurrentLocation() {
 export async function tdtXYZPoxyVECUrl() {
   const displayStore = useDisplayStore();
   if (displayStore.isWeb) {
-    return tdtXYZVECUrl;
+    return tdtWebUrl(tdtTileHost, tdtXYZVECPath);
   } else {
-    const port = await getProxyPort();
-    const proxyUrl =
-      `http://127.0.0.1:${port}/proxy/_/` + encodeUrl(tdtXYZVECUrl);
-    return proxyUrl;
+    return await getTileUrl("vec_w");
   }
 }
 
 export async function tdtXYZPoxyCVAUrl() {
   const displayStore = useDisplayStore();
   if (displayStore.isWeb) {
-    return tdtXYZCVAUrl;
+    return tdtWebUrl(tdtTileHost, tdtXYZCVAPath);
   } else {
-    const port = await getProxyPort();
-    const proxyUrl =
-      `http://127.0.0.1:${port}/proxy/_/` + encodeUrl(tdtXYZCVAUrl);
-    return proxyUrl;
+    return await getTileUrl("cva_w");
   }
 }
 
 export async function tdtXYZPoxyCIAUrl() {
   const displayStore = useDisplayStore();
   if (displayStore.isWeb) {
-    return tdtXYZCIAUrl;
+    return tdtWebUrl(tdtTileHost, tdtXYZCIAPath);
   } else {
-    const port = await getProxyPort();
-    const proxyUrl =
-      `http://127.0.0.1:${port}/proxy/_/` + encodeUrl(tdtXYZCIAUrl);
-    return proxyUrl;
+    return await getTileUrl("cia_w");
   }
 }
 
@@ -131,7 +142,7 @@ export async function tdtSearch(
   queryRadius?: string,
   pointLonlat?: string
 ): Promise<AddressType[]> {
-  const url = tdtSearchUrl.replace("{keyword}", keyword);
+  const url = await tdtApiUrl(tdtSearchPath.replace("{keyword}", keyword));
   try {
     const displayStore = useDisplayStore();
     let _fetch = window.fetch;
@@ -184,10 +195,12 @@ export async function tdtViewSearch(
   queryRadius: string,
   pointLonlat: string
 ): Promise<AddressType[]> {
-  const url = tdtViewSearchUrl
-    .replace("{keyword}", keyword)
-    .replace("{queryRadius}", queryRadius)
-    .replace("{pointLonlat}", pointLonlat);
+  const url = await tdtApiUrl(
+    tdtViewSearchPath
+      .replace("{keyword}", keyword)
+      .replace("{queryRadius}", queryRadius)
+      .replace("{pointLonlat}", pointLonlat)
+  );
   try {
     const displayStore = useDisplayStore();
     let _fetch = window.fetch;
@@ -229,7 +242,7 @@ export async function tdtViewSearch(
 export async function tdtGeoSearch(
   keyword: string
 ): Promise<GeoAdressType | undefined> {
-  const url = tdtGeoSearchUrl.replace("{keyword}", keyword);
+  const url = await tdtApiUrl(tdtGeoSearchPath.replace("{keyword}", keyword));
 
   try {
     const displayStore = useDisplayStore();
@@ -263,10 +276,11 @@ export async function tdtDrivePath(
   dest: { lng: number; lat: number },
   addStartEnd = true // 是否自动加上开始结束位置
 ): Promise<TDTDrivePath | undefined> {
-  const url = tdtDriveUrl
+  // 缓存以不含 key 和代理端口的路径为键
+  const path = tdtDriveSearchPath
     .replace("{orig}", `${orig.lng},${orig.lat}`)
     .replace("{dest}", `${dest.lng},${dest.lat}`);
-  let data = await lruCache.get(url);
+  let data = await lruCache.get(path);
   if (!data) {
     const displayStore = useDisplayStore();
     let _fetch = window.fetch;
@@ -285,10 +299,10 @@ export async function tdtDrivePath(
       };
     }
     try {
-      const response = await _fetch(url, options);
+      const response = await _fetch(await tdtApiUrl(path), options);
       data = await response.text();
       if (data) {
-        await lruCache.set(url, data);
+        await lruCache.set(path, data);
       }
     } catch (error) {
       console.error(`get tdtDrivePath failed: ${error}`);
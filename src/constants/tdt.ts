@@ -1,18 +1,22 @@
-export const tdtKey = "bea0c0310f3478b0a79d7e594f741fa6";
-export const tdtUrl = `https://api.tianditu.gov.cn/api?v=4.0&tk=${tdtKey}`;
-export const tdtPositionUrl = `https://api.tianditu.gov.cn/geocoder?postStr={'lon':{lng},'lat':{lat},'ver':1}&type=geocode&tk=${tdtKey}`;
+// 天地图 key 只保存在 Rust 侧, 应用内通过命名上游 tdt / tdt-tile 访问;
+// 网页版没有代理, 构建时通过环境变量 VITE_TDT_KEY 提供
+export const tdtApiHost = "https://api.tianditu.gov.cn";
+export const tdtTileHost = "https://t0.tianditu.gov.cn";
+
+export const tdtApiPath = "api?v=4.0";
+export const tdtPositionPath = "geocoder?postStr={'lon':{lng},'lat':{lat},'ver':1}&type=geocode";
 // 冒号 %3A 斜杠 %2F 问号 %3F 等于 %3D 和 %26
 // 矢量底图
-export const tdtXYZVECUrl = `https://t0.tianditu.gov.cn/DataServer?T=vec_w&x={x}&y={y}&l={z}&tk=${tdtKey}`;
+export const tdtXYZVECPath = "DataServer?T=vec_w&x={x}&y={y}&l={z}";
 // 矢量注记
-export const tdtXYZCVAUrl = `https://t0.tianditu.gov.cn/DataServer?T=cva_w&x={x}&y={y}&l={z}&tk=${tdtKey}`;
+export const tdtXYZCVAPath = "DataServer?T=cva_w&x={x}&y={y}&l={z}";
 // 影像注记
-export const tdtXYZCIAUrl = `https://t0.tianditu.gov.cn/DataServer?T=cia_w&x={x}&y={y}&l={z}&tk=${tdtKey}`;
+export const tdtXYZCIAPath = "DataServer?T=cia_w&x={x}&y={y}&l={z}";
 
-export const tdtSearchUrl = `https://api.tianditu.gov.cn/v2/search?postStr={"keyWord":"{keyword}","level":12,"mapBound":"-180,-90,180,90","queryType":4,"start":0,"count":20,"show":1}&type=query&tk=${tdtKey}`;
+export const tdtSearchPath = `v2/search?postStr={"keyWord":"{keyword}","level":12,"mapBound":"-180,-90,180,90","queryType":4,"start":0,"count":20,"show":1}&type=query`;
 // 地理编码查询
-export const tdtGeoSearchUrl = `https://api.tianditu.gov.cn/geocoder?ds={"keyWord":"{keyword}"}&tk=${tdtKey}`;
+export const tdtGeoSearchPath = `geocoder?ds={"keyWord":"{keyword}"}`;
 // 视野内搜索
-export const tdtViewSearchUrl = `https://api.tianditu.gov.cn/v2/search?postStr={"keyWord":"{keyword}","queryRadius":"{queryRadius}","pointLonlat":"{pointLonlat}","queryType":3,"start":0,"count":20,"show":1}&type=query&tk=${tdtKey}`;
+export const tdtViewSearchPath = `v2/search?postStr={"keyWord":"{keyword}","queryRadius":"{queryRadius}","pointLonlat":"{pointLonlat}","queryType":3,"start":0,"count":20,"show":1}&type=query`;
 
-export const tdtDriveUrl = `https://api.tianditu.gov.cn/drive?postStr={"orig":"{orig}","dest":"{dest}","style":"0"}&type=search&tk=${tdtKey}`;
+export const tdtDriveSearchPath = `drive?postStr={"orig":"{orig}","dest":"{dest}","style":"0"}&type=search`;
@@ -25,9 +25,5 @@ app
   .use(createDatetime())
   .use(DatePicker)
   .use(TimePicker)
-  // .use(installTianDiTu, {
-  //   v: "4.0", //目前只支持4.0版本
-  //   tk: "bea0c0310f3478b0a79d7e594f741fa6",
-  // })
   .directive("remember-scroll", RememberScrollDirective)
   .mount("#app");
@@ -0,0 +1,92 @@
+import { invoke } from "@tauri-apps/api/core";
+import { listen, UnlistenFn } from "@tauri-apps/api/event";
+import { Coordinate } from "ol/coordinate";
+import { TileCacheStats } from "@/utils/proxyUrl";
+
+export interface TileRegion {
+  west: number;
+  south: number;
+  east: number;
+  north: number;
+  minZoom: number;
+  maxZoom: number;
+}
+
+// 沿路线的走廊, path 为 [经度, 纬度] 序列, 可直接使用 TDTDrivePath 的 routelatlon
+export interface TileCorridor {
+  path: Coordinate[];
+  bufferKm: number;
+  minZoom: number;
+  maxZoom: number;
+}
+
+export interface DownloadProgress {
+  id: number;
+  total: number;
+  done: number;
+  skipped: number;
+  failed: number;
+  finished: boolean;
+  cancelled: boolean;
+}
+
+// 天地图 key 由代理从 tdt-tile 上游配置中读取
+export async function downloadTileRegion(
+  region: TileRegion,
+  layers: string[]
+): Promise<number> {
+  return await invoke("plugin:proxy-plugin|download_tile_region", {
+    region,
+    layers,
+  });
+}
+
+export async function downloadTileCorridor(
+  corridor: TileCorridor,
+  layers: string[]
+): Promise<number> {
+  return await invoke("plugin:proxy-plugin|download_tile_corridor", {
+    corridor,
+    layers,
+  });
+}
+
+export async function cancelTileDownload(id: number): Promise<boolean> {
+  return await invoke("plugin:proxy-plugin|cancel_tile_download", { id });
+}
+
+export async function onTileDownloadProgress(
+  handler: (progress: DownloadProgress) => void
+): Promise<UnlistenFn> {
+  return await listen<DownloadProgress>(
+    "proxy://tile-download-progress",
+    (event) => handler(event.payload)
+  );
+}
+
+export async function exportMBTiles(
+  path: string,
+  layer: string,
+  region?: TileRegion
+): Promise<number> {
+  return await invoke("plugin:proxy-plugin|export_mbtiles", {
+    path,
+    layer,
+    region,
+  });
+}
+
+export async function importMBTiles(
+  path: string,
+  layer?: string
+): Promise<number> {
+  return await invoke("plugin:proxy-plugin|import_mbtiles", { path, layer });
+}
+
+export async function getOfflineTileStats(): Promise<TileCacheStats> {
+  return await invoke("plugin:proxy-plugin|get_offline_tile_stats");
+}
+
+export async function clearOfflineTiles(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|clear_offline_tiles");
+}
@@ -1,5 +1,14 @@
 import { invoke } from "@tauri-apps/api/core";
+import { listen, UnlistenFn } from "@tauri-apps/api/event";
+import { save } from "@tauri-apps/plugin-dialog";
+import { type as osType } from "@tauri-apps/plugin-os";
+import {
+  androidSaveFile,
+  FileType,
+  GeneralPurposeSubType,
+} from "@/utils/android/fs";
 
+// 以下生成代理地址的函数在代理未运行时抛出错误, 可监听 proxy://ready 后重新获取
 export async function getProxyUrl(
   url: string,
   headers?: Record<string, string>
@@ -10,6 +19,428 @@ export async function getProxyUrl(
   });
 }
 
+// WebSocket 代理地址, url 为 ws:// 或 wss:// 地址
+export async function getProxyWsUrl(
+  url: string,
+  headers?: Record<string, string>
+): Promise<string> {
+  return await invoke("plugin:proxy-plugin|get_proxy_ws_url", {
+    url: url,
+    headers: Array.from(Object.entries(headers || {})),
+  });
+}
+
+// contain 等比缩放到框内且不放大, cover 填满后居中裁剪, fill 拉伸;
+// cover 和 fill 需要同时指定宽高; 默认输出 jpeg, quality 只用于 jpeg (默认 80);
+// webp 为无损压缩, 用于需要透明度的图片, 照片的体积较大
+export type ImageFit = "contain" | "cover" | "fill";
+export type ImageFormat = "webp" | "jpeg";
+
+export interface ImageOptions {
+  width?: number;
+  height?: number;
+  fit?: ImageFit;
+  format?: ImageFormat;
+  quality?: number;
+}
+
+// 缩放后的图片地址, 结果缓存在磁盘上
+export async function getImageUrl(
+  url: string,
+  options: ImageOptions,
+  headers?: Record<string, string>
+): Promise<string> {
+  return await invoke("plugin:proxy-plugin|get_image_url", {
+    url: url,
+    options,
+    headers: Array.from(Object.entries(headers || {})),
+  });
+}
+
 export async function getProxyPort(): Promise<number | null> {
   return await invoke("plugin:proxy-plugin|get_proxy_port");
 }
+
+// 代理地址前缀, 包含本次启动的访问 token
+export async function getProxyBaseUrl(): Promise<string> {
+  return await invoke("plugin:proxy-plugin|get_proxy_base_url");
+}
+
+// 未运行时 port 为 0; 停止事件中 port 和 baseUrl 为停止前的值
+export interface ProxyStatus {
+  running: boolean;
+  port: number;
+  baseUrl: string;
+}
+
+export async function getProxyStatus(): Promise<ProxyStatus> {
+  return await invoke("plugin:proxy-plugin|get_proxy_status");
+}
+
+export async function startProxy(): Promise<ProxyStatus> {
+  return await invoke("plugin:proxy-plugin|start_proxy");
+}
+
+// 等待进行中的请求完成后停止, 返回是否原本在运行
+export async function stopProxy(): Promise<boolean> {
+  return await invoke("plugin:proxy-plugin|stop_proxy");
+}
+
+export async function restartProxy(): Promise<ProxyStatus> {
+  return await invoke("plugin:proxy-plugin|restart_proxy");
+}
+
+// 换到指定端口, 失败时仍使用原端口; 之前获取的代理地址需要重新获取
+export async function rebindProxy(port?: number): Promise<ProxyStatus> {
+  return await invoke("plugin:proxy-plugin|rebind_proxy", { port });
+}
+
+export async function onProxyReady(
+  handler: (status: ProxyStatus) => void
+): Promise<UnlistenFn> {
+  return await listen<ProxyStatus>("proxy://ready", (event) =>
+    handler(event.payload)
+  );
+}
+
+export async function onProxyStopped(
+  handler: (status: ProxyStatus) => void
+): Promise<UnlistenFn> {
+  return await listen<ProxyStatus>("proxy://stopped", (event) =>
+    handler(event.payload)
+  );
+}
+
+// 命名上游的访问地址, path 可以带查询参数, key 由代理注入
+export async function getUpstreamUrl(
+  name: string,
+  path: string
+): Promise<string> {
+  const baseUrl = await getProxyBaseUrl();
+  return `${baseUrl}/upstream/${name}/${path}`;
+}
+
+export interface UpstreamInfo {
+  name: string;
+  baseUrl: string;
+  hasKey: boolean;
+}
+
+export async function listUpstreams(): Promise<UpstreamInfo[]> {
+  return await invoke("plugin:proxy-plugin|list_upstreams");
+}
+
+export async function reloadProxyConfig(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|reload_proxy_config");
+}
+
+export interface TileCacheStats {
+  dir: string;
+  tiles: number;
+  totalBytes: number;
+  maxBytes: number;
+}
+
+export interface TileLayerInfo {
+  id: string;
+  name: string;
+  minZoom: number;
+  maxZoom: number;
+  url: string;
+  attributions: string[];
+}
+
+export async function getTileLayers(): Promise<TileLayerInfo[]> {
+  return await invoke("plugin:proxy-plugin|get_tile_layers");
+}
+
+// 瓦片路由地址, 子域名轮换和备用提供方由代理处理
+export async function getTileUrl(layer: string): Promise<string> {
+  const baseUrl = await getProxyBaseUrl();
+  return `${baseUrl}/tiles/${layer}/{z}/{x}/{y}`;
+}
+
+export async function getTileCacheStats(): Promise<TileCacheStats> {
+  return await invoke("plugin:proxy-plugin|get_tile_cache_stats");
+}
+
+export async function clearTileCache(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|clear_tile_cache");
+}
+
+export interface ImageCacheStats {
+  dir: string;
+  entries: number;
+  totalBytes: number;
+  maxBytes: number;
+}
+
+export async function getImageCacheStats(): Promise<ImageCacheStats> {
+  return await invoke("plugin:proxy-plugin|get_image_cache_stats");
+}
+
+export async function clearImageCache(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|clear_image_cache");
+}
+
+export interface HttpCacheStats {
+  dir: string;
+  entries: number;
+  totalBytes: number;
+  maxBytes: number;
+}
+
+// 代理的 HTTP 缓存, 请求头 x-proxy-cache-ttl 可指定缓存时间 (如 1d) 或 no-store
+export async function getHttpCacheStats(): Promise<HttpCacheStats> {
+  return await invoke("plugin:proxy-plugin|get_http_cache_stats");
+}
+
+export async function clearHttpCache(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|clear_http_cache");
+}
+
+export interface ClientSettings {
+  connectTimeoutSecs: number;
+  readTimeoutSecs?: number | null;
+  timeoutSecs?: number | null;
+  poolMaxIdlePerHost: number;
+  poolIdleTimeoutSecs: number;
+  userAgent: string;
+  httpVersion: "auto" | "http1Only" | "http2Only";
+  outbound: OutboundProxy;
+}
+
+// system: 读取 HTTP(S)_PROXY / NO_PROXY 环境变量或系统设置; manual: 使用 url 指定的代理
+// url 支持 http://、https://、socks5://、socks5h://; WebSocket 只支持 http 和 socks5(h) 代理,
+// system 模式下 WebSocket 只读取环境变量
+export interface OutboundProxy {
+  mode: "system" | "manual" | "direct";
+  url?: string | null;
+  username?: string | null;
+  password?: string | null;
+  noProxy: string[];
+}
+
+export async function getClientSettings(): Promise<ClientSettings> {
+  return await invoke("plugin:proxy-plugin|get_client_settings");
+}
+
+export async function setClientSettings(
+  settings: ClientSettings
+): Promise<void> {
+  return await invoke("plugin:proxy-plugin|set_client_settings", { settings });
+}
+
+export async function rebuildHttpClient(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|rebuild_http_client");
+}
+
+export interface UrlPolicy {
+  allowedSchemes: string[];
+  // 默认只允许天地图, 为空时不限制主机, 保留地址仍由 blockPrivate 拦截
+  allowedHosts: string[];
+  blockPrivate: boolean;
+}
+
+export async function getUrlPolicy(): Promise<UrlPolicy> {
+  return await invoke("plugin:proxy-plugin|get_url_policy");
+}
+
+export async function setUrlPolicy(policy: UrlPolicy): Promise<void> {
+  return await invoke("plugin:proxy-plugin|set_url_policy", { policy });
+}
+
+export interface CircuitInfo {
+  host: string;
+  state: "closed" | "open" | "halfOpen";
+  failures: number;
+  retryAfterSecs?: number | null;
+}
+
+// 连续失败的主机会被熔断, 熔断期间代理直接返回缓存或 503
+export async function getCircuitStates(): Promise<CircuitInfo[]> {
+  return await invoke("plugin:proxy-plugin|get_circuit_states");
+}
+
+export async function resetCircuits(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|reset_circuits");
+}
+
+export interface HostLimit {
+  requestsPerSec?: number | null;
+  burst: number;
+  maxInFlight?: number | null;
+}
+
+// hosts 的键支持 *.example.com 通配, 未匹配的主机使用 default
+export interface RateLimits {
+  default: HostLimit;
+  hosts: Record<string, HostLimit>;
+  queueTimeoutMs: number;
+}
+
+// host 为 hosts 中匹配到的键, 例如 *.tianditu.gov.cn, 未匹配时为主机名
+export interface HostLimitStats {
+  host: string;
+  requestsPerSec?: number | null;
+  maxInFlight?: number | null;
+  inFlight: number;
+  queued: number;
+  throttled: number;
+  rejected: number;
+}
+
+export async function getRateLimits(): Promise<RateLimits> {
+  return await invoke("plugin:proxy-plugin|get_rate_limits");
+}
+
+export async function setRateLimits(limits: RateLimits): Promise<void> {
+  return await invoke("plugin:proxy-plugin|set_rate_limits", { limits });
+}
+
+export async function getRateLimitStats(): Promise<HostLimitStats[]> {
+  return await invoke("plugin:proxy-plugin|get_rate_limit_stats");
+}
+
+export interface TrafficLogSettings {
+  enabled: boolean;
+  capacity: number;
+}
+
+// 请求头为 [名称, 值] 数组, 密钥已替换为 [REDACTED]
+export interface TrafficEntry {
+  id: number;
+  startedAt: number;
+  method: string;
+  url: string;
+  requestHeaders: [string, string][];
+  requestSize?: number | null;
+  status: number;
+  responseHeaders: [string, string][];
+  responseSize: number;
+  cache?: string | null;
+  waitMs: number;
+  receiveMs: number;
+  completed: boolean;
+}
+
+export async function getTrafficLogSettings(): Promise<TrafficLogSettings> {
+  return await invoke("plugin:proxy-plugin|get_traffic_log_settings");
+}
+
+export async function setTrafficLogSettings(
+  settings: TrafficLogSettings
+): Promise<void> {
+  return await invoke("plugin:proxy-plugin|set_traffic_log_settings", {
+    settings,
+  });
+}
+
+export async function getTrafficLog(limit?: number): Promise<TrafficEntry[]> {
+  return await invoke("plugin:proxy-plugin|get_traffic_log", { limit });
+}
+
+export async function clearTrafficLog(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|clear_traffic_log");
+}
+
+// 导出为 HAR 1.2, 桌面端弹出保存对话框, Android 保存到下载目录; 取消时返回 false
+export async function exportTrafficHar(): Promise<boolean> {
+  const fileName = `proxy-${Date.now()}.har`;
+  if (osType() === "android") {
+    const har: string = await invoke("plugin:proxy-plugin|get_traffic_har");
+    await androidSaveFile(
+      FileType.general_purpose,
+      GeneralPurposeSubType.download,
+      fileName,
+      "application/json",
+      new TextEncoder().encode(har)
+    );
+    return true;
+  }
+  const path = await save({
+    defaultPath: fileName,
+    filters: [{ name: "HAR", extensions: ["har"] }],
+  });
+  if (!path) return false;
+  await invoke("plugin:proxy-plugin|export_traffic_har", { path });
+  return true;
+}
+
+// off: 正常请求; record: 请求上游并录制; replay: 只返回录制的响应
+export type ReplayMode = "off" | "record" | "replay";
+
+// dir 为空时使用缓存目录下的 fixtures, ignoreHeaders 支持 "sec-*" 通配
+export interface ReplaySettings {
+  mode: ReplayMode;
+  dir?: string | null;
+  ignoreParams: string[];
+  ignoreHeaders: string[];
+}
+
+export async function getReplaySettings(): Promise<ReplaySettings> {
+  return await invoke("plugin:proxy-plugin|get_replay_settings");
+}
+
+export async function setReplaySettings(
+  settings: ReplaySettings
+): Promise<void> {
+  return await invoke("plugin:proxy-plugin|set_replay_settings", { settings });
+}
+
+// 响应转换, 请求头 x-proxy-transform 可按请求指定 (charset、jsonp、all 或 off)
+export interface TransformSettings {
+  charset: boolean;
+  jsonp: boolean;
+}
+
+export async function getTransformSettings(): Promise<TransformSettings> {
+  return await invoke("plugin:proxy-plugin|get_transform_settings");
+}
+
+export async function setTransformSettings(
+  settings: TransformSettings
+): Promise<void> {
+  return await invoke("plugin:proxy-plugin|set_transform_settings", {
+    settings,
+  });
+}
+
+// 字节数以前端为视角: bytesIn 为请求体, bytesOut 为返回的响应体
+export interface HostStats {
+  host: string;
+  requests: number;
+  status: Record<string, number>;
+  bytesIn: number;
+  bytesOut: number;
+}
+
+export interface ProxyStats {
+  // 本次启动以来的秒数, 重启和换端口时重新计时, 未运行时为 0
+  uptimeSecs: number;
+  requests: number;
+  bytesIn: number;
+  bytesOut: number;
+  p50Ms?: number | null;
+  p95Ms?: number | null;
+  cacheHitRatio?: number | null;
+  errorStreak: number;
+  hosts: HostStats[];
+}
+
+export async function getProxyStats(): Promise<ProxyStats> {
+  return await invoke("plugin:proxy-plugin|get_proxy_stats");
+}
+
+export async function resetProxyStats(): Promise<void> {
+  return await invoke("plugin:proxy-plugin|reset_proxy_stats");
+}
+
+// 推送间隔由配置文件中的 stats.eventIntervalSecs 决定
+export async function onProxyStats(
+  handler: (stats: ProxyStats) => void
+): Promise<UnlistenFn> {
+  return await listen<ProxyStats>("proxy://stats", (event) =>
+    handler(event.payload)
+  );
+}
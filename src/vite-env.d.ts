@@ -5,3 +5,8 @@ declare module "*.vue" {
   const component: DefineComponent<{}, {}, any>;
   export default component;
 }
+
+interface ImportMetaEnv {
+  // 网页版直接访问天地图时使用的 key, 应用内由代理注入
+  readonly VITE_TDT_KEY?: string;
+}